@@ -0,0 +1,15 @@
+-- @suite examples_suite_directive
+-- This contains example Lua unit tests registered with MoonUnit without
+-- giving their suite name, which comes from the directive above instead.
+
+if not moonunit then return end
+
+require("example-code")
+
+moonunit:test("", "square_zero", function()
+    moonunit:expect_eq(0, square(0))
+end)
+
+moonunit:test("examples_passing", "explicit_suite_unaffected", function()
+    moonunit:expect_eq(4, square(2))
+end)
@@ -127,3 +127,657 @@ moonunit:test("examples_failing", "buggy_abs_should_fail_but_continue", function
     moonunit:expect_eq(1, buggy_abs(-1))
     moonunit:expect_eq(1, buggy_abs(-1))
 end)
+
+moonunit:test("examples_passing", "coroutines_not_comparable", function()
+    local co = coroutine.create(function() end)
+    local ok, err = pcall(function() moonunit:assert_eq(co, co) end)
+    moonunit:assert_false(ok)
+    moonunit:assert_true(
+        string.find(tostring(err), "Cannot compare coroutines for equality", 1, true)
+    )
+end)
+
+moonunit:test("examples_failing", "coroutine_eq", function()
+    local co = coroutine.create(function() end)
+    moonunit:assert_eq(co, 42)
+end)
+
+moonunit:test("examples_passing", "same_table_reference", function()
+    local golden = {}
+    for i = 1, 100000 do
+        golden[i] = {index = i, name = "item" .. i}
+    end
+    moonunit:assert_eq(golden, golden)
+    moonunit:expect_eq(golden, golden)
+end)
+
+moonunit:test("examples_passing", "matches_golden", function()
+    local value = {name = "example", square = square(3), values = {1, 2, 3}}
+    moonunit:expect_matches_golden(value, "example-golden.txt")
+    moonunit:assert_matches_golden(value, "example-golden.txt")
+end)
+
+moonunit:test("examples_failing", "matches_golden", function()
+    local value = {name = "example", square = square(4), values = {1, 2, 3}}
+    moonunit:assert_matches_golden(value, "example-golden.txt")
+end)
+
+moonunit:test("examples_passing", "table_keys", function()
+    local key1 = {}
+    local key2 = {}
+    local key3 = {}
+    moonunit:assert_eq({[key1] = 1, [key2] = 2}, {[key2] = 2, [key1] = 1})
+    moonunit:assert_ne({[key1] = 1, [key2] = 2}, {[key1] = 1, [key3] = 2})
+    moonunit:assert_ne({[key1] = 1, [key2] = 2}, {[key1] = 2, [key2] = 1})
+end)
+
+moonunit:test("examples_failing", "table_keys", function()
+    local key1 = {}
+    local key2 = {}
+    moonunit:assert_eq({[key1] = 1}, {[key2] = 1})
+end)
+
+moonunit:test("examples_passing", "numeric_string_keys", function()
+    local options = {coerce_numeric_string_keys = true}
+    moonunit:assert_ne({[1] = "a"}, {["1"] = "a"})
+    moonunit:assert_eq({[1] = "a"}, {["1"] = "a"}, options)
+    moonunit:expect_eq({x = {[2] = "b"}}, {x = {["2.0"] = "b"}}, options)
+    moonunit:assert_ne({[1] = "a"}, {["1"] = "b"}, options)
+end)
+
+moonunit:test("examples_passing", "nested_float_keys", function()
+    moonunit:assert_eq({x = {y = {[2.0] = "b"}}}, {x = {y = {[2] = "b"}}})
+    moonunit:assert_eq({x = {y = {[1.5] = "c"}}}, {x = {y = {[1.5] = "c"}}})
+    moonunit:assert_ne({x = {y = {[1.5] = "c"}}}, {x = {y = {[1.5] = "d"}}})
+end)
+
+moonunit:test("examples_failing", "nested_float_keys", function()
+    moonunit:expect_eq({x = {[1.0] = {[1.5] = "c"}}}, {x = {{[1.5] = "d"}}})
+end)
+
+moonunit:test("examples_failing", "many_expectation_failures", function()
+    for i = 1, 1000 do
+        moonunit:expect_eq(i, i + 1)
+    end
+end)
+
+local function case_insensitive(lhs, rhs)
+    return lhs:lower() == rhs:lower()
+end
+
+moonunit:test("examples_passing", "comparator", function()
+    moonunit:assert_eq("Hello", "hELLO", case_insensitive)
+    moonunit:expect_eq("Hello", "hELLO", case_insensitive)
+    moonunit:assert_ne("Hello", "World", case_insensitive)
+    moonunit:expect_ne("Hello", "World", case_insensitive)
+end)
+
+moonunit:test("examples_failing", "comparator_message", function()
+    moonunit:expect_eq(1, 2, function(lhs, rhs)
+        return lhs == rhs or ("%d and %d are not the same"):format(lhs, rhs)
+    end)
+    moonunit:expect_eq(1, 1, function(lhs, rhs)
+        error("comparator is broken")
+    end)
+end)
+
+moonunit:test("examples_passing", "square_negative", function()
+    moonunit:expect_eq(25, square(-5))
+end, {tags = {"math"}, description = "Squares of negative numbers are positive"})
+
+moonunit:test("examples_passing", "table_rendering_is_deterministic", function()
+    local ok, err = pcall(function()
+        moonunit:assert_eq(1, {b = 2, [1] = "x", a = true, [2.5] = {z = 1, y = 2}})
+    end)
+    moonunit:assert_false(ok)
+    moonunit:assert_true(string.find(
+        tostring(err),
+        'Expected 1 (integer), actual was {[1] = "x", [2.5] = {y = 2, z = 1}, a = true, b = 2}',
+        1,
+        true
+    ))
+end)
+
+moonunit:test("examples_passing", "null_sentinel", function()
+    moonunit:expect_eq({a = 1, b = moonunit.null}, {a = 1})
+    moonunit:expect_eq({a = 1, b = moonunit.null}, {a = 1, b = moonunit.null})
+    moonunit:expect_eq({a = 1}, {a = 1, b = moonunit.null})
+    moonunit:expect_eq(moonunit.null, nil)
+end)
+
+moonunit:test("examples_failing", "null_sentinel", function()
+    moonunit:expect_eq({a = 1, b = moonunit.null}, {a = 1, b = 2})
+    moonunit:expect_eq(moonunit.null, false)
+end)
+
+moonunit:test("examples_failing", "integer_overflow", function()
+    moonunit:expect_eq(math.maxinteger + 1, math.maxinteger)
+    moonunit:expect_gt(math.maxinteger + 1, 0)
+end)
+
+local function slow_sum(n)
+    local sum = 0
+    for i = 1, n do
+        sum = sum + i
+    end
+    return sum
+end
+
+moonunit:test("examples_passing", "behaves_like", function()
+    moonunit:expect_behaves_like(slow_sum, function(n)
+        return n * (n + 1) // 2
+    end, {0, 1, 2, 10, 100})
+    moonunit:expect_behaves_like(math.max, function(a, b)
+        return a > b and a or b
+    end, {{1, 2}, {2, 1}, {-5, -5}})
+end)
+
+moonunit:test("examples_failing", "behaves_like", function()
+    moonunit:expect_behaves_like(slow_sum, function(n)
+        return n * n // 2
+    end, {0, 1, 2, 10})
+    moonunit:expect_behaves_like(slow_sum, function(n)
+        error("not implemented")
+    end, {1})
+end)
+
+moonunit:test("examples_passing", "type", function()
+    moonunit:assert_type(3, "number")
+    moonunit:assert_type(6 // 2, "integer")
+    moonunit:assert_type(6 / 2, "float")
+    moonunit:expect_type("3", "string")
+    moonunit:expect_type({}, "table")
+    moonunit:expect_type(nil, "nil")
+    moonunit:expect_type({}, "table")
+end)
+
+moonunit:test("examples_failing", "type", function()
+    moonunit:expect_type(6 / 2, "integer")
+    moonunit:expect_type(3, "float")
+    moonunit:expect_type("3", "table")
+end)
+
+moonunit:test("examples_passing", "match", function()
+    moonunit:assert_match("version 1.2", "%d+%.%d+")
+    moonunit:expect_match("hello", "^h.-o$")
+end)
+
+moonunit:test("examples_failing", "match", function()
+    moonunit:expect_match("version 1.2", "^%d+$")
+    moonunit:expect_match(42, "%d+")
+end)
+
+moonunit:test("examples_passing", "contains", function()
+    moonunit:assert_contains({3, 1, 2}, 2)
+    moonunit:assert_contains({{x = 1}, {x = 2}}, {x = 2})
+    moonunit:expect_contains({a = "x", b = "y"}, "y")
+end)
+
+moonunit:test("examples_failing", "contains", function()
+    moonunit:expect_contains({3, 1, 2}, 4)
+    moonunit:expect_contains({{x = 1}}, {x = 2})
+    moonunit:expect_contains("123", "2")
+end)
+
+moonunit:test("examples_passing", "is_array", function()
+    moonunit:assert_is_array({})
+    moonunit:assert_is_array({"a", "b", "c"})
+    moonunit:expect_is_array({"a", "b", n = 2}, {allow_non_integer_keys = true})
+end)
+
+moonunit:test("examples_failing", "is_array", function()
+    moonunit:expect_is_array({[1] = "a", [2] = "b", [4] = "d"})
+    moonunit:expect_is_array({"a", "b", n = 2})
+    moonunit:expect_is_array({[0] = "z", "a"}, {allow_non_integer_keys = true})
+    moonunit:expect_is_array("abc")
+end)
+
+moonunit:test("examples_passing", "len", function()
+    moonunit:assert_len({}, 0)
+    moonunit:expect_len({"a", "b", "c"}, 3)
+end)
+
+moonunit:test("examples_failing", "len", function()
+    moonunit:expect_len({"a", "b"}, 3)
+    moonunit:expect_len("abc", 3)
+end)
+
+moonunit:test("examples_passing", "near", function()
+    moonunit:assert_ne(0.3, 0.1 + 0.2)
+    moonunit:assert_near(0.3, 0.1 + 0.2)
+    moonunit:expect_near(3, 3.05, 0.1)
+end)
+
+moonunit:test("examples_failing", "near", function()
+    moonunit:expect_near(3, 3.5, 0.1)
+    moonunit:expect_near(0.3, 0.31)
+end)
+
+moonunit:test("examples_passing", "array_near", function()
+    moonunit:assert_array_near({}, {}, 0)
+    moonunit:assert_array_near({1, 0.5, 0.25}, {1, 0.5001, 0.2499}, 1e-3)
+    moonunit:expect_array_near({1, 2}, {1.0, 2.0}, 0)
+end)
+
+moonunit:test("examples_failing", "array_near", function()
+    moonunit:expect_array_near({1, 0.5, 0.25}, {1, 0.51, 0.25}, 1e-3)
+    moonunit:expect_array_near({1, 2}, {1, 2, 3}, 1e-3)
+    moonunit:expect_array_near({1, 2}, {1, 0 / 0}, 1e-3)
+end)
+
+moonunit:test("examples_failing", "abort", function()
+    moonunit:expect_eq(1, 2)
+    moonunit:abort("precondition not met")
+    moonunit:expect_eq(3, 4)
+end)
+
+moonunit:test("examples_passing", "integer_float_equality", function()
+    moonunit:assert_eq(1, 1.0)
+    moonunit:assert_eq({x = 1}, {x = 1.0})
+    moonunit:assert_eq({[1] = "a"}, {[1.0] = "a"})
+    moonunit:assert_ne({x = 1}, {x = 1.5})
+end)
+
+moonunit:test("examples_failing", "integer_float_equality", function()
+    moonunit:expect_ne({x = 1}, {x = 1.0})
+    moonunit:expect_eq({x = 1}, {x = 1.5})
+end)
+
+moonunit:test("examples_passing", "cyclic_tables", function()
+    local a = {x = 1}
+    a.self = a
+    local b = {x = 1}
+    b.self = b
+    moonunit:assert_eq(a, b)
+    moonunit:assert_eq_diff(a, b)
+    local c = {x = 2}
+    c.self = c
+    moonunit:assert_ne(a, c)
+end)
+
+moonunit:test("examples_failing", "cyclic_tables", function()
+    local a = {x = 1}
+    a.self = a
+    local b = {x = 2}
+    b.self = b
+    moonunit:expect_eq(a, b)
+    moonunit:expect_eq_diff(a, b)
+end)
+
+moonunit:test("examples_passing", "skip", function()
+    moonunit:skip("not supported here")
+    moonunit:expect_eq(3, 4)
+end)
+
+moonunit:test("examples_failing", "elided_table_difference", function()
+    moonunit:expect_eq(
+        {name = "server", config = {host = "localhost", port = 80}, tags = {"a"}},
+        {name = "server", config = {host = "localhost", port = 8080}, tags = {"a"}}
+    )
+end)
+
+moonunit:test("examples_passing", "non_utf8_strings", function()
+    moonunit:expect_eq({["\xff"] = 1, a = 2}, {a = 2, ["\xff"] = 1})
+end)
+
+moonunit:test("examples_failing", "non_utf8_strings", function()
+    moonunit:expect_eq("\xff", "a")
+    moonunit:expect_eq({["\xff\xfe"] = 1}, {["\xff\xfe"] = 2})
+end)
+
+moonunit:test("examples_passing", "json_equivalent", function()
+    moonunit:assert_json_equivalent(
+        {name = "x", sizes = {1, 2.0, 3}, extra = moonunit.null},
+        {sizes = {1.0, 2, 3}, name = "x"}
+    )
+    moonunit:expect_json_equivalent({}, {})
+end)
+
+moonunit:test("examples_failing", "json_equivalent", function()
+    moonunit:expect_json_equivalent(
+        {name = "x", sizes = {1, 2, 3}},
+        {name = "x", sizes = {1, 2, 4}}
+    )
+end)
+
+local function find_user(name)
+    if name ~= "admin" then
+        error({code = "NOT_FOUND", message = "No user named " .. name})
+    end
+    return {name = name}
+end
+
+moonunit:test("examples_passing", "raises_with_value", function()
+    local err = moonunit:assert_raises_with_value(find_user, "nobody")
+    moonunit:expect_eq("NOT_FOUND", err.code)
+    moonunit:expect_eq("No user named nobody", err.message)
+    moonunit:expect_eq({code = "NOT_FOUND", message = "No user named x"},
+        moonunit:expect_raises_with_value(find_user, "x"))
+end)
+
+moonunit:test("examples_failing", "raises_with_value", function()
+    moonunit:expect_raises_with_value(find_user, "admin")
+end)
+
+moonunit:test("examples_passing", "unicode_normalization", function()
+    local composed = "caf\u{E9}"
+    local decomposed = "cafe\u{301}"
+    moonunit:expect_ne(composed, decomposed)
+    moonunit:expect_eq(composed, decomposed, {normalize = "NFC"})
+    moonunit:expect_eq({name = composed}, {name = decomposed}, {normalize = "NFD"})
+end)
+
+moonunit:test("examples_failing", "unicode_normalization", function()
+    moonunit:expect_eq("caf\u{E9}", "cafe", {normalize = "NFC"})
+end)
+
+-- When run with --strict, this test passes, but a warning is shown
+-- because it never calls anything.
+moonunit:test("examples_passing", "no_calls", function()
+    local function forgotten()
+        return square(2)
+    end
+end)
+
+moonunit:test("examples_passing", "from_file", function()
+    local value = {name = "example", square = square(3), values = {1, 2, 3}}
+    moonunit:expect_eq(moonunit:from_file("example-expected.json"), value)
+    moonunit:expect_eq(moonunit:from_file("example-golden.txt"), value)
+end)
+
+moonunit:test("examples_failing", "from_file", function()
+    moonunit:expect_eq(moonunit:from_file("example-expected.json"), {name = "example"})
+    moonunit:expect_eq(moonunit:from_file("no-such-file.json"), {})
+end)
+
+moonunit:test("examples_passing", "mixed_number_ordering", function()
+    moonunit:expect_lt(1, 2.5)
+    moonunit:expect_gt(3, 2.5)
+    moonunit:expect_le(2, 2.0)
+    moonunit:expect_lt("apple", "banana")
+end)
+
+moonunit:test("examples_passing", "unsupported_value_kinds", function()
+    local ok, err = pcall(moonunit.assert_lt, moonunit, {}, {})
+    moonunit:assert_false(ok)
+    moonunit:assert_true(string.find(
+        tostring(err),
+        "Cannot compare table and table for ordering",
+        1,
+        true
+    ))
+    local _, error_value = pcall(moonunit.assert_eq, moonunit, 1, 2)
+    ok, err = pcall(moonunit.assert_eq, moonunit, error_value, error_value)
+    moonunit:assert_false(ok)
+    moonunit:assert_true(string.find(
+        tostring(err),
+        "Cannot compare error values for equality",
+        1,
+        true
+    ))
+    moonunit:expect_ne(moonunit.null, 1)
+end)
+
+moonunit:test("examples_failing", "unsupported_value_kinds", function()
+    moonunit:expect_eq(1, moonunit.null)
+    moonunit:expect_eq(1, print)
+    moonunit:expect_lt(true, false)
+end)
+
+local Point = {}
+Point.__index = Point
+Point.__tostring = function(point)
+    return ("Point(%d, %d)"):format(point.x, point.y)
+end
+
+local function new_point(x, y)
+    return setmetatable({x = x, y = y}, Point)
+end
+
+moonunit:test("examples_passing", "tostring_rendering", function()
+    local ok, err = pcall(function()
+        moonunit:assert_eq({at = new_point(3, 4)}, "here")
+    end)
+    moonunit:assert_false(ok)
+    moonunit:assert_true(string.find(
+        tostring(err),
+        'Expected {at = Point(3, 4)}, actual was "here"',
+        1,
+        true
+    ))
+end)
+
+moonunit:test("examples_failing", "tostring_rendering", function()
+    moonunit:expect_eq(new_point(1, 2), 5)
+end)
+
+moonunit:test("examples_failing", "abort_after_failures", function()
+    for i = 1, 100 do
+        moonunit:expect_eq(i, 0)
+    end
+end, {abort_after_failures = 3})
+
+moonunit:test("examples_passing", "eq_diff", function()
+    moonunit:expect_eq_diff({a = {b = 1, c = {2, 3}}}, {a = {b = 1, c = {2, 3}}})
+    moonunit:expect_eq_diff(5, 5)
+end)
+
+moonunit:test("examples_failing", "eq_diff", function()
+    moonunit:expect_eq_diff(
+        {name = "widget", size = {w = 1, h = 2}, tags = {"a", "b"}},
+        {name = "gadget", size = {w = 1, h = 3}, tags = {"a"}, extra = true}
+    )
+end)
+
+moonunit:before_all("examples_passing", function()
+    return {greeting = "hello", squares = {square(1), square(2), square(3)}}
+end)
+
+moonunit:after_all("examples_passing", function(fixture)
+    assert(fixture.greeting == "hello")
+end)
+
+moonunit:test("examples_passing", "before_all_fixture", function(fixture)
+    moonunit:expect_eq("hello", fixture.greeting)
+    moonunit:expect_eq({1, 4, 9}, fixture.squares)
+end)
+
+moonunit:test("examples_failing", "quarantined", function()
+    moonunit:expect_eq(1, math.random(2))
+end, {tags = {"quarantine"}, description = "Fails about half the time"})
+
+moonunit:test("examples_failing", "dependent_on_failure", function()
+    moonunit:expect_true(true)
+end, {depends_on = {"abort"}})
+
+moonunit:test("examples_passing", "dependent", function()
+    moonunit:expect_true(true)
+end, {depends_on = {"type", "examples_passing.near"}})
+
+local setup_count = 0
+
+moonunit:setup("examples_setup", function()
+    setup_count = setup_count + 1
+end)
+
+moonunit:teardown("examples_setup", function()
+    setup_count = 0
+end)
+
+moonunit:test("examples_setup", "setup_called", function()
+    moonunit:expect_eq(1, setup_count)
+end)
+
+moonunit:test("examples_passing", "quiet_print", function()
+    print("this isn't shown, since the test passes")
+    moonunit:expect_eq(42, 42)
+end)
+
+moonunit:test("examples_failing", "captured_print", function()
+    print("the answer is", 42, nil)
+    moonunit:expect_eq(42, 43)
+end)
+
+moonunit:test("examples_passing", "throws", function()
+    moonunit:assert_throws(function() error("bad input") end)
+    moonunit:expect_throws_match(function() error("bad input") end, "bad %a+")
+end)
+
+moonunit:test("examples_failing", "throws", function()
+    moonunit:expect_throws(function() return 42 end)
+    moonunit:expect_throws_match(function() error("bad input") end, "^good")
+end)
+
+moonunit:test("examples_passing", "no_error", function()
+    moonunit:assert_no_error(function() return 42 end)
+    moonunit:expect_no_error(function() end)
+end)
+
+moonunit:test("examples_failing", "no_error", function()
+    moonunit:expect_no_error(function() error("bad input") end)
+    moonunit:expect_no_error(function() error({code = 42}) end)
+end)
+
+moonunit:test("examples_failing", "error_object", function()
+    error({code = "NOT_FOUND", message = "No such user"})
+end)
+
+moonunit:test("examples_failing", "failure_messages", function()
+    moonunit:expect_eq(1, 2, "first check")
+    moonunit:expect_eq({x = 1}, {x = 2}, {}, "second check")
+    moonunit:expect_near(1.0, 1.5, nil, "third check")
+    moonunit:expect_true(false, "fourth check")
+end)
+
+moonunit:test("examples_failing", "large_table_shown_in_part", function()
+    local big = {}
+    for i = 1, 30 do
+        big[i] = i
+    end
+    moonunit:expect_eq(42, big)
+    moonunit:expect_eq(42, {a = {b = {c = {d = {e = {f = 1}}}}}})
+end)
+
+moonunit:test("examples_passing", "nil", function()
+    moonunit:expect_nil(nil)
+    moonunit:expect_not_nil(false)
+end)
+
+moonunit:test("examples_failing", "nil", function()
+    moonunit:expect_nil({x = 1})
+    moonunit:expect_not_nil(nil)
+end)
+
+moonunit:test("examples_passing", "truthy", function()
+    moonunit:assert_truthy(0)
+    moonunit:expect_truthy("")
+    moonunit:expect_falsy(nil)
+end)
+
+moonunit:test("examples_failing", "truthy", function()
+    moonunit:expect_truthy(nil)
+    moonunit:expect_falsy({})
+end)
+
+-- The failure of an expectation checked by a helper function is reported
+-- at the line in the helper where it was checked.
+local function expect_positive(value)
+    moonunit:expect_gt(value, 0)
+end
+
+moonunit:test("examples_failing", "expectation_in_helper", function()
+    expect_positive(1)
+    expect_positive(-1)
+end)
+
+moonunit:test_each("examples_passing", "doubled", {1, 2, 3}, function(n)
+    moonunit:expect_eq(n + n, n * 2)
+end)
+
+moonunit:test_each("examples_failing", "doubled", {
+    {name = "small", input = 2, expected = 4},
+    {name = "wrong", input = 3, expected = 7},
+}, function(case)
+    moonunit:expect_eq(case.expected, case.input * 2)
+end)
+
+moonunit:test("examples_passing", "quiet_log", function()
+    moonunit:log("this isn't shown, since the test passes")
+    moonunit:expect_eq(42, 42)
+end)
+
+moonunit:test("examples_failing", "log", function()
+    for i = 1, 2 do
+        moonunit:log("checking case " .. i)
+        moonunit:expect_eq(1, i)
+    end
+    moonunit:log("all cases checked")
+end)
+
+moonunit:test("examples_passing", "approx_eq", function()
+    moonunit:expect_approx_eq({x = 0.3, y = {0.6}}, {x = 0.1 + 0.2, y = {0.2 * 3}})
+    moonunit:expect_eq(1.0, 1.05, {tolerance = 0.1})
+end)
+
+moonunit:test("examples_failing", "approx_eq", function()
+    moonunit:expect_approx_eq({x = 1.0, y = {2.0}}, {x = 1.0, y = {2.5}}, 0.1)
+end)
+
+moonunit:test("examples_passing", "subset", function()
+    moonunit:assert_subset({x = 1, y = {a = 2, b = 3}, z = 4}, {y = {a = 2}})
+    moonunit:expect_eq({x = 1}, {x = 1, y = 2}, {allow_extra_keys = true})
+end)
+
+moonunit:test("examples_failing", "subset", function()
+    moonunit:expect_subset({x = 1, y = {a = 2}}, {y = {a = 3}})
+    moonunit:expect_subset({x = 1}, {x = 1, y = 2})
+end)
+
+moonunit:test("examples_passing", "expect_fail", function()
+    moonunit:expect_eq(4, 2 + 3)
+end, {expect_fail = true})
+
+moonunit:test("examples_failing", "expect_fail", function()
+    moonunit:expect_eq(5, 2 + 3)
+end, {expect_fail = true})
+
+moonunit:test("examples_passing", "global_set", function()
+    example_global = 42
+    moonunit:expect_eq(42, example_global)
+end)
+
+moonunit:test("examples_passing", "global_not_leaked", function()
+    -- Each test sees the globals as the script left them, even when run
+    -- with --reuse-lua-states, so the global set by the previous test
+    -- isn't seen here.
+    moonunit:expect_eq(nil, example_global)
+end)
+
+moonunit:test("examples_failing", "disabled_in_safe_mode", function()
+    -- When run with --safe, this fails with an error explaining that
+    -- os.getenv is disabled, rather than with an error about calling nil.
+    moonunit:expect_eq(nil, os.getenv("PATH"))
+end)
+
+moonunit:test("examples_failing", "sandbox_reachable", function()
+    -- This fails unless run with --safe, since otherwise io and os.execute
+    -- can be reached by test scripts.
+    moonunit:expect_throws_match(function()
+        return io.type(nil)
+    end, "disabled in safe mode")
+    moonunit:expect_throws_match(function()
+        return os.execute()
+    end, "disabled in safe mode")
+end)
+
+moonunit:test("examples_passing", "handles-dashed-name", function()
+    -- This can be run by itself with a filter giving its exact name,
+    -- "examples_passing.handles-dashed-name", since a '-' only starts the
+    -- patterns leaving out tests at the start of a filter or after a ':'.
+    moonunit:expect_eq(4, square(2))
+end)
+
+moonunit:test("examples_failing", "log_before_assert", function()
+    moonunit:log("this is shown before the assertion failure")
+    moonunit:assert_eq(1, 2)
+end)
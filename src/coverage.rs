@@ -0,0 +1,287 @@
+use std::fmt::Write;
+
+// Line coverage gathered while running tests: the number of times each
+// line of each Lua source file was executed, keyed by the path of the file
+// and then by line number.
+#[derive(Default)]
+pub struct Coverage {
+    hits: std::collections::BTreeMap<
+        String,
+        std::collections::BTreeMap<usize, usize>,
+    >,
+
+    // These are the test scripts which have been executed so far.  Since a
+    // test script is executed again before each of its tests is run, only
+    // its first execution is counted, so that the lines which aren't part
+    // of any test (along with any modules loaded by them) are counted once,
+    // as if the script had been executed just once for all of its tests.
+    scripts_executed: std::collections::HashSet<std::path::PathBuf>,
+    paused: bool,
+}
+
+impl Coverage {
+    pub fn record(
+        &mut self,
+        file: &str,
+        line: usize,
+    ) {
+        if self.paused {
+            return;
+        }
+        *self
+            .hits
+            .entry(file.to_owned())
+            .or_default()
+            .entry(line)
+            .or_insert(0) += 1;
+    }
+
+    // This is called before a test script is executed, to stop counting
+    // lines while executing it if it was executed before.
+    pub fn begin_script(
+        &mut self,
+        path: &std::path::Path,
+    ) {
+        self.paused = !self.scripts_executed.insert(path.to_path_buf());
+    }
+
+    // This is called after a test script is executed, to count lines again.
+    pub fn end_script(&mut self) {
+        self.paused = false;
+    }
+
+    // Render the coverage in the LCOV tracefile format.  The lines which
+    // could have been executed are found by compiling each file again, so
+    // lines which were never executed are listed with no hits.  If a file
+    // can't be compiled again (for example, because it was removed), only
+    // its lines which were executed are listed.
+    pub fn to_lcov(&self) -> String {
+        let mut buffer = String::new();
+        for (file, hits) in &self.hits {
+            let mut lines = executable_lines(file).unwrap_or_default();
+            lines.extend(hits.keys());
+            writeln!(&mut buffer, "TN:").unwrap();
+            writeln!(&mut buffer, "SF:{}", file).unwrap();
+            for line in &lines {
+                let line_hits = hits.get(line).copied().unwrap_or(0);
+                writeln!(&mut buffer, "DA:{},{}", line, line_hits).unwrap();
+            }
+            writeln!(&mut buffer, "LF:{}", lines.len()).unwrap();
+            writeln!(&mut buffer, "LH:{}", hits.len()).unwrap();
+            writeln!(&mut buffer, "end_of_record").unwrap();
+        }
+        buffer
+    }
+}
+
+// Determine the path of the file from which a Lua chunk was loaded, given
+// the source name of the chunk.  Only chunks loaded from files are covered,
+// which have source names beginning with '@' (as with `require` or
+// `dofile`) or '=' (as with the test scripts loaded by MoonUnit).  Relative
+// paths are resolved against the current working directory, which is the
+// folder of the test script being run.
+pub fn source_file(source: &[u8]) -> Option<String> {
+    let path = match source.split_first() {
+        Some((b'@' | b'=', path)) => String::from_utf8_lossy(path),
+        _ => return None,
+    };
+    let path = std::path::Path::new(path.as_ref());
+    let path = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        std::env::current_dir().ok()?.join(path)
+    };
+    Some(path.to_string_lossy().into_owned())
+}
+
+// Find the lines of a Lua source file which hold code that can be executed
+// (the "active lines" of every function in it, as Lua calls them).  Lua
+// only gives the active lines of functions which can be reached from Lua,
+// so instead, the file is compiled, and the line information of every
+// function in it is read from the binary chunk made by `string.dump`.
+fn executable_lines(path: &str) -> Option<std::collections::BTreeSet<usize>> {
+    let source = std::fs::read(path).ok()?;
+
+    // Like `loadfile`, skip a first line starting with '#' (such as
+    // "#!/usr/bin/env lua"), keeping its line break so that the numbers of
+    // the other lines stay the same.
+    let source = if source.starts_with(b"#") {
+        let end = source
+            .iter()
+            .position(|&byte| byte == b'\n')
+            .unwrap_or_else(|| source.len());
+        &source[end..]
+    } else {
+        &source[..]
+    };
+    let lua = mlua::Lua::new();
+    let function = lua.load(source).into_function().ok()?;
+    let dump: mlua::String = lua
+        .globals()
+        .get::<_, mlua::Table>("string")
+        .ok()?
+        .get::<_, mlua::Function>("dump")
+        .ok()?
+        .call(function)
+        .ok()?;
+    let mut lines = std::collections::BTreeSet::new();
+    ChunkReader::new(dump.as_bytes())?.read_function(&mut lines)?;
+    Some(lines)
+}
+
+// This reads a binary chunk made by `string.dump` in Lua 5.3, only as far
+// as needed to find the line information of the functions in it (see
+// "ldump.c" in the source code of Lua).
+struct ChunkReader<'a> {
+    bytes: &'a [u8],
+    int_size: usize,
+    size_t_size: usize,
+    instruction_size: usize,
+    integer_size: usize,
+    number_size: usize,
+}
+
+impl<'a> ChunkReader<'a> {
+    // Start reading a binary chunk, checking its header, which gives the
+    // sizes of the values stored in the rest of the chunk.
+    fn new(bytes: &'a [u8]) -> Option<Self> {
+        let mut reader = Self {
+            bytes,
+            int_size: 0,
+            size_t_size: 0,
+            instruction_size: 0,
+            integer_size: 0,
+            number_size: 0,
+        };
+        if reader.take(4)? != b"\x1bLua"
+            || reader.byte()? != 0x53
+            || reader.byte()? != 0
+            || reader.take(6)? != b"\x19\x93\r\n\x1a\n"
+        {
+            return None;
+        }
+        reader.int_size = usize::from(reader.byte()?);
+        reader.size_t_size = usize::from(reader.byte()?);
+        reader.instruction_size = usize::from(reader.byte()?);
+        reader.integer_size = usize::from(reader.byte()?);
+        reader.number_size = usize::from(reader.byte()?);
+        reader.take(reader.integer_size + reader.number_size)?;
+
+        // This is the number of upvalues of the main function.
+        reader.byte()?;
+        Some(reader)
+    }
+
+    fn take(
+        &mut self,
+        size: usize,
+    ) -> Option<&'a [u8]> {
+        if size > self.bytes.len() {
+            return None;
+        }
+        let (taken, rest) = self.bytes.split_at(size);
+        self.bytes = rest;
+        Some(taken)
+    }
+
+    fn byte(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    // Read an unsigned value of the given size, stored in the byte order
+    // of the machine on which the chunk was made, which is this one.
+    fn unsigned(
+        &mut self,
+        size: usize,
+    ) -> Option<usize> {
+        if size > 8 {
+            return None;
+        }
+        let bytes = self.take(size)?;
+        let mut buffer = [0; 8];
+        let value = if cfg!(target_endian = "little") {
+            buffer[..size].copy_from_slice(bytes);
+            u64::from_le_bytes(buffer)
+        } else {
+            buffer[8 - size..].copy_from_slice(bytes);
+            u64::from_be_bytes(buffer)
+        };
+        usize::try_from(value).ok()
+    }
+
+    fn int(&mut self) -> Option<usize> {
+        self.unsigned(self.int_size)
+    }
+
+    fn skip_string(&mut self) -> Option<()> {
+        let size = match self.byte()? {
+            0xFF => self.unsigned(self.size_t_size)?,
+            size => usize::from(size),
+        };
+        self.take(size.saturating_sub(1))?;
+        Some(())
+    }
+
+    // Read a function, adding the lines of its instructions, along with
+    // those of the functions defined inside of it, to the given lines.
+    fn read_function(
+        &mut self,
+        lines: &mut std::collections::BTreeSet<usize>,
+    ) -> Option<()> {
+        // Source name, first and last lines, and the numbers of parameters
+        // and registers, along with whether it takes extra arguments.
+        self.skip_string()?;
+        self.take(self.int_size * 2 + 3)?;
+
+        // Instructions.
+        let instructions = self.int()?;
+        self.take(instructions.checked_mul(self.instruction_size)?)?;
+
+        // Constants.
+        for _ in 0..self.int()? {
+            match self.byte()? {
+                0 => (),
+                1 => {
+                    self.byte()?;
+                },
+                3 => {
+                    self.take(self.number_size)?;
+                },
+                19 => {
+                    self.take(self.integer_size)?;
+                },
+                4 | 20 => self.skip_string()?,
+                _ => return None,
+            }
+        }
+
+        // Upvalues.
+        let upvalues = self.int()?;
+        self.take(upvalues.checked_mul(2)?)?;
+
+        // Functions defined inside of this one.
+        for _ in 0..self.int()? {
+            self.read_function(lines)?;
+        }
+
+        // The line of each instruction.
+        for _ in 0..self.int()? {
+            let line = self.int()?;
+            if line > 0 {
+                lines.insert(line);
+            }
+        }
+
+        // Local variable names, along with where they're in scope.
+        for _ in 0..self.int()? {
+            self.skip_string()?;
+            self.take(self.int_size * 2)?;
+        }
+
+        // Upvalue names.
+        for _ in 0..self.int()? {
+            self.skip_string()?;
+        }
+        Some(())
+    }
+}
@@ -0,0 +1,316 @@
+use super::json;
+use std::io::Write;
+
+// These are the events which occur during a test run, in the order they
+// occur: a `RunStart`, then a `TestStart` followed by either a `TestPass` or
+// a `TestFail` for each test run, or a `TestSkip` for each test not run
+// because a test it depends on didn't pass, and finally a `RunEnd`.  A test
+// which skips itself while running has a `TestStart` followed by a
+// `TestSkip`.
+pub enum Event<'a> {
+    RunStart {
+        total_tests: usize,
+        total_test_suites: usize,
+    },
+    TestStart {
+        suite: &'a str,
+        name: &'a str,
+    },
+    TestPass {
+        suite: &'a str,
+        name: &'a str,
+        elapsed_ms: u128,
+        assertions: usize,
+    },
+    TestFail {
+        suite: &'a str,
+        name: &'a str,
+        elapsed_ms: u128,
+        assertions: usize,
+        messages: &'a [String],
+        aborted: bool,
+        quarantined: bool,
+        unexpectedly_passed: bool,
+    },
+    TestSkip {
+        suite: &'a str,
+        name: &'a str,
+        reason: &'a str,
+    },
+    RunEnd {
+        passed: usize,
+        failed: usize,
+        quarantined: usize,
+        elapsed_ms: u128,
+    },
+}
+
+impl Event<'_> {
+    // Render the event as a single-line JSON object.
+    pub fn to_json(&self) -> String {
+        let fields = match self {
+            Event::RunStart {
+                total_tests,
+                total_test_suites,
+            } => vec![
+                String::from("\"event\": \"run_start\""),
+                format!("\"total_tests\": {}", total_tests),
+                format!("\"total_test_suites\": {}", total_test_suites),
+            ],
+            Event::TestStart {
+                suite,
+                name,
+            } => vec![
+                String::from("\"event\": \"test_start\""),
+                format!("\"suite\": {}", json::string(suite)),
+                format!("\"name\": {}", json::string(name)),
+            ],
+            Event::TestPass {
+                suite,
+                name,
+                elapsed_ms,
+                assertions,
+            } => vec![
+                String::from("\"event\": \"test_pass\""),
+                format!("\"suite\": {}", json::string(suite)),
+                format!("\"name\": {}", json::string(name)),
+                format!("\"elapsed_ms\": {}", elapsed_ms),
+                format!("\"assertions\": {}", assertions),
+            ],
+            Event::TestFail {
+                suite,
+                name,
+                elapsed_ms,
+                assertions,
+                messages,
+                aborted,
+                quarantined,
+                unexpectedly_passed,
+            } => vec![
+                String::from("\"event\": \"test_fail\""),
+                format!("\"suite\": {}", json::string(suite)),
+                format!("\"name\": {}", json::string(name)),
+                format!("\"elapsed_ms\": {}", elapsed_ms),
+                format!("\"assertions\": {}", assertions),
+                format!(
+                    "\"messages\": [{}]",
+                    messages
+                        .iter()
+                        .map(|message| json::string(message))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+                format!("\"aborted\": {}", aborted),
+                format!("\"quarantined\": {}", quarantined),
+                format!("\"unexpectedly_passed\": {}", unexpectedly_passed),
+            ],
+            Event::TestSkip {
+                suite,
+                name,
+                reason,
+            } => vec![
+                String::from("\"event\": \"test_skip\""),
+                format!("\"suite\": {}", json::string(suite)),
+                format!("\"name\": {}", json::string(name)),
+                format!("\"reason\": {}", json::string(reason)),
+            ],
+            Event::RunEnd {
+                passed,
+                failed,
+                quarantined,
+                elapsed_ms,
+            } => vec![
+                String::from("\"event\": \"run_end\""),
+                format!("\"passed\": {}", passed),
+                format!("\"failed\": {}", failed),
+                format!("\"quarantined\": {}", quarantined),
+                format!("\"elapsed_ms\": {}", elapsed_ms),
+            ],
+        };
+        format!("{{{}}}", fields.join(", "))
+    }
+}
+
+pub trait EventSink {
+    fn event(
+        &mut self,
+        event: &Event,
+    );
+}
+
+pub fn emit(
+    sinks: &mut [Box<dyn EventSink>],
+    event: &Event,
+) {
+    for sink in sinks {
+        sink.event(event);
+    }
+}
+
+// This sink streams events over a TCP connection as line-delimited JSON.
+// If the connection fails at any point, a warning is issued and the
+// remaining events are dropped, so that the tests still run locally.
+pub struct SocketSink {
+    address: String,
+    stream: Option<std::net::TcpStream>,
+}
+
+impl SocketSink {
+    pub fn connect(address: &str) -> Self {
+        let stream = match std::net::TcpStream::connect(address) {
+            Ok(stream) => Some(stream),
+            Err(error) => {
+                eprintln!(
+                    "WARNING: Unable to connect to report socket '{}': {}",
+                    address, error
+                );
+                None
+            },
+        };
+        Self {
+            address: String::from(address),
+            stream,
+        }
+    }
+}
+
+impl EventSink for SocketSink {
+    fn event(
+        &mut self,
+        event: &Event,
+    ) {
+        if let Some(stream) = &mut self.stream {
+            let line = format!("{}\n", event.to_json());
+            if let Err(error) = stream.write_all(line.as_bytes()) {
+                eprintln!(
+                    "WARNING: Lost connection to report socket '{}': {}",
+                    self.address, error
+                );
+                self.stream = None;
+            }
+        }
+    }
+}
+
+// This is the prefix of each line of standard output which carries an event
+// from a worker process, as opposed to the normal output of the tests it
+// runs.
+pub const WORKER_EVENT_PREFIX: &str = "@moonunit-event ";
+
+// This sink is used by a worker process running a test suite on behalf of
+// another MoonUnit process, to pass the events of the test run back to it
+// through standard output, mixed in with the normal output.
+pub struct WorkerSink;
+
+impl EventSink for WorkerSink {
+    fn event(
+        &mut self,
+        event: &Event,
+    ) {
+        println!("{}{}", WORKER_EVENT_PREFIX, event.to_json());
+    }
+}
+
+// This sink writes the results of the test run to standard output in the
+// Test Anything Protocol (TAP) format: a plan line giving the number of
+// tests, then an "ok" or "not ok" line for each test, with the details of
+// each failed test in a YAML block following its line.  Failures of
+// quarantined tests are marked with the TODO directive, so that TAP
+// consumers don't count them against the test run, just as MoonUnit
+// doesn't.
+#[derive(Default)]
+pub struct TapSink {
+    tests: usize,
+}
+
+impl TapSink {
+    // Describe a test in a TAP result line.  A '#' would start a directive,
+    // so it has to be escaped.
+    fn description(
+        suite: &str,
+        name: &str,
+    ) -> String {
+        format!("{}.{}", suite, name).replace('#', "\\#")
+    }
+}
+
+impl EventSink for TapSink {
+    fn event(
+        &mut self,
+        event: &Event,
+    ) {
+        match event {
+            Event::RunStart {
+                total_tests,
+                ..
+            } => {
+                self.tests = 0;
+                println!("1..{}", total_tests);
+            },
+            Event::TestPass {
+                suite,
+                name,
+                ..
+            } => {
+                self.tests += 1;
+                println!(
+                    "ok {} - {}",
+                    self.tests,
+                    Self::description(suite, name)
+                );
+            },
+            Event::TestSkip {
+                suite,
+                name,
+                reason,
+            } => {
+                self.tests += 1;
+                println!(
+                    "ok {} - {} # SKIP {}",
+                    self.tests,
+                    Self::description(suite, name),
+                    reason
+                );
+            },
+            Event::TestFail {
+                suite,
+                name,
+                elapsed_ms,
+                messages,
+                aborted,
+                quarantined,
+                ..
+            } => {
+                self.tests += 1;
+                println!(
+                    "not ok {} - {}{}",
+                    self.tests,
+                    Self::description(suite, name),
+                    if *quarantined {
+                        " # TODO quarantined"
+                    } else {
+                        ""
+                    }
+                );
+                println!("  ---");
+                println!("  elapsed_ms: {}", elapsed_ms);
+                println!("  aborted: {}", aborted);
+                if messages.is_empty() {
+                    println!("  messages: []");
+                } else {
+                    println!("  messages:");
+                    for message in messages.iter() {
+                        println!("    - {}", json::string(message));
+                    }
+                }
+                println!("  ...");
+            },
+            Event::TestStart {
+                ..
+            }
+            | Event::RunEnd {
+                ..
+            } => (),
+        }
+    }
+}
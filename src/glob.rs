@@ -0,0 +1,164 @@
+// Render a path as a string which can be matched against a glob pattern,
+// using '/' as the path delimiter.
+pub fn path_string(path: &std::path::Path) -> String {
+    path.to_string_lossy().replace(std::path::MAIN_SEPARATOR, "/")
+}
+
+// Tell whether a pattern has any wildcards in it.
+pub fn has_wildcards(pattern: &str) -> bool {
+    pattern.contains(|c: char| c == '*' || c == '?')
+}
+
+// Find the files which match a glob pattern (as in `matches_path`), sorted
+// by path.  Only the folder named by the components of the pattern before
+// the first one with a wildcard is searched, along with its subfolders.
+pub fn find_files(pattern: &std::path::Path) -> Vec<std::path::PathBuf> {
+    let pattern_string = path_string(pattern);
+    let base = pattern
+        .components()
+        .take_while(|component| {
+            !has_wildcards(&component.as_os_str().to_string_lossy())
+        })
+        .collect::<std::path::PathBuf>();
+    let mut files = Vec::new();
+    collect_files(&base, &mut files, &mut std::collections::HashSet::new());
+    files.retain(|file| matches_path(&pattern_string, &path_string(file)));
+    files.sort();
+    files
+}
+
+// Add the files in the given folder and its subfolders to the given files.
+// Subfolders reached through symbolic links are followed, but each folder
+// is only searched once, by its canonical path, so that links which lead
+// back to one of their own ancestors don't make the search go on forever.
+fn collect_files(
+    folder: &std::path::Path,
+    files: &mut Vec<std::path::PathBuf>,
+    visited: &mut std::collections::HashSet<std::path::PathBuf>,
+) {
+    match folder.canonicalize() {
+        Ok(canonical_folder) => {
+            if !visited.insert(canonical_folder) {
+                return;
+            }
+        },
+        Err(_) => return,
+    }
+    for path in std::fs::read_dir(folder)
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .map(|dir_entry| dir_entry.path())
+    {
+        if path.is_dir() {
+            collect_files(&path, files, visited);
+        } else {
+            files.push(path);
+        }
+    }
+}
+
+// Match a path against a glob pattern, where both use '/' as the path
+// delimiter.  In the pattern, '*' matches any sequence of characters
+// within a single path component, '?' matches any single character
+// other than a path delimiter, and '**' matches any sequence of characters
+// including path delimiters (so "a/**/b" matches "a/b", "a/x/b", "a/x/y/b",
+// etc.).
+pub fn matches_path(
+    pattern: &str,
+    path: &str,
+) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let path = path.chars().collect::<Vec<_>>();
+    match_path_chars(&pattern, &path)
+}
+
+fn match_path_chars(
+    pattern: &[char],
+    path: &[char],
+) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some(('*', rest)) => {
+            if let Some(('*', rest)) = rest.split_first() {
+                // A "**/" is also allowed to match no directories at all.
+                let matches_no_directories = match rest.split_first() {
+                    Some(('/', rest)) => match_path_chars(rest, path),
+                    _ => false,
+                };
+                matches_no_directories
+                    || (0..=path.len())
+                        .any(|i| match_path_chars(rest, &path[i..]))
+            } else {
+                (0..=path.len())
+                    .take_while(|&i| i == 0 || path[i - 1] != '/')
+                    .any(|i| match_path_chars(rest, &path[i..]))
+            }
+        },
+        Some(('?', rest)) => match path.split_first() {
+            Some((c, path)) if *c != '/' => match_path_chars(rest, path),
+            _ => false,
+        },
+        Some((c, rest)) => match path.split_first() {
+            Some((d, path)) if c == d => match_path_chars(rest, path),
+            _ => false,
+        },
+    }
+}
+
+// Match the full name of a test ("SUITE.NAME") against a Google Test
+// filter.  The filter is a ':'-separated list of positive patterns,
+// optionally followed by '-' and a ':'-separated list of negative patterns.
+// The '-' only starts the negative patterns at the start of the filter or
+// right after a ':', so that names containing '-' can still be matched
+// exactly.  A test matches if it matches any positive pattern (or there
+// are none) and no negative pattern.  In the patterns, '*' matches any
+// sequence of characters and '?' matches any single character.
+pub fn matches_filter(
+    filter: &str,
+    name: &str,
+) -> bool {
+    let delimiter_index = filter.char_indices().find_map(|(index, c)| {
+        if c == '-' && (index == 0 || filter[..index].ends_with(':')) {
+            Some(index)
+        } else {
+            None
+        }
+    });
+    let (positive, negative) = match delimiter_index {
+        Some(delimiter_index) => {
+            (&filter[..delimiter_index], &filter[delimiter_index + 1..])
+        },
+        None => (filter, ""),
+    };
+    let name = name.chars().collect::<Vec<_>>();
+    let matches_any = |patterns: &str| {
+        patterns.split(':').filter(|pattern| !pattern.is_empty()).any(
+            |pattern| {
+                let pattern = pattern.chars().collect::<Vec<_>>();
+                match_wildcard_chars(&pattern, &name)
+            },
+        )
+    };
+    (positive.is_empty() || matches_any(positive)) && !matches_any(negative)
+}
+
+fn match_wildcard_chars(
+    pattern: &[char],
+    name: &[char],
+) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some(('*', rest)) => {
+            (0..=name.len()).any(|i| match_wildcard_chars(rest, &name[i..]))
+        },
+        Some(('?', rest)) => match name.split_first() {
+            Some((_, name)) => match_wildcard_chars(rest, name),
+            None => false,
+        },
+        Some((c, rest)) => match name.split_first() {
+            Some((d, name)) if c == d => match_wildcard_chars(rest, name),
+            _ => false,
+        },
+    }
+}
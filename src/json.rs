@@ -0,0 +1,356 @@
+use std::fmt::Write;
+
+// Render the given text as a JSON string, including the surrounding quotes.
+pub fn string(value: &str) -> String {
+    let mut buffer = String::with_capacity(value.len() + 2);
+    buffer.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => buffer.push_str("\\\""),
+            '\\' => buffer.push_str("\\\\"),
+            '\n' => buffer.push_str("\\n"),
+            '\r' => buffer.push_str("\\r"),
+            '\t' => buffer.push_str("\\t"),
+            c if c < ' ' => {
+                write!(&mut buffer, "\\u{:04x}", u32::from(c)).unwrap();
+            },
+            c => buffer.push(c),
+        }
+    }
+    buffer.push('"');
+    buffer
+}
+
+pub enum Value {
+    Null,
+    Boolean(bool),
+    Integer(i64),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    // Look up the value of a member of an object, if this is an object and
+    // it has the member.
+    pub fn get(
+        &self,
+        name: &str,
+    ) -> Option<&Value> {
+        match self {
+            Value::Object(members) => members
+                .iter()
+                .find(|(member_name, _)| member_name == name)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    // Render the value in a canonical form, with the members of objects
+    // sorted by name, and with each array element and object member on
+    // its own line, so that equivalent values are always rendered the same
+    // way, and differences can be found line by line.
+    pub fn to_canonical(&self) -> String {
+        let mut buffer = String::new();
+        self.write_canonical(&mut buffer, 0);
+        buffer
+    }
+
+    fn write_canonical(
+        &self,
+        buffer: &mut String,
+        indentation: usize,
+    ) {
+        match self {
+            Value::Null => buffer.push_str("null"),
+            Value::Boolean(value) => write!(buffer, "{}", value).unwrap(),
+            Value::Integer(value) => write!(buffer, "{}", value).unwrap(),
+            Value::Number(value) => write!(buffer, "{:?}", value).unwrap(),
+            Value::String(value) => buffer.push_str(&string(value)),
+            Value::Array(elements) => {
+                if elements.is_empty() {
+                    buffer.push_str("[]");
+                    return;
+                }
+                buffer.push_str("[\n");
+                for (i, element) in elements.iter().enumerate() {
+                    if i > 0 {
+                        buffer.push_str(",\n");
+                    }
+                    write_indentation(buffer, indentation + 1);
+                    element.write_canonical(buffer, indentation + 1);
+                }
+                buffer.push('\n');
+                write_indentation(buffer, indentation);
+                buffer.push(']');
+            },
+            Value::Object(members) => {
+                if members.is_empty() {
+                    buffer.push_str("{}");
+                    return;
+                }
+                let mut members = members.iter().collect::<Vec<_>>();
+                members.sort_by(|(lhs, _), (rhs, _)| lhs.cmp(rhs));
+                buffer.push_str("{\n");
+                for (i, (name, value)) in members.into_iter().enumerate() {
+                    if i > 0 {
+                        buffer.push_str(",\n");
+                    }
+                    write_indentation(buffer, indentation + 1);
+                    buffer.push_str(&string(name));
+                    buffer.push_str(": ");
+                    value.write_canonical(buffer, indentation + 1);
+                }
+                buffer.push('\n');
+                write_indentation(buffer, indentation);
+                buffer.push('}');
+            },
+        }
+    }
+}
+
+fn write_indentation(
+    buffer: &mut String,
+    indentation: usize,
+) {
+    for _ in 0..indentation {
+        buffer.push_str("  ");
+    }
+}
+
+// This is the deepest that arrays and objects may be nested in a JSON
+// document, to keep the recursive parser from overflowing the stack.
+const MAX_DEPTH: usize = 256;
+
+// Parse the given text as a JSON document.
+pub fn parse(text: &str) -> Result<Value, String> {
+    let mut parser = Parser {
+        chars: text.chars().collect(),
+        position: 0,
+        depth: 0,
+    };
+    let value = parser.parse_value()?;
+    parser.skip_whitespace();
+    if parser.position < parser.chars.len() {
+        Err(parser.error("unexpected text after value"))
+    } else {
+        Ok(value)
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn error(
+        &self,
+        message: &str,
+    ) -> String {
+        format!("{} at character {}", message, self.position + 1)
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).copied()
+    }
+
+    fn advance(&mut self) -> Option<char> {
+        let c = self.peek();
+        self.position += 1;
+        c
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(' ' | '\t' | '\n' | '\r') = self.peek() {
+            self.position += 1;
+        }
+    }
+
+    fn expect_literal(
+        &mut self,
+        literal: &str,
+        value: Value,
+    ) -> Result<Value, String> {
+        for expected in literal.chars() {
+            if self.advance() != Some(expected) {
+                self.position -= 1;
+                return Err(self.error("invalid literal"));
+            }
+        }
+        Ok(value)
+    }
+
+    fn parse_value(&mut self) -> Result<Value, String> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('n') => self.expect_literal("null", Value::Null),
+            Some('t') => self.expect_literal("true", Value::Boolean(true)),
+            Some('f') => self.expect_literal("false", Value::Boolean(false)),
+            Some('"') => self.parse_string().map(Value::String),
+            Some('[') => self.parse_nested(Self::parse_array),
+            Some('{') => self.parse_nested(Self::parse_object),
+            Some('-' | '0'..='9') => self.parse_number(),
+            Some(_) => Err(self.error("unexpected character")),
+            None => Err(self.error("unexpected end of text")),
+        }
+    }
+
+    fn parse_nested(
+        &mut self,
+        parse: fn(&mut Self) -> Result<Value, String>,
+    ) -> Result<Value, String> {
+        if self.depth == MAX_DEPTH {
+            return Err(self.error("arrays or objects nested too deeply"));
+        }
+        self.depth += 1;
+        let value = parse(self);
+        self.depth -= 1;
+        value
+    }
+
+    fn parse_number(&mut self) -> Result<Value, String> {
+        let start = self.position;
+        let mut is_integer = true;
+        while let Some(c) = self.peek() {
+            match c {
+                '0'..='9' | '-' | '+' => (),
+                '.' | 'e' | 'E' => is_integer = false,
+                _ => break,
+            }
+            self.position += 1;
+        }
+        let text = self.chars[start..self.position].iter().collect::<String>();
+        if is_integer {
+            if let Ok(value) = text.parse() {
+                return Ok(Value::Integer(value));
+            }
+        }
+        text.parse().map(Value::Number).map_err(|_| {
+            self.position = start;
+            self.error("invalid number")
+        })
+    }
+
+    fn parse_hex_escape(&mut self) -> Result<u32, String> {
+        let mut code = 0;
+        for _ in 0..4 {
+            let digit = self
+                .advance()
+                .and_then(|c| c.to_digit(16))
+                .ok_or_else(|| self.error("invalid unicode escape"))?;
+            code = code * 16 + digit;
+        }
+        Ok(code)
+    }
+
+    // Parse the escape of the low surrogate which should follow the escape
+    // of a high surrogate.  If anything else follows, nothing is consumed.
+    fn parse_low_surrogate(&mut self) -> Result<Option<u32>, String> {
+        let start = self.position;
+        if self.advance() == Some('\\') && self.advance() == Some('u') {
+            let code = self.parse_hex_escape()?;
+            if (0xDC00..0xE000).contains(&code) {
+                return Ok(Some(code));
+            }
+        }
+        self.position = start;
+        Ok(None)
+    }
+
+    fn parse_string(&mut self) -> Result<String, String> {
+        self.position += 1;
+        let mut value = String::new();
+        loop {
+            match self.advance() {
+                None => return Err(self.error("unterminated string")),
+                Some('"') => return Ok(value),
+                Some('\\') => match self.advance() {
+                    Some('"') => value.push('"'),
+                    Some('\\') => value.push('\\'),
+                    Some('/') => value.push('/'),
+                    Some('b') => value.push('\u{8}'),
+                    Some('f') => value.push('\u{c}'),
+                    Some('n') => value.push('\n'),
+                    Some('r') => value.push('\r'),
+                    Some('t') => value.push('\t'),
+                    Some('u') => {
+                        let mut code = self.parse_hex_escape()?;
+                        if (0xD800..0xDC00).contains(&code) {
+                            if let Some(low) = self.parse_low_surrogate()? {
+                                code = 0x10000
+                                    + ((code - 0xD800) << 10)
+                                    + (low - 0xDC00);
+                            }
+                        }
+
+                        // Surrogates which aren't part of a pair are
+                        // replaced, since they aren't characters.
+                        value.push(
+                            std::char::from_u32(code)
+                                .unwrap_or(std::char::REPLACEMENT_CHARACTER),
+                        );
+                    },
+                    _ => return Err(self.error("invalid escape")),
+                },
+                Some(c) => value.push(c),
+            }
+        }
+    }
+
+    fn parse_array(&mut self) -> Result<Value, String> {
+        self.position += 1;
+        let mut elements = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(']') {
+            self.position += 1;
+            return Ok(Value::Array(elements));
+        }
+        loop {
+            elements.push(self.parse_value()?);
+            self.skip_whitespace();
+            match self.advance() {
+                Some(',') => (),
+                Some(']') => return Ok(Value::Array(elements)),
+                _ => {
+                    self.position -= 1;
+                    return Err(self.error("expected ',' or ']'"));
+                },
+            }
+        }
+    }
+
+    fn parse_object(&mut self) -> Result<Value, String> {
+        self.position += 1;
+        let mut members = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some('}') {
+            self.position += 1;
+            return Ok(Value::Object(members));
+        }
+        loop {
+            self.skip_whitespace();
+            if self.peek() != Some('"') {
+                return Err(self.error("expected string key"));
+            }
+            let key = self.parse_string()?;
+            self.skip_whitespace();
+            if self.advance() != Some(':') {
+                self.position -= 1;
+                return Err(self.error("expected ':'"));
+            }
+            members.push((key, self.parse_value()?));
+            self.skip_whitespace();
+            match self.advance() {
+                Some(',') => (),
+                Some('}') => return Ok(Value::Object(members)),
+                _ => {
+                    self.position -= 1;
+                    return Err(self.error("expected ',' or '}'"));
+                },
+            }
+        }
+    }
+}
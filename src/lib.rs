@@ -0,0 +1,12 @@
+#![warn(clippy::pedantic)]
+
+// The modules of the MoonUnit program are also built as a library, so that
+// other programs can discover and run Lua tests with a `runner::Runner` of
+// their own, such as a long-lived host process which runs tests again and
+// again, or one which makes its own globals visible to the tests.
+mod coverage;
+pub mod events;
+pub mod glob;
+pub mod json;
+pub mod random;
+pub mod runner;
@@ -1,15 +1,95 @@
 #![warn(clippy::pedantic)]
 
-mod runner;
-
+use moon_unit::{
+    events,
+    glob,
+    json,
+    random,
+    runner,
+};
 use std::{
     io::Write,
     usize,
 };
 use structopt::StructOpt;
 
+// When the report is written to standard output, the normal console output
+// is written to standard error instead, so that the two aren't mixed.
+static CONSOLE_TO_STDERR: std::sync::atomic::AtomicBool =
+    std::sync::atomic::AtomicBool::new(false);
+
+macro_rules! console {
+    ($($arg:tt)*) => {
+        if CONSOLE_TO_STDERR.load(std::sync::atomic::Ordering::Relaxed) {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+
+// Whether the markers in the console output are colored, as Google Test
+// does, using ANSI escape sequences.
+static CONSOLE_COLOR: std::sync::atomic::AtomicBool =
+    std::sync::atomic::AtomicBool::new(false);
+
+fn colored(
+    marker: &str,
+    color: &str,
+) -> String {
+    if CONSOLE_COLOR.load(std::sync::atomic::Ordering::Relaxed) {
+        format!("\x1b[0;{}m{}\x1b[m", color, marker)
+    } else {
+        marker.to_owned()
+    }
+}
+
+fn green(marker: &str) -> String {
+    colored(marker, "32")
+}
+
+fn red(marker: &str) -> String {
+    colored(marker, "31")
+}
+
+// Decide whether to color the console output, given the value of the
+// "--gtest_color" option.  Like Google Test, "auto" (the default) colors
+// the output only if it's going to a terminal which isn't "dumb", and any
+// value not meaning "yes" or "auto" is taken to mean "no".
+fn use_color(gtest_color: Option<&str>) -> bool {
+    use std::io::IsTerminal;
+    let gtest_color = gtest_color.unwrap_or("auto").to_ascii_lowercase();
+    match gtest_color.as_str() {
+        "auto" => {
+            let is_terminal =
+                if CONSOLE_TO_STDERR.load(std::sync::atomic::Ordering::Relaxed)
+                {
+                    std::io::stderr().is_terminal()
+                } else {
+                    std::io::stdout().is_terminal()
+                };
+            is_terminal
+                && std::env::var("TERM").map_or(false, |term| term != "dumb")
+        },
+        "yes" | "true" | "t" | "1" => true,
+        _ => false,
+    }
+}
+
+// Options are listed in the help in two groups: first the options which
+// are MoonUnit's own, and then the options which mimic Google Test, so that
+// it's clear which options are MoonUnit features and which are only there
+// for compatibility.
+const MOONUNIT_OPTIONS: usize = 1;
+const GTEST_OPTIONS: usize = 2;
+
 #[allow(clippy::doc_markdown)]
-#[structopt(verbatim_doc_comment)]
+#[structopt(
+    verbatim_doc_comment,
+    after_help = "Options whose names begin with 'gtest_' mimic those of \
+    Google Test, so that MoonUnit can stand in for a Google Test program, \
+    and are listed after MoonUnit's own options in each section above."
+)]
 /// NOTE: The block below is required to fool 'C++ TestMate' -- DO NOT TOUCH
 /// ----------------------------------------------------------------
 /// This program contains tests written using Google Test.
@@ -42,166 +122,1442 @@ struct Opts {
     /// specifying paths to directories containing Lua test files to run
     /// (or other '.moonunit' files) or individual Lua test files to run.
     /// If not specified, the current working directory is used instead.
-    #[structopt(long, default_value = ".")]
+    #[structopt(long, default_value = ".", display_order = MOONUNIT_OPTIONS)]
     path: std::path::PathBuf,
 
     /// List the names of all tests instead of running them
-    #[structopt(long = "gtest_list_tests")]
+    #[structopt(long = "gtest_list_tests", display_order = GTEST_OPTIONS)]
     gtest_list_tests: bool,
 
-    /// One or more test names separated by colons, which selects
-    /// just the named tests to be run.
+    /// List the tests which would be run, instead of running them,
+    /// as a JSON document containing the suite, name, file, line number,
+    /// tags, description, and whether or not it's disabled, for each test.
+    #[structopt(long = "list-json", display_order = MOONUNIT_OPTIONS)]
+    list_json: bool,
+
+    /// List every tag given to the tests which would be run, along with
+    /// the number of those tests having each tag, instead of running them.
+    #[structopt(long = "list-tags", display_order = MOONUNIT_OPTIONS)]
+    list_tags: bool,
+
+    /// One or more test name patterns separated by colons, which selects
+    /// just the matching tests to be run, optionally followed by '-' and
+    /// more patterns, which leave out matching tests.  In a pattern, '*'
+    /// matches any sequence of characters and '?' any single character.
     /// If not specified, all discovered tests will be run.
-    #[structopt(long = "gtest_filter")]
+    #[structopt(long = "gtest_filter", display_order = GTEST_OPTIONS)]
     gtest_filter: Option<String>,
 
-    /// The relative or absolute path to an XML file to be generated
-    /// containing a report about the tests discovered by the test runner,
-    /// in a format compatible with Google Test.
+    /// A glob pattern matched against the path of each Lua test file,
+    /// selecting just the tests from matching files to be run.
+    /// May be given more than once.  Relative patterns are resolved
+    /// against the current working directory.  In a pattern, '*' and '?'
+    /// do not match path delimiters, while '**' matches any number of
+    /// directories (for example, 'src/net/**').
+    /// If combined with --gtest_filter, a test must match both to be run.
+    #[structopt(long = "include-glob", display_order = MOONUNIT_OPTIONS)]
+    include_globs: Vec<String>,
+
+    /// A glob pattern matched against the path of each Lua test file,
+    /// excluding the tests from matching files from being run.
+    /// May be given more than once, and uses the same pattern syntax
+    /// as --include-glob.
+    #[structopt(long = "exclude-glob", display_order = MOONUNIT_OPTIONS)]
+    exclude_globs: Vec<String>,
+
+    /// A git revision (such as a branch name or commit hash), selecting
+    /// only the tests from Lua test files which were changed since then,
+    /// or which git isn't tracking yet.  If git can't find the changed
+    /// files, such as when the tests aren't in a git repository, all
+    /// tests are run instead.
+    #[structopt(long = "changed-since", display_order = MOONUNIT_OPTIONS)]
+    changed_since: Option<String>,
+
+    /// The format ('xml' or 'json') and the relative or absolute path of a
+    /// file to be generated containing a report about the tests discovered
+    /// by the test runner, in a format compatible with Google Test.  If the
+    /// path is '-' (as in 'xml:-'), the report is written to standard output
+    /// instead, and the normal output is written to standard error.
     /// Unless this is specified, no report will be generated.
-    #[structopt(long = "gtest_output")]
+    #[structopt(long = "gtest_output", display_order = GTEST_OPTIONS)]
     gtest_output: Option<String>,
 
-    #[structopt(long = "gtest_color")]
+    /// The maximum number of expectation failures to report for any one
+    /// test.  Further failures still cause the test to fail, but are only
+    /// counted, and the number of them is reported after the others.
+    /// If not specified, all failures are reported.
+    #[structopt(
+        long = "max-failures-per-test",
+        display_order = MOONUNIT_OPTIONS,
+    )]
+    max_failures_per_test: Option<usize>,
+
+    /// The number of expectation failures after which a test is stopped,
+    /// as if an assertion had failed.  Tests may override this with the
+    /// 'abort_after_failures' test option.
+    /// If not specified, tests are not stopped by expectation failures.
+    #[structopt(
+        long = "abort-after-failures",
+        display_order = MOONUNIT_OPTIONS,
+    )]
+    abort_after_failures: Option<usize>,
+
+    /// Stop running tests as soon as any test fails, including any further
+    /// repetitions of the tests.
+    #[structopt(long = "fail-fast", display_order = MOONUNIT_OPTIONS)]
+    fail_fast: bool,
+
+    /// The maximum total time, in milliseconds, which running all the
+    /// tests may take.  If the test run takes longer than this, it fails,
+    /// even if every test passed.
+    /// If not specified, the test run may take any amount of time.
+    #[structopt(long = "max-total-time", display_order = MOONUNIT_OPTIONS)]
+    max_total_time: Option<u128>,
+
+    /// The maximum time, in milliseconds, which any one test may run.
+    /// A test which runs longer than this is stopped and fails, and the
+    /// run continues with the next test.
+    /// If not specified, tests may run for any amount of time.
+    #[structopt(long = "timeout", display_order = MOONUNIT_OPTIONS)]
+    timeout: Option<u64>,
+
+    /// After running tests, show how many tests passed in each test suite,
+    /// before the overall totals.
+    #[structopt(long = "suite-summary", display_order = MOONUNIT_OPTIONS)]
+    suite_summary: bool,
+
+    /// After running tests, show how much of the time was spent discovering
+    /// tests, making Lua states, executing test scripts, and running the
+    /// bodies of the tests.
+    #[structopt(long = "profile", display_order = MOONUNIT_OPTIONS)]
+    profile: bool,
+
+    /// Execute each test script only once, and run all of its tests in the
+    /// same Lua state, rather than executing the script again in a fresh
+    /// Lua state for each test.  This makes large test suites faster to
+    /// run.  The globals of each script are put back the way the script
+    /// left them before each of its tests is run, unless --share-globals
+    /// is also given.
+    #[structopt(long = "reuse-lua-states", display_order = MOONUNIT_OPTIONS)]
+    reuse_lua_states: bool,
+
+    /// When reusing Lua states, don't put the globals of each test script
+    /// back the way the script left them before each test, so that tests of
+    /// the same script share any globals they set.  This saves a little
+    /// more time, but tests are no longer isolated from each other.
+    #[structopt(long = "share-globals", display_order = MOONUNIT_OPTIONS)]
+    share_globals: bool,
+
+    /// Run test scripts in sandboxed Lua states, without the debug and
+    /// io libraries, the functions of the os library other than those
+    /// dealing with time, or the ability to load C modules or precompiled
+    /// chunks.
+    #[structopt(long = "safe", display_order = MOONUNIT_OPTIONS)]
+    safe: bool,
+
+    /// Warn about tests which may be ineffective, such as tests which
+    /// don't make any function calls at all.
+    #[structopt(long = "strict", display_order = MOONUNIT_OPTIONS)]
+    strict: bool,
+
+    /// Run tests tagged 'quarantine' as quarantined tests: they are still
+    /// run and their results shown, but their failures are listed
+    /// separately and don't cause the test run to fail.
+    #[structopt(long = "quarantine", display_order = MOONUNIT_OPTIONS)]
+    quarantine: bool,
+
+    /// The relative or absolute path to a file to be generated containing
+    /// the line coverage of the Lua code run by the tests, in the LCOV
+    /// format.  Unless this is specified, coverage is not gathered.
+    #[structopt(long = "coverage", display_order = MOONUNIT_OPTIONS)]
+    coverage: Option<std::path::PathBuf>,
+
+    /// Instead of comparing values against golden files in
+    /// golden-file assertions, overwrite the golden files
+    /// with the values given.
+    #[structopt(long = "update-golden", display_order = MOONUNIT_OPTIONS)]
+    update_golden: bool,
+
+    /// The address (host:port) of a TCP server to which to stream events
+    /// about the test run, such as the start, passing, or failure of
+    /// each test, as line-delimited JSON while the tests are run.
+    /// If the connection fails, tests are still run locally.
+    #[structopt(long = "report-socket", display_order = MOONUNIT_OPTIONS)]
+    report_socket: Option<String>,
+
+    /// Write the results of the test run to standard output in the Test
+    /// Anything Protocol (TAP) format, and write the normal output to
+    /// standard error instead, so that the two aren't mixed.
+    #[structopt(long = "tap", display_order = MOONUNIT_OPTIONS)]
+    tap: bool,
+
+    /// The number of test suites to run at the same time, each in its own
+    /// worker process.  The output of each test suite is shown all
+    /// together once the test suite is done.
+    /// If not specified, test suites are run one at a time.
+    #[structopt(long = "jobs", display_order = MOONUNIT_OPTIONS)]
+    jobs: Option<usize>,
+
+    /// The order in which to run tests: 'defined' (the order in which the
+    /// tests are defined), 'alpha' (sorted by test suite and test name),
+    /// or 'random' (shuffled, using the seed given by --test-order-seed).
+    #[structopt(
+        long = "test-order",
+        default_value = "defined",
+        possible_values = &["defined", "alpha", "random"],
+        display_order = MOONUNIT_OPTIONS,
+    )]
+    test_order: TestOrder,
+
+    /// The seed to use for shuffling tests when --test-order=random.
+    /// If not specified, a seed is made from the current time.
+    #[structopt(long = "test-order-seed", display_order = MOONUNIT_OPTIONS)]
+    test_order_seed: Option<u64>,
+
+    /// Seed Lua's random number generator (math.randomseed) before each
+    /// test is run, so that tests using math.random are reproducible.
+    /// The seed is either a number, used for every test, or 'name',
+    /// which derives a seed for each test from its suite and test names.
+    #[structopt(
+        long = "fixture-seed",
+        parse(try_from_str = parse_fixture_seed),
+        display_order = MOONUNIT_OPTIONS,
+    )]
+    fixture_seed: Option<runner::FixtureSeed>,
+
+    /// Color the test result markers in the output: 'yes', 'no', or
+    /// 'auto' (the default) to color them only when writing to a terminal.
+    #[structopt(long = "gtest_color", display_order = GTEST_OPTIONS)]
     gtest_color: Option<String>,
 
-    #[structopt(long = "gtest_also_run_disabled_tests")]
+    /// Run disabled tests (those whose test suite or test names begin
+    /// with 'DISABLED_') as well, instead of leaving them out.
+    #[structopt(
+        long = "gtest_also_run_disabled_tests",
+        display_order = GTEST_OPTIONS,
+    )]
     gtest_also_run_disabled_tests: bool,
+
+    /// Run the tests in a random order, the same as --test-order=random.
+    #[structopt(long = "gtest_shuffle", display_order = GTEST_OPTIONS)]
+    gtest_shuffle: bool,
+
+    /// The seed to use for shuffling tests when --gtest_shuffle is given,
+    /// the same as --test-order-seed.  If not specified (or 0), a seed is
+    /// made from the current time.
+    #[structopt(long = "gtest_random_seed", display_order = GTEST_OPTIONS)]
+    gtest_random_seed: Option<u64>,
+
+    /// The number of times to run the tests, as a way to find flaky tests.
+    /// If negative, the tests are run over and over until stopped.  The
+    /// test run fails if any test fails in any of the repetitions.
+    /// If not specified, the tests are run once.
+    #[structopt(
+        long = "gtest_repeat",
+        allow_hyphen_values = true,
+        display_order = GTEST_OPTIONS,
+    )]
+    gtest_repeat: Option<i32>,
+
+    // These are given by MoonUnit to the worker processes it starts to run
+    // test suites in parallel, to select the test suite to run, and to
+    // match the test order and coloring of the output to its own.
+    #[structopt(long = "worker-suite", hidden = true)]
+    worker_suite: Option<String>,
+
+    #[structopt(long = "worker-seed", hidden = true)]
+    worker_seed: Option<u64>,
+
+    #[structopt(long = "worker-color", hidden = true)]
+    worker_color: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum TestOrder {
+    Defined,
+    Alpha,
+    Random,
+}
+
+impl std::str::FromStr for TestOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "defined" => Ok(TestOrder::Defined),
+            "alpha" => Ok(TestOrder::Alpha),
+            "random" => Ok(TestOrder::Random),
+            _ => Err(format!("Unknown test order '{}'", s)),
+        }
+    }
+}
+
+fn parse_fixture_seed(s: &str) -> Result<runner::FixtureSeed, String> {
+    if s == "name" {
+        Ok(runner::FixtureSeed::TestName)
+    } else {
+        s.parse().map(runner::FixtureSeed::Fixed).map_err(|_| {
+            format!("Fixture seed '{}' is neither a number nor 'name'", s)
+        })
+    }
 }
 
 type SelectedTests =
     std::collections::HashMap<String, std::collections::HashSet<String>>;
 
+// This lists the names of test suites to run, in the order to run them,
+// along with the names of the tests to run from each, in order.
+type OrderedTests = Vec<(String, Vec<String>)>;
+
+// This identifies a test by its suite name and test name.
+type TestId = (String, String);
+
+// For each test suite run, this holds the name of the suite, the number of
+// its tests which passed, and the number of its tests which were run.
+type SuiteResults = Vec<(String, usize, usize)>;
+
+// This is the tag which marks a test as known to be flaky, so that its
+// failures don't fail the test run when --quarantine is given.
+const QUARANTINE_TAG: &str = "quarantine";
+
+// On Windows, canonicalizing a path gives it a "verbatim" prefix ("\\?\"),
+// which is confusing when the path is shown, and keeps it from matching
+// paths written the usual way, so it's removed from paths which can be
+// written without it.
+fn strip_verbatim_prefix(path: std::path::PathBuf) -> std::path::PathBuf {
+    let string = path.to_string_lossy().into_owned();
+    if let Some(rest) = string.strip_prefix(r"\\?\UNC\") {
+        return std::path::PathBuf::from(format!(r"\\{}", rest));
+    }
+    if let Some(rest) = string.strip_prefix(r"\\?\") {
+        let mut chars = rest.chars();
+        if let (Some(drive), Some(':')) = (chars.next(), chars.next()) {
+            if drive.is_ascii_alphabetic() {
+                return std::path::PathBuf::from(rest);
+            }
+        }
+    }
+    path
+}
+
+fn select_tests_by_path(
+    opts: &Opts,
+    runner: &runner::Runner,
+    selected_tests: &mut SelectedTests,
+) {
+    let base = std::env::current_dir()
+        .and_then(std::fs::canonicalize)
+        .map(strip_verbatim_prefix)
+        .unwrap_or_default();
+    let resolve = |pattern: &String| glob::path_string(&base.join(pattern));
+    let include_globs =
+        opts.include_globs.iter().map(resolve).collect::<Vec<_>>();
+    let exclude_globs =
+        opts.exclude_globs.iter().map(resolve).collect::<Vec<_>>();
+    selected_tests.retain(|test_suite_name, test_names| {
+        test_names.retain(|test_name| {
+            runner.get_test_path(test_suite_name, test_name).map_or(
+                false,
+                |path| {
+                    let path = glob::path_string(
+                        &path.components().collect::<std::path::PathBuf>(),
+                    );
+                    (include_globs.is_empty()
+                        || include_globs
+                            .iter()
+                            .any(|glob| glob::matches_path(glob, &path)))
+                        && !exclude_globs
+                            .iter()
+                            .any(|glob| glob::matches_path(glob, &path))
+                },
+            )
+        });
+        !test_names.is_empty()
+    });
+}
+
+// Find the Lua files which git reports as changed since the given revision,
+// along with those which git isn't tracking yet, as canonical paths.  Git
+// is run in the folder given by the "--path" option, so that it finds the
+// repository holding the tests.
+fn changed_lua_files(
+    opts: &Opts,
+    revision: &str,
+) -> Result<std::collections::HashSet<std::path::PathBuf>, String> {
+    let git = |args: &[&str]| {
+        let output = std::process::Command::new("git")
+            .args(args)
+            .current_dir(&opts.path)
+            .output()
+            .map_err(|error| format!("unable to run git: {}", error))?;
+        if output.status.success() {
+            Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+        } else {
+            Err(String::from_utf8_lossy(&output.stderr).trim().to_owned())
+        }
+    };
+    let top_level = git(&["rev-parse", "--show-toplevel"])?;
+    let top_level = std::path::Path::new(top_level.trim());
+
+    // The revision comes from the user, so it's kept from being taken as an
+    // option of `git diff` (such as "--output=<file>") if it starts with '-'.
+    let changed =
+        git(&["diff", "--name-only", "--end-of-options", revision, "--"])?;
+    let untracked =
+        git(&["ls-files", "--others", "--exclude-standard", "--full-name"])?;
+    Ok(changed
+        .lines()
+        .chain(untracked.lines())
+        .map(|path| top_level.join(path))
+        .filter(|path| {
+            path.extension().map_or(false, |extension| extension == "lua")
+        })
+        .filter_map(|path| path.canonicalize().ok())
+        .map(strip_verbatim_prefix)
+        .collect())
+}
+
+fn select_tests_by_changes(
+    opts: &Opts,
+    revision: &str,
+    runner: &runner::Runner,
+    selected_tests: &mut SelectedTests,
+) {
+    let quiet = opts.list_json || opts.list_tags || opts.worker_suite.is_some();
+    let changed_files = match changed_lua_files(opts, revision) {
+        Ok(changed_files) => changed_files,
+        Err(error) => {
+            if !quiet {
+                console!(
+                    "Note: Unable to find the files changed since '{}' \
+                    ({}), so all tests will be run",
+                    revision,
+                    error
+                );
+            }
+            return;
+        },
+    };
+    if !quiet {
+        console!(
+            "Note: Running only tests from the {} Lua file{} changed since \
+            '{}'",
+            changed_files.len(),
+            if changed_files.len() == 1 {
+                ""
+            } else {
+                "s"
+            },
+            revision
+        );
+    }
+    selected_tests.retain(|test_suite_name, test_names| {
+        test_names.retain(|test_name| {
+            runner
+                .get_test_path(test_suite_name, test_name)
+                .and_then(|path| path.canonicalize().ok())
+                .map_or(false, |path| {
+                    changed_files.contains(&strip_verbatim_prefix(path))
+                })
+        });
+        !test_names.is_empty()
+    });
+}
+
+// Leave out disabled tests, giving the number of tests left out.
+fn remove_disabled_tests(selected_tests: &mut SelectedTests) -> usize {
+    let mut disabled = 0;
+    selected_tests.retain(|test_suite_name, test_names| {
+        test_names.retain(|test_name| {
+            if is_disabled(test_suite_name, test_name) {
+                disabled += 1;
+                false
+            } else {
+                true
+            }
+        });
+        !test_names.is_empty()
+    });
+    disabled
+}
+
 fn select_tests(
     opts: &Opts,
     runner: &runner::Runner,
-) -> (SelectedTests, usize, usize) {
-    let mut selected_tests = std::collections::HashMap::new();
-    let mut total_tests = 0;
-    let mut total_test_suites = 0;
-    match &opts.gtest_filter {
-        None => {
-            for test_suite_name in runner.get_test_suite_names() {
-                total_test_suites += 1;
-                total_tests += runner.get_test_names(test_suite_name).count();
+) -> (SelectedTests, usize, usize, usize) {
+    if let Some(filter) = &opts.gtest_filter {
+        if !opts.list_json && !opts.list_tags && opts.worker_suite.is_none() {
+            console!("Note: Google Test filter = {}", filter);
+        }
+    }
+    let mut selected_tests = runner
+        .select_tests(opts.gtest_filter.as_deref(), true)
+        .into_iter()
+        .map(|(test_suite_name, test_names)| {
+            (test_suite_name, test_names.into_iter().collect())
+        })
+        .collect::<SelectedTests>();
+    if !opts.include_globs.is_empty() || !opts.exclude_globs.is_empty() {
+        select_tests_by_path(opts, runner, &mut selected_tests);
+    }
+    if let Some(revision) = &opts.changed_since {
+        select_tests_by_changes(opts, revision, runner, &mut selected_tests);
+    }
+
+    // Like Google Test, disabled tests are not run unless requested, but
+    // are still listed.
+    let disabled_tests = if opts.gtest_also_run_disabled_tests
+        || opts.gtest_list_tests
+        || opts.list_json
+    {
+        0
+    } else {
+        remove_disabled_tests(&mut selected_tests)
+    };
+    let total_tests = selected_tests
+        .values()
+        .map(std::collections::HashSet::len)
+        .sum();
+    let total_test_suites = selected_tests.len();
+    (selected_tests, total_tests, total_test_suites, disabled_tests)
+}
+
+fn order_tests(
+    test_order: TestOrder,
+    seed: u64,
+    runner: &runner::Runner,
+    selected_tests: &SelectedTests,
+) -> OrderedTests {
+    let mut ordered_tests = runner
+        .get_test_suite_names()
+        .filter_map(|test_suite_name| {
+            let selected_tests_entry = selected_tests.get(&test_suite_name)?;
+            let test_names = runner
+                .get_test_names(&test_suite_name)
+                .filter(|test_name| selected_tests_entry.contains(test_name))
+                .collect::<Vec<_>>();
+            Some((test_suite_name, test_names))
+        })
+        .collect::<OrderedTests>();
+    match test_order {
+        TestOrder::Defined => {
+            let order = |test_suite_name: &String, test_name: &String| {
+                runner
+                    .get_test_info(test_suite_name, test_name)
+                    .map_or(usize::MAX, |test_info| test_info.order)
+            };
+            for (test_suite_name, test_names) in &mut ordered_tests {
+                test_names.sort_by_key(|test_name| {
+                    order(test_suite_name, test_name)
+                });
             }
+            ordered_tests.sort_by_key(|(test_suite_name, test_names)| {
+                test_names
+                    .first()
+                    .map_or(usize::MAX, |test_name| {
+                        order(test_suite_name, test_name)
+                    })
+            });
         },
-        Some(filter) => {
-            println!("Note: Google Test filter = {}", filter);
-            for filter in filter.split(':') {
-                total_test_suites += 1;
-                if let Some(delimiter_index) = filter.find('.') {
-                    let test_suite_name = &filter[0..delimiter_index];
-                    let test_name = &filter[delimiter_index + 1..];
-                    if selected_tests
-                        .entry(test_suite_name.to_owned())
-                        .or_insert_with(std::collections::HashSet::new)
-                        .insert(test_name.to_owned())
-                    {
-                        total_tests += 1;
-                    }
-                }
+        TestOrder::Alpha => {},
+        TestOrder::Random => {
+            let mut random = random::Random::new(seed);
+            random.shuffle(&mut ordered_tests);
+            for (_, test_names) in &mut ordered_tests {
+                random.shuffle(test_names);
             }
         },
+    }
+    ordered_tests
+}
+
+// Reorder tests so that each test is run after the tests on which it
+// depends, keeping the order otherwise.  The tests of each suite are still
+// run together, so the tests within each suite are sorted by their
+// dependencies on each other, and then the suites themselves are sorted
+// by the dependencies of their tests on tests of other suites.
+// Dependencies on tests which aren't selected to be run are ignored, while
+// circular dependencies, either between tests or between the suites which
+// hold them, are an error.
+fn order_by_dependencies(
+    runner: &runner::Runner,
+    ordered_tests: OrderedTests,
+) -> Result<OrderedTests, String> {
+    let selected = ordered_tests
+        .iter()
+        .flat_map(|(test_suite_name, test_names)| {
+            test_names.iter().map(move |test_name| {
+                (test_suite_name.clone(), test_name.clone())
+            })
+        })
+        .collect::<std::collections::HashSet<TestId>>();
+    let dependencies = |test_suite_name: &String, test_name: &String| {
+        runner.get_test_info(test_suite_name, test_name).map_or_else(
+            Vec::new,
+            |info| {
+                info.depends_on
+                    .into_iter()
+                    .filter(|dependency| selected.contains(dependency))
+                    .collect::<Vec<_>>()
+            },
+        )
     };
-    (selected_tests, total_tests, total_test_suites)
+    let mut test_names_by_suite = std::collections::HashMap::new();
+    let mut suite_dependencies = std::collections::HashMap::new();
+    let mut test_suite_names = Vec::new();
+    for (test_suite_name, test_names) in ordered_tests {
+        let test_names = sort_by_dependencies(test_names, |test_name| {
+            dependencies(&test_suite_name, test_name)
+                .into_iter()
+                .filter(|(dependency_suite_name, _)| {
+                    *dependency_suite_name == test_suite_name
+                })
+                .map(|(_, dependency_name)| dependency_name)
+                .collect()
+        })
+        .map_err(|cycle| {
+            format!(
+                "ERROR: Tests have circular dependencies: {}",
+                cycle
+                    .iter()
+                    .map(|test_name| {
+                        format!("{}.{}", test_suite_name, test_name)
+                    })
+                    .collect::<Vec<_>>()
+                    .join(" -> ")
+            )
+        })?;
+        let mut other_suite_names = Vec::new();
+        for test_name in &test_names {
+            for (dependency_suite_name, _) in
+                dependencies(&test_suite_name, test_name)
+            {
+                if dependency_suite_name != test_suite_name
+                    && !other_suite_names.contains(&dependency_suite_name)
+                {
+                    other_suite_names.push(dependency_suite_name);
+                }
+            }
+        }
+        suite_dependencies.insert(test_suite_name.clone(), other_suite_names);
+        test_names_by_suite.insert(test_suite_name.clone(), test_names);
+        test_suite_names.push(test_suite_name);
+    }
+    let test_suite_names =
+        sort_by_dependencies(test_suite_names, |test_suite_name| {
+            suite_dependencies
+                .get(test_suite_name)
+                .cloned()
+                .unwrap_or_default()
+        })
+        .map_err(|cycle| {
+            format!(
+                "ERROR: Test suites have circular dependencies: {}",
+                cycle.join(" -> ")
+            )
+        })?;
+    Ok(test_suite_names
+        .into_iter()
+        .map(|test_suite_name| {
+            let test_names = test_names_by_suite
+                .remove(&test_suite_name)
+                .unwrap_or_default();
+            (test_suite_name, test_names)
+        })
+        .collect())
+}
+
+// Sort items so that each comes after the items on which it depends, as
+// given by the `dependencies` function, keeping their order otherwise.  If
+// the items have circular dependencies, the chain of items forming the
+// cycle (starting and ending with the same item) is given back instead.
+fn sort_by_dependencies<T, F>(
+    items: Vec<T>,
+    dependencies: F,
+) -> Result<Vec<T>, Vec<T>>
+where
+    T: Clone + Eq + std::hash::Hash,
+    F: Fn(&T) -> Vec<T>,
+{
+    let mut visited = std::collections::HashSet::new();
+    let mut sorted = Vec::new();
+    for item in &items {
+        visit_dependencies(
+            item,
+            &dependencies,
+            &mut visited,
+            &mut Vec::new(),
+            &mut sorted,
+        )?;
+    }
+    Ok(sorted)
+}
+
+// Add an item to the sorted items, after first adding the items on which
+// it depends, keeping track of the chain of dependencies being followed in
+// order to detect circular dependencies.
+fn visit_dependencies<T, F>(
+    item: &T,
+    dependencies: &F,
+    visited: &mut std::collections::HashSet<T>,
+    chain: &mut Vec<T>,
+    sorted: &mut Vec<T>,
+) -> Result<(), Vec<T>>
+where
+    T: Clone + Eq + std::hash::Hash,
+    F: Fn(&T) -> Vec<T>,
+{
+    if visited.contains(item) {
+        return Ok(());
+    }
+    if let Some(start) = chain.iter().position(|other| other == item) {
+        let mut cycle = chain[start..].to_vec();
+        cycle.push(item.clone());
+        return Err(cycle);
+    }
+    chain.push(item.clone());
+    for dependency in dependencies(item) {
+        visit_dependencies(&dependency, dependencies, visited, chain, sorted)?;
+    }
+    chain.pop();
+    visited.insert(item.clone());
+    sorted.push(item.clone());
+    Ok(())
+}
+
+fn is_disabled(
+    test_suite_name: &str,
+    test_name: &str,
+) -> bool {
+    test_suite_name.starts_with("DISABLED_")
+        || test_name.starts_with("DISABLED_")
+}
+
+// This is incremented whenever the layout of the JSON document produced
+// by --list-json changes in a way which could affect its consumers.
+const LIST_JSON_SCHEMA_VERSION: usize = 1;
+
+fn list_tags(
+    runner: &runner::Runner,
+    selected_tests: &SelectedTests,
+) {
+    let mut tag_counts = std::collections::BTreeMap::new();
+    for (test_suite_name, test_names) in selected_tests {
+        for test_name in test_names {
+            if let Some(info) = runner.get_test_info(test_suite_name, test_name)
+            {
+                let tags = info
+                    .tags
+                    .into_iter()
+                    .collect::<std::collections::HashSet<_>>();
+                for tag in tags {
+                    *tag_counts.entry(tag).or_insert(0) += 1;
+                }
+            }
+        }
+    }
+    for (tag, count) in tag_counts {
+        println!(
+            "{} ({} test{})",
+            tag,
+            count,
+            if count == 1 {
+                ""
+            } else {
+                "s"
+            }
+        );
+    }
 }
 
+fn list_tests_json(
+    runner: &runner::Runner,
+    selected_tests: &SelectedTests,
+) {
+    let mut test_suite_names = selected_tests.keys().collect::<Vec<_>>();
+    test_suite_names.sort();
+    let mut tests = Vec::new();
+    for test_suite_name in test_suite_names {
+        let mut test_names =
+            selected_tests[test_suite_name].iter().collect::<Vec<_>>();
+        test_names.sort();
+        for test_name in test_names {
+            let info = if let Some(info) =
+                runner.get_test_info(test_suite_name, test_name)
+            {
+                info
+            } else {
+                continue;
+            };
+            let fields = [
+                format!("\"suite\": {}", json::string(test_suite_name)),
+                format!("\"name\": {}", json::string(test_name)),
+                format!(
+                    "\"file\": {}",
+                    json::string(&info.path.to_string_lossy())
+                ),
+                format!("\"line\": {}", info.line_number),
+                format!(
+                    "\"tags\": [{}]",
+                    info.tags
+                        .iter()
+                        .map(|tag| json::string(tag))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+                format!(
+                    "\"description\": {}",
+                    info.description
+                        .as_deref()
+                        .map_or_else(|| String::from("null"), json::string)
+                ),
+                format!(
+                    "\"disabled\": {}",
+                    is_disabled(test_suite_name, test_name)
+                ),
+            ];
+            tests.push(format!(
+                "    {{\n      {}\n    }}",
+                fields.join(",\n      ")
+            ));
+        }
+    }
+    println!("{{");
+    println!("  \"schema_version\": {},", LIST_JSON_SCHEMA_VERSION);
+    println!("  \"tests\": [");
+    if !tests.is_empty() {
+        println!("{}", tests.join(",\n"));
+    }
+    println!("  ]");
+    println!("}}");
+}
+
+fn tests_from_suite(
+    total_tests: usize,
+    test_suite_name: &str,
+) -> String {
+    format!(
+        "{} test{} from {}",
+        total_tests,
+        if total_tests == 1 {
+            ""
+        } else {
+            "s"
+        },
+        test_suite_name
+    )
+}
+
+// Show the outcome of calling the before_all or after_all function of a
+// test suite.
+fn report_suite_hook(
+    hook_name: &str,
+    test_suite_name: &str,
+    result: &runner::TestResult,
+) {
+    for line in &result.messages {
+        console!("{}", line);
+    }
+    if !result.passed {
+        console!(
+            "{} {} of {} ({} ms)",
+            red("[  FAILED  ]"),
+            hook_name,
+            test_suite_name,
+            result.elapsed.as_millis()
+        );
+    }
+}
+
+fn is_quarantined(
+    opts: &Opts,
+    runner: &runner::Runner,
+    test_suite_name: &str,
+    test_name: &str,
+) -> bool {
+    opts.quarantine
+        && runner.get_test_info(test_suite_name, test_name).map_or(
+            false,
+            |info| info.tags.iter().any(|tag| tag == QUARANTINE_TAG),
+        )
+}
+
+fn list_tests(ordered_tests: &OrderedTests) {
+    for (test_suite_name, test_names) in ordered_tests {
+        console!("{}.", test_suite_name);
+        for test_name in test_names {
+            console!("  {}", test_name);
+        }
+    }
+}
+
+// Run the given tests with `Runner::run_all`, showing the progress of the
+// test run as it goes, in the format of Google Test.
+#[allow(clippy::too_many_lines)]
 fn run_tests(
     opts: &Opts,
     runner: &mut runner::Runner,
-    selected_tests: &SelectedTests,
-) -> (bool, usize, Vec<String>, u128) {
-    let mut success = true;
-    let mut passed = 0;
+    ordered_tests: &OrderedTests,
+    event_sinks: &mut [Box<dyn events::EventSink>],
+) -> (bool, usize, Vec<String>, Vec<String>, Vec<String>, SuiteResults, u128) {
+    if opts.gtest_list_tests {
+        list_tests(ordered_tests);
+        return (
+            true,
+            0,
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            SuiteResults::new(),
+            0,
+        );
+    }
     let mut failed = Vec::new();
-    let runner_start_time = std::time::Instant::now();
-    for test_suite_name in runner.get_test_suite_names() {
-        let selected_tests_entry = selected_tests.get(&test_suite_name);
-        if !selected_tests.is_empty() && selected_tests_entry.is_none() {
-            continue;
-        }
-        if opts.gtest_list_tests {
-            println!("{}.", test_suite_name);
-        } else if let Some(selected_tests_entry) = selected_tests_entry {
-            println!(
-                "[----------] {} test{} from {}",
-                selected_tests_entry.len(),
-                if selected_tests_entry.len() == 1 {
-                    ""
-                } else {
-                    "s"
-                },
-                test_suite_name
-            );
-        }
-        let test_suite_start_time = std::time::Instant::now();
-        for test_name in runner.get_test_names(&test_suite_name) {
-            if let Some(selected_tests_entry) = selected_tests_entry {
-                if selected_tests_entry.get(&test_name).is_none() {
-                    continue;
-                }
+    let mut quarantined = Vec::new();
+    let mut skipped = Vec::new();
+    let mut suite_results = SuiteResults::new();
+    let mut test_suite_passed = 0;
+
+    // The observer looks up information about tests through its own handle
+    // to the runner, since the runner is busy running the tests.
+    let test_info_runner = runner.clone();
+    let summary = runner.run_all(ordered_tests, |event| match event {
+        runner::RunEvent::SuiteStart {
+            suite,
+            tests,
+        } => {
+            console!("[----------] {}", tests_from_suite(tests, suite));
+            test_suite_passed = 0;
+            true
+        },
+        runner::RunEvent::SuiteHook(outcome) => {
+            report_suite_hook(&outcome.name, &outcome.suite, &outcome.result);
+            true
+        },
+        runner::RunEvent::TestSkip {
+            suite,
+            name,
+            reason,
+        } => {
+            console!("[  SKIPPED ] {}.{} ({})", suite, name, reason);
+            events::emit(event_sinks, &events::Event::TestSkip {
+                suite,
+                name,
+                reason,
+            });
+            skipped.push(format!("{}.{}", suite, name));
+            true
+        },
+        runner::RunEvent::TestStart {
+            suite,
+            name,
+        } => {
+            console!("{} {}.{}", green("[ RUN      ]"), suite, name);
+            events::emit(event_sinks, &events::Event::TestStart {
+                suite,
+                name,
+            });
+            true
+        },
+        runner::RunEvent::TestEnd(outcome) => {
+            let test_suite_name = &outcome.suite;
+            let test_name = &outcome.name;
+            let result = &outcome.result;
+            let test_elapsed_time = result.elapsed.as_millis();
+
+            // Tests which are expected to fail pass when they fail, and are
+            // marked accordingly.
+            let expect_fail = test_info_runner
+                .get_test_info(test_suite_name, test_name)
+                .map_or(false, |info| info.expect_fail);
+            for line in &result.messages {
+                console!("{}", line);
             }
-            if opts.gtest_list_tests {
-                println!("  {}", test_name);
+            if let (true, Some(reason)) = (result.passed, &result.skipped) {
+                events::emit(event_sinks, &events::Event::TestSkip {
+                    suite: test_suite_name,
+                    name: test_name,
+                    reason,
+                });
+                skipped.push(format!("{}.{}", test_suite_name, test_name));
+                console!(
+                    "[  SKIPPED ] {}.{} ({} ms)",
+                    test_suite_name, test_name, test_elapsed_time,
+                );
+            } else if result.passed {
+                events::emit(event_sinks, &events::Event::TestPass {
+                    suite: test_suite_name,
+                    name: test_name,
+                    elapsed_ms: test_elapsed_time,
+                    assertions: result.assertions,
+                });
+                test_suite_passed += 1;
+                console!(
+                    "{} {}.{} ({} ms)",
+                    green(if expect_fail {
+                        "[    XFAIL ]"
+                    } else {
+                        "[       OK ]"
+                    }),
+                    test_suite_name, test_name, test_elapsed_time,
+                );
+            } else if is_quarantined(
+                opts,
+                &test_info_runner,
+                test_suite_name,
+                test_name,
+            ) {
+                events::emit(event_sinks, &events::Event::TestFail {
+                    suite: test_suite_name,
+                    name: test_name,
+                    elapsed_ms: test_elapsed_time,
+                    assertions: result.assertions,
+                    messages: &result.messages,
+                    aborted: result.aborted,
+                    quarantined: true,
+                    unexpectedly_passed: result.unexpectedly_passed,
+                });
+                quarantined.push(format!("{}.{}", test_suite_name, test_name));
+                console!(
+                    "[ QUARANTINED ] {}.{} ({} ms)",
+                    test_suite_name, test_name, test_elapsed_time,
+                );
             } else {
-                println!("[ RUN      ] {}.{}", test_suite_name, test_name,);
-                let error_messages = std::cell::RefCell::new(Vec::new());
-                let test_start_time = std::time::Instant::now();
-                let test_passed =
-                    runner.run_test(&test_suite_name, &test_name, |message| {
-                        error_messages.borrow_mut().push(message)
-                    });
-                let error_messages = error_messages.borrow();
-                let test_elapsed_time = test_start_time.elapsed().as_millis();
-                if test_passed {
-                    passed += 1;
-                    println!(
-                        "[       OK ] {}.{} ({} ms)",
+                events::emit(event_sinks, &events::Event::TestFail {
+                    suite: test_suite_name,
+                    name: test_name,
+                    elapsed_ms: test_elapsed_time,
+                    assertions: result.assertions,
+                    messages: &result.messages,
+                    aborted: result.aborted,
+                    quarantined: false,
+                    unexpectedly_passed: result.unexpectedly_passed,
+                });
+                if result.unexpectedly_passed {
+                    failed.push(format!(
+                        "{}.{} (unexpectedly passed)",
+                        test_suite_name, test_name
+                    ));
+                    console!(
+                        "{} {}.{} ({} ms)",
+                        red("[    XPASS ]"),
+                        test_suite_name, test_name, test_elapsed_time,
+                    );
+                } else if result.aborted {
+                    failed.push(format!(
+                        "{}.{} (aborted)",
+                        test_suite_name, test_name
+                    ));
+                    console!(
+                        "{} {}.{} ({} ms)",
+                        red("[ ABORTED  ]"),
                         test_suite_name, test_name, test_elapsed_time,
                     );
                 } else {
                     failed.push(format!("{}.{}", test_suite_name, test_name));
-                    if !error_messages.is_empty() {
-                        for line in error_messages.iter() {
-                            println!("{}", line);
-                        }
-                    }
-                    println!(
-                        "[  FAILED  ] {}.{} ({} ms)",
+                    console!(
+                        "{} {}.{} ({} ms)",
+                        red("[  FAILED  ]"),
                         test_suite_name, test_name, test_elapsed_time,
                     );
-                    success = false;
+                }
+
+                // The rest of the test run is skipped, but the `after_all`
+                // function of the test suite is still called.
+                if opts.fail_fast {
+                    return false;
                 }
             }
-        }
-        let test_suite_elapsed_time =
-            test_suite_start_time.elapsed().as_millis();
-        if !opts.gtest_list_tests {
-            if let Some(selected_tests_entry) = selected_tests_entry {
-                println!(
-                    "[----------] {} test{} from {} ({} ms total)\n",
-                    selected_tests_entry.len(),
-                    if selected_tests_entry.len() == 1 {
-                        ""
-                    } else {
-                        "s"
+            true
+        },
+        runner::RunEvent::SuiteEnd {
+            suite,
+            tests,
+            elapsed,
+        } => {
+            console!(
+                "[----------] {} ({} ms total)\n",
+                tests_from_suite(tests, suite),
+                elapsed.as_millis(),
+            );
+            suite_results.push((suite.to_string(), test_suite_passed, tests));
+            true
+        },
+    });
+
+    // The test run fails if any test failed, unless it's quarantined, or
+    // if the `before_all` or `after_all` function of any test suite failed.
+    let success = failed.is_empty()
+        && summary.suite_hooks.iter().all(|outcome| outcome.result.passed);
+    let passed = summary
+        .tests
+        .iter()
+        .filter(|outcome| {
+            outcome.result.passed && outcome.result.skipped.is_none()
+        })
+        .count();
+    (
+        success,
+        passed,
+        failed,
+        quarantined,
+        skipped,
+        suite_results,
+        summary.elapsed.as_millis(),
+    )
+}
+
+// Run the tests of one test suite on behalf of another MoonUnit process
+// which is running test suites in parallel (see `run_tests_in_workers`),
+// writing the normal output of the test suite, mixed with the events of the
+// test run, to standard output.  The exit code tells whether the test suite
+// passed.
+fn run_worker_suite(
+    opts: &Opts,
+    runner: &mut runner::Runner,
+    ordered_tests: OrderedTests,
+    test_suite_name: &str,
+) -> i32 {
+    let ordered_tests = ordered_tests
+        .into_iter()
+        .filter(|(other_test_suite_name, _)| {
+            other_test_suite_name == test_suite_name
+        })
+        .collect::<OrderedTests>();
+    let mut event_sinks: Vec<Box<dyn events::EventSink>> =
+        vec![Box::new(events::WorkerSink)];
+    let (success, ..) =
+        run_tests(opts, runner, &ordered_tests, &mut event_sinks);
+    if success {
+        0
+    } else {
+        1
+    }
+}
+
+// Tell whether any of the given tests depends on a test of another test
+// suite, in which case the test suites can't be run in parallel, since the
+// results of the tests of one test suite are needed to run another.
+fn has_cross_suite_dependencies(
+    runner: &runner::Runner,
+    ordered_tests: &OrderedTests,
+) -> bool {
+    ordered_tests.iter().any(|(test_suite_name, test_names)| {
+        test_names.iter().any(|test_name| {
+            runner.get_test_info(test_suite_name, test_name).map_or(
+                false,
+                |info| {
+                    info.depends_on.iter().any(|(dependency_suite_name, _)| {
+                        dependency_suite_name != test_suite_name
+                    })
+                },
+            )
+        })
+    })
+}
+
+// This is the result of a test, as reported by a worker process.
+enum WorkerTestOutcome {
+    Pass,
+    Fail {
+        messages: Vec<String>,
+        aborted: bool,
+        quarantined: bool,
+        unexpectedly_passed: bool,
+    },
+    Skip {
+        reason: String,
+    },
+}
+
+struct WorkerTestResult {
+    name: String,
+    elapsed_ms: u128,
+    assertions: usize,
+    outcome: WorkerTestOutcome,
+}
+
+// Recover the result of a test from an event passed back by a worker
+// process, if it's an event which gives the result of a test.
+fn parse_worker_event(event: &str) -> Option<WorkerTestResult> {
+    let event = json::parse(event).ok()?;
+    let text = |name: &str| match event.get(name) {
+        Some(json::Value::String(value)) => Some(value.clone()),
+        _ => None,
+    };
+    let flag = |name: &str| {
+        matches!(event.get(name), Some(json::Value::Boolean(true)))
+    };
+    let elapsed_ms = match event.get("elapsed_ms") {
+        Some(json::Value::Integer(elapsed_ms)) => {
+            u128::try_from(*elapsed_ms).unwrap_or(0)
+        },
+        _ => 0,
+    };
+    let assertions = match event.get("assertions") {
+        Some(json::Value::Integer(assertions)) => {
+            usize::try_from(*assertions).unwrap_or(0)
+        },
+        _ => 0,
+    };
+    let outcome = match text("event")?.as_str() {
+        "test_pass" => WorkerTestOutcome::Pass,
+        "test_fail" => WorkerTestOutcome::Fail {
+            messages: match event.get("messages") {
+                Some(json::Value::Array(messages)) => messages
+                    .iter()
+                    .filter_map(|message| match message {
+                        json::Value::String(message) => Some(message.clone()),
+                        _ => None,
+                    })
+                    .collect(),
+                _ => Vec::new(),
+            },
+            aborted: flag("aborted"),
+            quarantined: flag("quarantined"),
+            unexpectedly_passed: flag("unexpectedly_passed"),
+        },
+        "test_skip" => WorkerTestOutcome::Skip {
+            reason: text("reason")?,
+        },
+        _ => return None,
+    };
+    Some(WorkerTestResult {
+        name: text("name")?,
+        elapsed_ms,
+        assertions,
+        outcome,
+    })
+}
+
+// Run a test suite in a worker process, which is this program run again
+// with the same options, along with options selecting the test suite and
+// matching the test order and coloring of the output to ours.
+fn run_worker(
+    test_suite_name: &str,
+    seed: u64,
+) -> std::io::Result<std::process::Output> {
+    let mut command = std::process::Command::new(std::env::current_exe()?);
+    command
+        .args(std::env::args_os().skip(1))
+        .arg(format!("--worker-suite={}", test_suite_name))
+        .arg(format!("--worker-seed={}", seed));
+    if CONSOLE_COLOR.load(std::sync::atomic::Ordering::Relaxed) {
+        command.arg("--worker-color");
+    }
+    command.output()
+}
+
+// Run test suites in parallel, each in its own worker process, since every
+// test changes the current working directory, which is shared by all the
+// threads of a process.  The output of each test suite is shown once the
+// worker running it is done, and the results of its tests are gathered
+// from the events it passes back, so that they can be reported just as if
+// the tests were run by this process.
+#[allow(clippy::too_many_lines)]
+fn run_tests_in_workers(
+    jobs: usize,
+    seed: u64,
+    runner: &mut runner::Runner,
+    selected_tests: &SelectedTests,
+    ordered_tests: &OrderedTests,
+    event_sinks: &mut [Box<dyn events::EventSink>],
+) -> (bool, usize, Vec<String>, Vec<String>, Vec<String>, SuiteResults, u128) {
+    let mut success = true;
+    let mut passed = 0;
+    let mut failed = Vec::new();
+    let mut quarantined = Vec::new();
+    let mut skipped = Vec::new();
+    let mut suite_results = SuiteResults::new();
+    let runner_start_time = std::time::Instant::now();
+    let queue = ordered_tests
+        .iter()
+        .map(|(test_suite_name, _)| test_suite_name.clone())
+        .filter(|test_suite_name| selected_tests.contains_key(test_suite_name))
+        .collect::<std::collections::VecDeque<_>>();
+    let workers = jobs.min(queue.len());
+    let queue = std::sync::Arc::new(std::sync::Mutex::new(queue));
+    let (sender, receiver) = std::sync::mpsc::channel();
+    for _ in 0..workers {
+        let queue = queue.clone();
+        let sender = sender.clone();
+        std::thread::spawn(move || loop {
+            let next = queue.lock().unwrap().pop_front();
+            let test_suite_name = match next {
+                Some(test_suite_name) => test_suite_name,
+                None => break,
+            };
+            let output = run_worker(&test_suite_name, seed);
+            if sender.send((test_suite_name, output)).is_err() {
+                break;
+            }
+        });
+    }
+    drop(sender);
+    for (test_suite_name, output) in receiver {
+        let output = match output {
+            Ok(output) => output,
+            Err(error) => {
+                console!(
+                    "ERROR: Unable to start worker for test suite '{}': {}",
+                    test_suite_name, error
+                );
+                success = false;
+                continue;
+            },
+        };
+        let mut test_suite_passed = 0;
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            let result = if let Some(event) =
+                line.strip_prefix(events::WORKER_EVENT_PREFIX)
+            {
+                parse_worker_event(event)
+            } else {
+                console!("{}", line);
+                continue;
+            };
+            let result = if let Some(result) = result {
+                result
+            } else {
+                continue;
+            };
+            let test_name = &result.name;
+            let full_name = format!("{}.{}", test_suite_name, test_name);
+            if let WorkerTestOutcome::Skip {
+                reason,
+            } = &result.outcome
+            {
+                events::emit(event_sinks, &events::Event::TestSkip {
+                    suite: &test_suite_name,
+                    name: test_name,
+                    reason,
+                });
+                runner.record_result(
+                    test_suite_name.as_str(),
+                    test_name.as_str(),
+                    runner::TestResult {
+                        passed: true,
+                        aborted: false,
+                        skipped: Some(reason.clone()),
+                        elapsed: std::time::Duration::from_millis(
+                            u64::try_from(result.elapsed_ms)
+                                .unwrap_or(u64::MAX),
+                        ),
+                        messages: Vec::new(),
+                        assertions: result.assertions,
+                        unexpectedly_passed: false,
                     },
-                    test_suite_name,
-                    test_suite_elapsed_time,
                 );
+                skipped.push(full_name);
+                continue;
             }
+            events::emit(event_sinks, &events::Event::TestStart {
+                suite: &test_suite_name,
+                name: test_name,
+            });
+            let (test_passed, aborted, unexpectedly_passed, messages) =
+                match result.outcome {
+                    WorkerTestOutcome::Fail {
+                        messages,
+                        aborted,
+                        quarantined: test_quarantined,
+                        unexpectedly_passed,
+                    } => {
+                        events::emit(event_sinks, &events::Event::TestFail {
+                            suite: &test_suite_name,
+                            name: test_name,
+                            elapsed_ms: result.elapsed_ms,
+                            assertions: result.assertions,
+                            messages: &messages,
+                            aborted,
+                            quarantined: test_quarantined,
+                            unexpectedly_passed,
+                        });
+                        if test_quarantined {
+                            quarantined.push(full_name);
+                        } else if unexpectedly_passed {
+                            failed.push(format!(
+                                "{} (unexpectedly passed)",
+                                full_name
+                            ));
+                        } else if aborted {
+                            failed.push(format!("{} (aborted)", full_name));
+                        } else {
+                            failed.push(full_name);
+                        }
+                        (false, aborted, unexpectedly_passed, messages)
+                    },
+                    _ => {
+                        events::emit(event_sinks, &events::Event::TestPass {
+                            suite: &test_suite_name,
+                            name: test_name,
+                            elapsed_ms: result.elapsed_ms,
+                            assertions: result.assertions,
+                        });
+                        passed += 1;
+                        test_suite_passed += 1;
+                        (true, false, false, Vec::new())
+                    },
+                };
+            runner.record_result(
+                test_suite_name.as_str(),
+                test_name.as_str(),
+                runner::TestResult {
+                    passed: test_passed,
+                    aborted,
+                    skipped: None,
+                    elapsed: std::time::Duration::from_millis(
+                        u64::try_from(result.elapsed_ms).unwrap_or(u64::MAX),
+                    ),
+                    messages,
+                    assertions: result.assertions,
+                    unexpectedly_passed,
+                },
+            );
+        }
+
+        // Anything the worker wrote to standard error (such as warnings
+        // about the configuration, or a panic) is shown after its output.
+        for line in String::from_utf8_lossy(&output.stderr).lines() {
+            console!("{}", line);
+        }
+
+        // The worker exits with 0 if the test suite passed, or 1 if it
+        // didn't.  Anything else means the worker itself failed.
+        match output.status.code() {
+            Some(0) => (),
+            Some(1) => success = false,
+            _ => {
+                console!(
+                    "ERROR: Worker for test suite '{}' failed ({})",
+                    test_suite_name, output.status
+                );
+                success = false;
+            },
         }
+        suite_results.push((
+            test_suite_name.clone(),
+            test_suite_passed,
+            selected_tests[&test_suite_name].len(),
+        ));
     }
     let runner_elapsed_time = runner_start_time.elapsed().as_millis();
-    (success, passed, failed, runner_elapsed_time)
+    (
+        success,
+        passed,
+        failed,
+        quarantined,
+        skipped,
+        suite_results,
+        runner_elapsed_time,
+    )
 }
 
 fn run_tests_prelude(
     total_tests: usize,
     total_test_suites: usize,
 ) {
-    println!(
+    console!(
         "[==========] Running {} test{} from {} test suite{}.",
         total_tests,
         if total_tests == 1 {
@@ -216,17 +1572,30 @@ fn run_tests_prelude(
             "s"
         }
     );
-    println!("[----------] Global test environment set-up.");
+    console!("[----------] Global test environment set-up.");
 }
 
 fn run_tests_conclusion(
     total_tests: usize,
     total_test_suites: usize,
     passed: usize,
+    assertions: usize,
     runner_elapsed_time: u128,
+    suite_results: Option<&SuiteResults>,
 ) {
-    println!("[----------] Global test environment tear-down");
-    println!(
+    console!("[----------] Global test environment tear-down");
+    if let Some(suite_results) = suite_results {
+        let mut suite_results = suite_results.iter().collect::<Vec<_>>();
+        suite_results.sort();
+        console!("[----------] Results by test suite:");
+        for (test_suite_name, passed, total) in suite_results {
+            console!(
+                "[----------]   {}: {}/{}",
+                test_suite_name, passed, total
+            );
+        }
+    }
+    console!(
         "[==========] {} test{} from {} test suite{} ran. ({} ms total)",
         total_tests,
         if total_tests == 1 {
@@ -242,8 +1611,18 @@ fn run_tests_conclusion(
         },
         runner_elapsed_time,
     );
-    println!(
-        "[  PASSED  ] {} test{}.",
+    console!(
+        "[==========] {} assertion{} checked.",
+        assertions,
+        if assertions == 1 {
+            ""
+        } else {
+            "s"
+        },
+    );
+    console!(
+        "{} {} test{}.",
+        green("[  PASSED  ]"),
         passed,
         if passed == 1 {
             ""
@@ -253,9 +1632,56 @@ fn run_tests_conclusion(
     );
 }
 
+// Show where the time went, both while discovering tests and while
+// running them.  Making Lua states and executing test scripts happens both
+// during discovery and before each test is run, so discovery overlaps them.
+fn report_profile(
+    discovery_elapsed_time: std::time::Duration,
+    runner_elapsed_time: u128,
+    profile: runner::Profile,
+) {
+    console!("[----------] Profile:");
+    console!(
+        "[----------]   Discovering tests: {} ms",
+        discovery_elapsed_time.as_millis()
+    );
+    console!(
+        "[----------]   Making Lua states: {} ms",
+        profile.state_setup.as_millis()
+    );
+    console!(
+        "[----------]   Executing test scripts: {} ms",
+        profile.script_execution.as_millis()
+    );
+    console!(
+        "[----------]   Running test bodies: {} ms",
+        profile.test_bodies.as_millis()
+    );
+    console!(
+        "[----------]   Running tests in total: {} ms",
+        runner_elapsed_time
+    );
+}
+
+fn report_skipped_tests(skipped: &[String]) {
+    console!(
+        "[  SKIPPED ] {} test{}, listed below:",
+        skipped.len(),
+        if skipped.len() == 1 {
+            ""
+        } else {
+            "s"
+        },
+    );
+    for instance in skipped {
+        console!("[  SKIPPED ] {}", instance);
+    }
+}
+
 fn report_failed_tests(failed: &[String]) {
-    println!(
-        "[  FAILED  ] {} test{}, listed below:",
+    console!(
+        "{} {} test{}, listed below:",
+        red("[  FAILED  ]"),
         failed.len(),
         if failed.len() == 1 {
             ""
@@ -264,10 +1690,10 @@ fn report_failed_tests(failed: &[String]) {
         },
     );
     for instance in failed {
-        println!("[  FAILED  ] {}", instance);
+        console!("{} {}", red("[  FAILED  ]"), instance);
     }
-    println!();
-    println!(
+    console!();
+    console!(
         " {} FAILED TEST{}",
         failed.len(),
         if failed.len() == 1 {
@@ -278,18 +1704,90 @@ fn report_failed_tests(failed: &[String]) {
     );
 }
 
+// Quarantined tests which failed are listed separately from other failed
+// tests, since they don't cause the test run to fail.
+fn report_quarantined_tests(quarantined: &[String]) {
+    console!(
+        "[ QUARANTINED ] {} test{} failed, listed below:",
+        quarantined.len(),
+        if quarantined.len() == 1 {
+            ""
+        } else {
+            "s"
+        },
+    );
+    for instance in quarantined {
+        console!("[ QUARANTINED ] {}", instance);
+    }
+}
+
+#[allow(clippy::too_many_lines)]
 fn app() -> i32 {
     // Parse all command-line options.
     let opts: Opts = Opts::from_args();
 
+    // Running tests changes the current working directory, so relative
+    // paths of files written once the tests are done are resolved against
+    // the directory in which we were started.
+    let original_working_directory = std::env::current_dir().ok();
+    let resolve_output_path = |path: &std::path::Path| {
+        original_working_directory.as_ref().map_or_else(
+            || path.to_path_buf(),
+            |directory| directory.join(path),
+        )
+    };
+    let report_to_stdout = matches!(
+        opts.gtest_output.as_deref(),
+        Some("xml:-" | "json:-")
+    );
+    let tap = opts.tap && !opts.gtest_list_tests;
+    if report_to_stdout && tap {
+        eprintln!(
+            "ERROR: --tap can't be combined with a report written to \
+            standard output, since both would be written there"
+        );
+        return 1;
+    }
+    let jobs = opts.jobs.unwrap_or(1);
+    if jobs > 1 && (opts.coverage.is_some() || opts.profile) {
+        eprintln!(
+            "ERROR: --jobs can't be combined with --coverage or --profile, \
+            since worker processes don't report them"
+        );
+        return 1;
+    }
+    let worker = opts.worker_suite.is_some();
+    if (report_to_stdout || tap) && !worker {
+        CONSOLE_TO_STDERR.store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+    CONSOLE_COLOR.store(
+        if worker {
+            opts.worker_color
+        } else {
+            use_color(opts.gtest_color.as_deref())
+        },
+        std::sync::atomic::Ordering::Relaxed,
+    );
+
     // Locate the highest-level ancestor folder of the current working
     // folder that contains a ".moonunit" file, and configure the runner
     // using it (and any other ".moonunit" files found indirectly).
+    let root = if let Ok(root) = opts.path.canonicalize() {
+        strip_verbatim_prefix(root)
+    } else {
+        eprintln!(
+            "ERROR: path '{}' does not exist or is not accessible",
+            opts.path.display()
+        );
+        return 1;
+    };
     let mut runner = runner::Runner::new();
-    for path in opts
-        .path
-        .canonicalize()
-        .unwrap()
+
+    // Safe mode is set before discovering tests, since test scripts are
+    // executed in order to discover their tests.
+    runner.set_safe(opts.safe);
+    let discovery_start_time = std::time::Instant::now();
+    for path in root
         .ancestors()
         .collect::<Vec<_>>()
         .into_iter()
@@ -304,33 +1802,278 @@ fn app() -> i32 {
         }
     }
 
+    let discovery_elapsed_time = discovery_start_time.elapsed();
+
+    runner.set_abort_after_failures(opts.abort_after_failures);
+    runner.set_coverage(opts.coverage.is_some());
+    runner.set_fixture_seed(opts.fixture_seed);
+    runner.set_max_failures_per_test(opts.max_failures_per_test);
+    runner.set_reuse_lua_states(opts.reuse_lua_states);
+    runner.set_share_globals(opts.share_globals);
+    runner.set_strict(opts.strict);
+    runner.set_timeout(opts.timeout.map(std::time::Duration::from_millis));
+    runner.set_update_golden(opts.update_golden);
+
     // Select which tests to run.
-    let (selected_tests, total_tests, total_test_suites) =
+    let (selected_tests, total_tests, total_test_suites, disabled_tests) =
         select_tests(&opts, &runner);
 
-    // List or run all unit tests.
-    if !opts.gtest_list_tests {
-        run_tests_prelude(total_tests, total_test_suites);
+    // List the tags of the selected tests, if requested, instead of
+    // running them.
+    if opts.list_tags {
+        list_tags(&runner, &selected_tests);
+        return 0;
+    }
+
+    // Output a JSON document listing the selected tests, if requested,
+    // instead of running them.
+    if opts.list_json {
+        list_tests_json(&runner, &selected_tests);
+        return 0;
+    }
+
+    // Set up where to send events about the test run.
+    let mut event_sinks: Vec<Box<dyn events::EventSink>> = Vec::new();
+    if let Some(address) = &opts.report_socket {
+        if !opts.gtest_list_tests {
+            event_sinks.push(Box::new(events::SocketSink::connect(address)));
+        }
+    }
+    if tap {
+        event_sinks.push(Box::new(events::TapSink::default()));
+    }
+
+    // Decide the order in which to list or run the tests.
+    // The Google Test options for shuffling tests are the same as
+    // --test-order=random and --test-order-seed.  Like Google Test, a random
+    // seed of 0 means to make a seed from the current time.
+    let test_order = if opts.gtest_shuffle {
+        TestOrder::Random
+    } else {
+        opts.test_order
+    };
+    let seed = opts
+        .worker_seed
+        .or(opts.test_order_seed)
+        .or(opts.gtest_random_seed.filter(|&seed| seed != 0))
+        .unwrap_or_else(random::seed_from_time);
+    let ordered_tests = match order_by_dependencies(
+        &runner,
+        order_tests(test_order, seed, &runner, &selected_tests),
+    ) {
+        Ok(ordered_tests) => ordered_tests,
+        Err(message) => {
+            eprintln!("{}", message);
+            return 1;
+        },
+    };
+
+    // If this is a worker process, just run the one test suite requested.
+    if let Some(test_suite_name) = &opts.worker_suite {
+        return run_worker_suite(
+            &opts,
+            &mut runner,
+            ordered_tests,
+            test_suite_name,
+        );
     }
-    let (success, passed, failed, runner_elapsed_time) =
-        run_tests(&opts, &mut runner, &selected_tests);
     if !opts.gtest_list_tests {
-        run_tests_conclusion(
-            total_tests,
-            total_test_suites,
+        match test_order {
+            TestOrder::Defined => console!("Note: Test order = defined"),
+            TestOrder::Alpha => console!("Note: Test order = alpha"),
+            TestOrder::Random if opts.gtest_shuffle => {
+                console!(
+                    "Note: Randomizing tests' orders with a seed of {} .",
+                    seed
+                );
+            },
+            TestOrder::Random => {
+                console!("Note: Test order = random (seed {})", seed);
+            },
+        }
+    }
+
+    // Decide whether to run test suites in parallel.
+    let mut parallel = jobs > 1 && !opts.gtest_list_tests;
+    if parallel && has_cross_suite_dependencies(&runner, &ordered_tests) {
+        console!(
+            "Note: Running test suites one at a time, since some tests \
+            depend on tests of other test suites"
+        );
+        parallel = false;
+    }
+    if parallel && opts.fail_fast {
+        console!(
+            "Note: Running test suites one at a time, since the test run \
+            stops at the first failing test"
+        );
+        parallel = false;
+    }
+
+    // List or run all unit tests, as many times as requested.  Like Google
+    // Test, a negative number of repetitions means to repeat forever.
+    let repeat = if opts.gtest_list_tests {
+        1
+    } else {
+        opts.gtest_repeat.unwrap_or(1)
+    };
+    let mut success = true;
+    let mut iteration = 0;
+    let mut total_runner_elapsed_time = 0;
+    while repeat < 0 || iteration < repeat {
+        iteration += 1;
+        if repeat != 1 {
+            console!("\nRepeating all tests (iteration {}) . . .\n", iteration);
+        }
+        if !opts.gtest_list_tests {
+            run_tests_prelude(total_tests, total_test_suites);
+            events::emit(&mut event_sinks, &events::Event::RunStart {
+                total_tests,
+                total_test_suites,
+            });
+        }
+        let assertions_before = runner.get_assertions_checked();
+        let (
+            mut iteration_success,
             passed,
+            failed,
+            quarantined,
+            skipped,
+            suite_results,
             runner_elapsed_time,
-        );
+        ) = if parallel {
+            run_tests_in_workers(
+                jobs,
+                seed,
+                &mut runner,
+                &selected_tests,
+                &ordered_tests,
+                &mut event_sinks,
+            )
+        } else {
+            run_tests(&opts, &mut runner, &ordered_tests, &mut event_sinks)
+        };
+        if !opts.gtest_list_tests {
+            events::emit(&mut event_sinks, &events::Event::RunEnd {
+                passed,
+                failed: failed.len(),
+                quarantined: quarantined.len(),
+                elapsed_ms: runner_elapsed_time,
+            });
+            run_tests_conclusion(
+                total_tests,
+                total_test_suites,
+                passed,
+                runner.get_assertions_checked() - assertions_before,
+                runner_elapsed_time,
+                if opts.suite_summary {
+                    Some(&suite_results)
+                } else {
+                    None
+                },
+            );
+            let tests_run =
+                passed + failed.len() + quarantined.len() + skipped.len();
+            if opts.fail_fast && tests_run < total_tests {
+                console!(
+                    "Note: Stopped at the first failing test; {} of {} \
+                    tests not run",
+                    total_tests - tests_run,
+                    total_tests,
+                );
+            }
+        }
+
+        // Each repetition of the tests starts again from fresh Lua states.
+        runner.forget_lua_states();
+        total_runner_elapsed_time += runner_elapsed_time;
+        if !quarantined.is_empty() {
+            report_quarantined_tests(&quarantined);
+        }
+        if !skipped.is_empty() {
+            report_skipped_tests(&skipped);
+        }
+
+        // Fail the test run if it took longer than allowed.
+        if let Some(max_total_time) = opts.max_total_time {
+            if !opts.gtest_list_tests && runner_elapsed_time > max_total_time {
+                console!(
+                    "{} Test run took {} ms, exceeding the maximum total \
+                    time of {} ms",
+                    red("[  FAILED  ]"),
+                    runner_elapsed_time,
+                    max_total_time
+                );
+                iteration_success = false;
+            }
+        }
+        if !failed.is_empty() {
+            report_failed_tests(&failed);
+        }
+        if disabled_tests > 0 {
+            if failed.is_empty() {
+                console!();
+            }
+            console!(
+                "  YOU HAVE {} DISABLED TEST{}\n",
+                disabled_tests,
+                if disabled_tests == 1 {
+                    ""
+                } else {
+                    "S"
+                },
+            );
+        }
+        success &= iteration_success;
+        if opts.fail_fast && !failed.is_empty() {
+            break;
+        }
     }
-    if !failed.is_empty() {
-        report_failed_tests(&failed);
+
+    // The profile covers every repetition of the tests, since the runner
+    // adds up the time spent in each phase across all of them.
+    if opts.profile && !opts.gtest_list_tests {
+        report_profile(
+            discovery_elapsed_time,
+            total_runner_elapsed_time,
+            runner.get_profile(),
+        );
     }
 
     // Generate report if requested.
     if let Some(gtest_output) = opts.gtest_output {
-        if let Some(report_path) = gtest_output.strip_prefix("xml:") {
-            if let Ok(mut report_file) = std::fs::File::create(report_path) {
-                report_file.write_all(runner.get_report().as_bytes()).unwrap();
+        let report = if let Some(report_path) =
+            gtest_output.strip_prefix("xml:")
+        {
+            Some((report_path, runner.get_report()))
+        } else {
+            gtest_output
+                .strip_prefix("json:")
+                .map(|report_path| (report_path, runner.get_json_report()))
+        };
+        if let Some((report_path, report)) = report {
+            if report_path == "-" {
+                std::io::stdout().write_all(report.as_bytes()).unwrap();
+            } else if let Ok(mut report_file) = std::fs::File::create(
+                resolve_output_path(std::path::Path::new(report_path)),
+            ) {
+                report_file.write_all(report.as_bytes()).unwrap();
+            }
+        }
+    }
+
+    // Write the coverage report if requested.
+    if let Some(coverage_path) = &opts.coverage {
+        if let Some(coverage_report) = runner.get_coverage_report() {
+            if let Err(error) = std::fs::write(
+                resolve_output_path(coverage_path),
+                coverage_report,
+            ) {
+                eprintln!(
+                    "ERROR: Unable to write coverage report '{}': {}",
+                    coverage_path.display(),
+                    error
+                );
             }
         }
     }
@@ -2,6 +2,12 @@ use std::{
     fmt::Write,
     io::Read,
 };
+use super::{
+    coverage,
+    glob,
+    json,
+};
+use unicode_normalization::UnicodeNormalization;
 
 trait FixPathNonsense {
     fn fix_silly_path_delimiter_nonsense(&self) -> std::borrow::Cow<str>;
@@ -22,7 +28,19 @@ impl FixPathNonsense for &str {
 struct Test {
     file: String,
     path: std::path::PathBuf,
+
+    // This is the '.moonunit' file which included the test script, if any.
+    configuration: Option<std::path::PathBuf>,
     line_number: usize,
+    tags: Vec<String>,
+    description: Option<String>,
+    order: usize,
+    abort_after_failures: Option<usize>,
+    depends_on: Vec<(String, String)>,
+
+    // This is set for tests which are known to fail, so that they fail if
+    // they pass, and pass if they fail.
+    expect_fail: bool,
 }
 
 #[derive(Default)]
@@ -32,23 +50,423 @@ struct TestSuite {
 
 type TestSuites = std::collections::HashMap<String, TestSuite>;
 
+// These are the kinds of functions which can be registered for a test
+// suite: `before_all` and `after_all` are called once for the suite, while
+// `setup` and `teardown` are called before and after each of its tests.
+#[derive(Clone, Copy)]
+enum SuiteHookKind {
+    BeforeAll,
+    AfterAll,
+    Setup,
+    Teardown,
+}
+
+impl SuiteHookKind {
+    fn name(self) -> &'static str {
+        match self {
+            SuiteHookKind::BeforeAll => "before_all",
+            SuiteHookKind::AfterAll => "after_all",
+            SuiteHookKind::Setup => "setup",
+            SuiteHookKind::Teardown => "teardown",
+        }
+    }
+
+    // This is the name of the table in the Lua registry which holds the
+    // hook functions of this kind, keyed by test suite name.
+    fn registry_name(self) -> &'static str {
+        match self {
+            SuiteHookKind::BeforeAll => "moonunit_before_all",
+            SuiteHookKind::AfterAll => "moonunit_after_all",
+            SuiteHookKind::Setup => "moonunit_setup",
+            SuiteHookKind::Teardown => "moonunit_teardown",
+        }
+    }
+}
+
+// Call the `setup` or `teardown` function of a test suite, if it has one,
+// passing it the value returned by the suite's `before_all` function.
+// These are found in the same Lua state as the test, since they must be
+// registered by the same test script.
+fn call_test_hook<'lua>(
+    lua: &'lua mlua::Lua,
+    kind: SuiteHookKind,
+    test_suite_name: &str,
+    fixture: mlua::Value<'lua>,
+) -> mlua::Result<()> {
+    let hooks = match lua
+        .named_registry_value::<_, Option<mlua::Table>>(kind.registry_name())?
+    {
+        Some(hooks) => hooks,
+        None => return Ok(()),
+    };
+    match hooks.get::<_, Option<mlua::Function>>(test_suite_name)? {
+        Some(function) => function.call::<_, ()>(fixture),
+        None => Ok(()),
+    }
+}
+
+// Call a test function, passing it the value returned by the `before_all`
+// function of its suite.  The function is called through Lua's own
+// `xpcall`, with a message handler which adds a traceback to the error, as
+// `mlua` would if the test were called directly, except that an error raised
+// with a value other than a string (such as `error({code = 42})`) is
+// rendered showing its contents, rather than just the address of a table.
+fn call_test<'lua>(
+    lua: &'lua mlua::Lua,
+    test: mlua::Function<'lua>,
+    fixture: mlua::Value<'lua>,
+) -> mlua::Result<()> {
+    let handler = lua.create_function(|lua, raised: mlua::Value| {
+        let debug: mlua::Table =
+            lua.named_registry_value(DEBUG_REGISTRY_NAME)?;
+        let traceback: mlua::Function = debug.raw_get("traceback")?;
+        Ok(match raised {
+            // Errors raised by MoonUnit itself, or by other functions
+            // written in Rust, are kept as they are, along with a
+            // traceback of where they were raised.
+            mlua::Value::Error(error) => match error {
+                mlua::Error::CallbackError {
+                    ..
+                } => mlua::Value::Error(error),
+                error => mlua::Value::Error(mlua::Error::CallbackError {
+                    traceback: traceback
+                        .call::<_, String>((mlua::Value::Nil, 2))?,
+                    cause: std::sync::Arc::new(error),
+                }),
+            },
+            mlua::Value::String(message) => {
+                traceback.call::<_, mlua::Value>((message, 2))?
+            },
+            raised => traceback.call::<_, mlua::Value>((render(&raised), 2))?,
+        })
+    })?;
+    let xpcall: mlua::Function = lua.globals().raw_get("xpcall")?;
+    let (succeeded, raised): (bool, mlua::Value) =
+        xpcall.call((test, handler, fixture))?;
+    match raised {
+        _ if succeeded => Ok(()),
+        mlua::Value::Error(error) => Err(error),
+        mlua::Value::String(message) => Err(mlua::Error::RuntimeError(
+            message.to_string_lossy().into_owned(),
+        )),
+        raised => Err(mlua::Error::RuntimeError(render(&raised))),
+    }
+}
+
+// This records which test script registered a `before_all` or `after_all`
+// function for a test suite, so the script can be run again to call it.
+#[derive(Clone)]
+struct SuiteHook {
+    file: String,
+    path: std::path::PathBuf,
+}
+
+// This holds a copy of the value returned by the `before_all` function of
+// a test suite, so that it can be given to each test of the suite, even
+// though each test is run in a fresh Lua state.  Only plain data (nil,
+// booleans, numbers, strings, and tables of these) can be copied this way.
+enum FixtureValue {
+    Nil,
+    Boolean(bool),
+    Integer(mlua::Integer),
+    Number(mlua::Number),
+    String(Vec<u8>),
+    Table(Vec<(FixtureValue, FixtureValue)>),
+}
+
+impl FixtureValue {
+    fn from_lua<'lua>(
+        value: &mlua::Value<'lua>,
+        ancestors: &mut Vec<mlua::Table<'lua>>,
+    ) -> Result<Self, String> {
+        Ok(match value {
+            mlua::Value::Nil => FixtureValue::Nil,
+            mlua::Value::Boolean(value) => FixtureValue::Boolean(*value),
+            mlua::Value::Integer(value) => FixtureValue::Integer(*value),
+            mlua::Value::Number(value) => FixtureValue::Number(*value),
+            mlua::Value::String(value) => {
+                FixtureValue::String(value.as_bytes().to_vec())
+            },
+            mlua::Value::Table(table) => {
+                if ancestors.contains(table) {
+                    return Err(String::from(
+                        "before_all returned a table which contains itself",
+                    ));
+                }
+                ancestors.push(table.clone());
+                let mut pairs = Vec::new();
+                for pair in table.clone().pairs::<mlua::Value, mlua::Value>() {
+                    let (key, value) =
+                        pair.map_err(|error| error.to_string())?;
+                    pairs.push((
+                        Self::from_lua(&key, ancestors)?,
+                        Self::from_lua(&value, ancestors)?,
+                    ));
+                }
+                ancestors.pop();
+                FixtureValue::Table(pairs)
+            },
+            value => {
+                return Err(format!(
+                    "before_all returned a value containing a {}, \
+                    but only nil, booleans, numbers, strings, and tables \
+                    can be given to tests",
+                    value.type_name()
+                ));
+            },
+        })
+    }
+
+    fn to_lua<'lua>(
+        &self,
+        lua: &'lua mlua::Lua,
+    ) -> mlua::Result<mlua::Value<'lua>> {
+        Ok(match self {
+            FixtureValue::Nil => mlua::Value::Nil,
+            FixtureValue::Boolean(value) => mlua::Value::Boolean(*value),
+            FixtureValue::Integer(value) => mlua::Value::Integer(*value),
+            FixtureValue::Number(value) => mlua::Value::Number(*value),
+            FixtureValue::String(value) => {
+                mlua::Value::String(lua.create_string(value)?)
+            },
+            FixtureValue::Table(pairs) => {
+                let table = lua.create_table()?;
+                for (key, value) in pairs {
+                    table.raw_set(key.to_lua(lua)?, value.to_lua(lua)?)?;
+                }
+                mlua::Value::Table(table)
+            },
+        })
+    }
+}
+
+enum SuiteSetup {
+    Ready(FixtureValue),
+    Failed,
+}
+
+pub struct TestInfo {
+    pub path: std::path::PathBuf,
+    pub line_number: usize,
+    pub tags: Vec<String>,
+    pub description: Option<String>,
+    pub order: usize,
+
+    // These are the suite and test names of the tests which must pass
+    // before this test is run.
+    pub depends_on: Vec<(String, String)>,
+
+    // This is set if the test is known to fail (see `Test`).
+    pub expect_fail: bool,
+}
+
+// This selects what to pass to `math.randomseed` before each test is run.
+#[derive(Clone, Copy, Debug)]
+pub enum FixtureSeed {
+    Fixed(u32),
+    TestName,
+}
+
+#[derive(Clone)]
+pub struct TestResult {
+    pub passed: bool,
+    pub aborted: bool,
+    pub skipped: Option<String>,
+    pub elapsed: std::time::Duration,
+    pub messages: Vec<String>,
+    pub assertions: usize,
+
+    // This is set if the test failed only because it was expected to fail
+    // (see `Test`), but passed.
+    pub unexpectedly_passed: bool,
+}
+
+// The outcome of a test, or of the `before_all` or `after_all` function of
+// a test suite (named accordingly), run by `Runner::run_all`.
+pub struct TestOutcome {
+    pub suite: String,
+    pub name: String,
+    pub result: TestResult,
+}
+
+// The outcomes of everything run by `Runner::run_all`, in the order it
+// was run, along with how long it all took.
+#[derive(Default)]
+pub struct RunSummary {
+    pub tests: Vec<TestOutcome>,
+    pub suite_hooks: Vec<TestOutcome>,
+    pub elapsed: std::time::Duration,
+}
+
+// These are what `Runner::run_all` tells its observer about while it
+// runs tests, so that the progress of the test run can be shown as it
+// happens, rather than only once every test has been run.
+pub enum RunEvent<'a> {
+    // The tests of a test suite are about to be run.
+    SuiteStart {
+        suite: &'a str,
+        tests: usize,
+    },
+
+    // The `before_all` or `after_all` function of a test suite was called.
+    SuiteHook(&'a TestOutcome),
+
+    // A test is about to be run.
+    TestStart {
+        suite: &'a str,
+        name: &'a str,
+    },
+
+    // A test was run.
+    TestEnd(&'a TestOutcome),
+
+    // A test was skipped without being run, because a test on which it
+    // depends didn't pass.
+    TestSkip {
+        suite: &'a str,
+        name: &'a str,
+        reason: &'a str,
+    },
+
+    // All the tests of a test suite were run (or the test run was stopped
+    // part of the way through them).
+    SuiteEnd {
+        suite: &'a str,
+        tests: usize,
+        elapsed: std::time::Duration,
+    },
+}
+
+// This breaks down where the time spent by the runner went, across both
+// discovering and running tests.
+#[derive(Clone, Copy, Default)]
+pub struct Profile {
+    // Making fresh Lua states.
+    pub state_setup: std::time::Duration,
+
+    // Executing test scripts, before their tests are called.
+    pub script_execution: std::time::Duration,
+
+    // Calling test functions.
+    pub test_bodies: std::time::Duration,
+}
+
+// This makes the value of a global registered by the host application.
+// It's called again for each test script, and for each test run in a
+// reused Lua state, so that tests don't share values made by it.
+type GlobalFactory =
+    dyn for<'lua> Fn(&'lua mlua::Lua) -> mlua::Result<mlua::Value<'lua>>;
+
+// The Lua state in which a test script was executed, kept so that the
+// other tests of the script can be run in it, when Lua states are reused.
+// Unless globals are shared between tests, a copy of the globals table is
+// taken right after the script is executed, and used to put the globals
+// back the way they were before each of the other tests is run.
+struct LoadedScript {
+    lua: mlua::Lua,
+    tests_registry_key: std::rc::Rc<mlua::RegistryKey>,
+    errors: std::rc::Rc<std::cell::RefCell<Vec<String>>>,
+    globals_snapshot: Option<mlua::RegistryKey>,
+}
+
 struct RunnerInner {
+    abort_after_failures: Option<usize>,
+    after_all: std::collections::HashMap<String, SuiteHook>,
+    assertions_checked: usize,
+    before_all: std::collections::HashMap<String, SuiteHook>,
+    coverage: Option<std::rc::Rc<std::cell::RefCell<coverage::Coverage>>>,
+    current_configuration: Option<std::path::PathBuf>,
+    current_test_abort_after_failures: Option<usize>,
+    current_test_aborted: bool,
+    current_test_assertions: usize,
     current_test_failed: bool,
+    current_test_failures: usize,
+    current_test_log: Vec<String>,
+    current_test_output: Vec<String>,
+    current_test_skipped: Option<String>,
+    current_test_unexpectedly_passed: bool,
+    fixture_seed: Option<FixtureSeed>,
+    globals: Vec<(String, std::rc::Rc<GlobalFactory>)>,
+    loaded_scripts: std::collections::HashMap<std::path::PathBuf, LoadedScript>,
+    max_failures_per_test: Option<usize>,
+    profile: Profile,
+    results: std::collections::HashMap<(String, String), TestResult>,
+    reuse_lua_states: bool,
+    safe: bool,
+    share_globals: bool,
+    strict: bool,
+    suite_setups: std::collections::HashMap<String, SuiteSetup>,
     test_suites: TestSuites,
+    tests_defined: usize,
+    timeout: Option<std::time::Duration>,
+    unavailable_sources: std::collections::HashSet<std::path::PathBuf>,
+    update_golden: bool,
 }
 
 impl RunnerInner {
     fn new() -> Self {
         Self {
+            abort_after_failures: None,
+            after_all: std::collections::HashMap::new(),
+            assertions_checked: 0,
+            before_all: std::collections::HashMap::new(),
+            coverage: None,
+            current_configuration: None,
+            current_test_abort_after_failures: None,
+            current_test_aborted: false,
+            current_test_assertions: 0,
             current_test_failed: false,
+            current_test_failures: 0,
+            current_test_log: Vec::new(),
+            current_test_output: Vec::new(),
+            current_test_skipped: None,
+            current_test_unexpectedly_passed: false,
+            fixture_seed: None,
+            globals: Vec::new(),
+            loaded_scripts: std::collections::HashMap::new(),
+            max_failures_per_test: None,
+            profile: Profile::default(),
+            results: std::collections::HashMap::new(),
+            reuse_lua_states: false,
+            safe: false,
+            share_globals: false,
+            strict: false,
+            suite_setups: std::collections::HashMap::new(),
             test_suites: TestSuites::new(),
+            tests_defined: 0,
+            timeout: None,
+            unavailable_sources: std::collections::HashSet::new(),
+            update_golden: false,
         }
     }
 }
 
+// The address of this is used to make a unique light userdata value,
+// available to tests as `moonunit.null`, which stands for a value that is
+// intentionally nil (or absent) when comparing values.
+static NULL_SENTINEL: u8 = 0;
+
+fn null_sentinel() -> mlua::LightUserData {
+    mlua::LightUserData(
+        std::ptr::addr_of!(NULL_SENTINEL).cast::<std::ffi::c_void>().cast_mut(),
+    )
+}
+
+fn is_null(value: &mlua::Value) -> bool {
+    matches!(
+        value,
+        mlua::Value::LightUserData(value) if *value == null_sentinel()
+    )
+}
+
+fn is_nil_or_null(value: &mlua::Value) -> bool {
+    matches!(value, mlua::Value::Nil) || is_null(value)
+}
+
 fn render(value: &mlua::Value) -> String {
     match value {
         mlua::Value::Nil => String::from("nil"),
+        value if is_null(value) => String::from("null"),
         mlua::Value::Boolean(value) => {
             format!("{}", value)
         },
@@ -59,603 +477,3384 @@ fn render(value: &mlua::Value) -> String {
             format!("{}", value)
         },
         mlua::Value::String(value) => {
-            format!("\"{}\"", value.to_str().unwrap())
+            format!("\"{}\"", value.to_string_lossy())
         },
-        _ => {
-            format!("{:?}", value)
+        mlua::Value::Thread(thread) => {
+            format!("<coroutine: {}>", coroutine_status(thread))
+        },
+        mlua::Value::Table(table) => {
+            let mut buffer = String::new();
+            render_table(&mut buffer, table, &mut Vec::new());
+            buffer
         },
+        mlua::Value::LightUserData(_) => String::from("<light userdata>"),
+        mlua::Value::Function(_) => String::from("<function>"),
+        mlua::Value::UserData(_) => String::from("<userdata>"),
+        mlua::Value::Error(error) => format!("<error: {}>", error),
     }
 }
 
-struct LuaValueForDisplay<'lua>(&'lua mlua::Value<'lua>);
+// The deepest nesting of tables, and the most entries of any one table,
+// rendered in failure messages, so that a large table doesn't flood the
+// output.  Anything beyond these is left out and marked with "...".
+const RENDER_TABLE_MAX_DEPTH: usize = 5;
+const RENDER_TABLE_MAX_ENTRIES: usize = 20;
 
-impl<'lua> std::fmt::Display for LuaValueForDisplay<'lua> {
-    fn fmt(
-        &self,
-        f: &mut std::fmt::Formatter<'_>,
-    ) -> std::fmt::Result {
-        match &self.0 {
-            mlua::Value::Nil => {
-                write!(f, "nil")
-            },
-            mlua::Value::Boolean(value) => {
-                write!(f, "{} (boolean)", value)
-            },
-            mlua::Value::Integer(value) => {
-                write!(f, "{} (integer)", value)
-            },
-            mlua::Value::Number(value) => {
-                write!(f, "{} (number)", value)
-            },
-            mlua::Value::String(value) => {
-                write!(f, "\"{}\" (string)", value.to_str().unwrap())
-            },
-            _ => {
-                write!(f, "{:?}", self.0)
-            },
+// Render a table on a single line, resembling a Lua table constructor,
+// with keys in the same deterministic order used when comparing tables,
+// so that the same table is always rendered the same way.  Tables with a
+// `__tostring` metamethod are rendered using that instead.
+fn render_table<'lua>(
+    buffer: &mut String,
+    table: &mlua::Table<'lua>,
+    ancestors: &mut Vec<mlua::Table<'lua>>,
+) {
+    if let Some(string) = tostring_metamethod(table) {
+        buffer.push_str(&string);
+        return;
+    }
+    if ancestors.contains(table) {
+        buffer.push_str("<cycle>");
+        return;
+    }
+    if ancestors.len() >= RENDER_TABLE_MAX_DEPTH {
+        buffer.push_str("{...}");
+        return;
+    }
+    ancestors.push(table.clone());
+    buffer.push('{');
+    let pairs = sorted_pairs(table);
+    let omitted = pairs.len().saturating_sub(RENDER_TABLE_MAX_ENTRIES);
+    for (i, (key, value)) in
+        pairs.into_iter().take(RENDER_TABLE_MAX_ENTRIES).enumerate()
+    {
+        if i > 0 {
+            buffer.push_str(", ");
+        }
+        render_table_key(buffer, &key.0);
+        buffer.push_str(" = ");
+        if let mlua::Value::Table(value) = &value {
+            render_table(buffer, value, ancestors);
+        } else {
+            buffer.push_str(&render(&value));
         }
     }
+    if omitted > 0 {
+        write!(buffer, ", ... ({} more)", omitted).unwrap();
+    }
+    buffer.push('}');
+    ancestors.pop();
 }
 
-struct OrderedLuaValue<'lua>(mlua::Value<'lua>);
+// Render a table key as it would appear in a table constructor.
+fn render_table_key(
+    buffer: &mut String,
+    key: &mlua::Value,
+) {
+    match key {
+        mlua::Value::String(key) if is_lua_identifier(key.as_bytes()) => {
+            buffer.push_str(&key.to_string_lossy());
+        },
+        key => {
+            buffer.push('[');
+            buffer.push_str(&render(key));
+            buffer.push(']');
+        },
+    }
+}
 
-impl<'lua> PartialEq for OrderedLuaValue<'lua> {
-    fn eq(
-        &self,
-        other: &Self,
-    ) -> bool {
-        self.0.eq(&other.0)
+// Render a value, showing only the path to a difference within it, given
+// by the chain of keys leading to the difference, and collapsing all other
+// entries of the tables along the path into "...", so that the difference
+// isn't buried in the rest of a large table.  Keys are matched up the same
+// way as when the tables were compared.
+fn render_elided(
+    value: &mlua::Value,
+    key_chain: &[mlua::Value],
+    options: &ComparisonOptions,
+) -> String {
+    let (table, key, rest) = match (value, key_chain.split_first()) {
+        (mlua::Value::Table(table), Some((key, rest)))
+            if tostring_metamethod(table).is_none() =>
+        {
+            (table, key, rest)
+        },
+        _ => return render(value),
+    };
+    let comparison_key = options.comparison_key(key.clone());
+    let pairs = sorted_pairs(table);
+    let mut buffer = String::from("{");
+    render_table_key(&mut buffer, key);
+    buffer.push_str(" = ");
+    let value = pairs
+        .iter()
+        .find(|(other_key, _)| {
+            options.comparison_key(other_key.0.clone()) == comparison_key
+        })
+        .map_or(mlua::Value::Nil, |(_, value)| value.clone());
+    buffer.push_str(&render_elided(&value, rest, options));
+    if pairs.len() > 1 {
+        buffer.push_str(", ...");
     }
+    buffer.push('}');
+    buffer
 }
 
-impl<'lua> Eq for OrderedLuaValue<'lua> {
-    fn assert_receiver_is_total_eq(&self) {}
+// Render a table using its `__tostring` metamethod, if it has one which
+// successfully returns a string.
+fn tostring_metamethod(table: &mlua::Table) -> Option<String> {
+    let tostring = table
+        .get_metatable()?
+        .raw_get::<_, Option<mlua::Function>>("__tostring")
+        .ok()??;
+    tostring
+        .call::<_, mlua::String>(table.clone())
+        .ok()
+        .map(|string| string.to_string_lossy().into_owned())
 }
 
-impl<'lua> PartialOrd for OrderedLuaValue<'lua> {
-    fn partial_cmp(
-        &self,
-        other: &Self,
-    ) -> Option<std::cmp::Ordering> {
-        Some(self.cmp(other))
+fn coroutine_status(thread: &mlua::Thread) -> &'static str {
+    match thread.status() {
+        mlua::ThreadStatus::Resumable => "suspended",
+        mlua::ThreadStatus::Unresumable | mlua::ThreadStatus::Error => "dead",
     }
 }
 
-impl<'lua> Ord for OrderedLuaValue<'lua> {
-    fn cmp(
-        &self,
-        other: &Self,
-    ) -> std::cmp::Ordering {
-        let self_type_name = self.0.type_name();
-        let other_type_name = other.0.type_name();
-        if self_type_name == other_type_name {
-            match &self.0 {
-                mlua::Value::Boolean(value) => {
-                    if let mlua::Value::Boolean(other_value) = &other.0 {
-                        value.cmp(other_value)
-                    } else {
-                        panic!()
-                    }
-                },
-                mlua::Value::Integer(value) => {
-                    if let mlua::Value::Integer(other_value) = &other.0 {
-                        value.cmp(other_value)
-                    } else {
-                        panic!()
-                    }
-                },
-                mlua::Value::Number(value) => {
-                    if let mlua::Value::Number(other_value) = &other.0 {
-                        if value < other_value {
-                            std::cmp::Ordering::Less
-                        } else if value > other_value {
-                            std::cmp::Ordering::Greater
-                        } else {
-                            std::cmp::Ordering::Equal
-                        }
-                    } else {
-                        panic!()
-                    }
-                },
-                mlua::Value::String(value) => {
-                    if let mlua::Value::String(other_value) = &other.0 {
-                        value
-                            .to_str()
-                            .unwrap()
-                            .cmp(other_value.to_str().unwrap())
-                    } else {
-                        panic!()
-                    }
-                },
-                _ => std::cmp::Ordering::Equal,
-            }
-        } else {
-            self_type_name.cmp(other_type_name)
-        }
+fn check_equality_comparable(
+    lhs: &mlua::Value,
+    rhs: &mlua::Value,
+) -> mlua::Result<()> {
+    if matches!(lhs, mlua::Value::Thread(_))
+        || matches!(rhs, mlua::Value::Thread(_))
+    {
+        Err(mlua::Error::RuntimeError(String::from(
+            "Cannot compare coroutines for equality",
+        )))
+    } else if matches!(lhs, mlua::Value::Error(_))
+        || matches!(rhs, mlua::Value::Error(_))
+    {
+        Err(mlua::Error::RuntimeError(String::from(
+            "Cannot compare error values for equality",
+        )))
+    } else {
+        Ok(())
     }
 }
 
-struct RunContext {
-    errors: std::rc::Rc<std::cell::RefCell<Vec<String>>>,
-    file: String,
-    path: std::path::PathBuf,
-    runner: Runner,
-    tests_registry_key: std::rc::Rc<mlua::RegistryKey>,
+// Describe the type of a value, with an article, as in "an integer".
+// Numbers are described by their subtype, since Lua 5.3 distinguishes
+// integers from floats, even though both have the type "number".
+fn describe_type(value: &mlua::Value) -> &'static str {
+    match value {
+        mlua::Value::Nil => "nil",
+        mlua::Value::Boolean(_) => "a boolean",
+        mlua::Value::Integer(_) => "an integer",
+        mlua::Value::Number(_) => "a float",
+        mlua::Value::String(_) => "a string",
+        mlua::Value::Table(_) => "a table",
+        mlua::Value::Function(_) => "a function",
+        mlua::Value::Thread(_) => "a thread",
+        mlua::Value::LightUserData(_) | mlua::Value::UserData(_) => {
+            "a userdata"
+        },
+        mlua::Value::Error(_) => "an error",
+    }
 }
 
-impl mlua::UserData for RunContext {
-    fn add_methods<'lua, M: mlua::UserDataMethods<'lua, Self>>(
-        methods: &mut M
-    ) {
-        methods.add_method("test", moonunit_test);
-        methods.add_method("assert_eq", moonunit_assert_eq);
-        methods.add_method("assert_ne", moonunit_assert_ne);
-        methods.add_method("assert_ge", moonunit_assert_ge);
-        methods.add_method("assert_gt", moonunit_assert_gt);
-        methods.add_method("assert_le", moonunit_assert_le);
-        methods.add_method("assert_lt", moonunit_assert_lt);
-        methods.add_method("assert_true", moonunit_assert_true);
-        methods.add_method("assert_false", moonunit_assert_false);
-        methods.add_method("expect_eq", moonunit_expect_eq);
-        methods.add_method("expect_ne", moonunit_expect_ne);
-        methods.add_method("expect_ge", moonunit_expect_ge);
-        methods.add_method("expect_gt", moonunit_expect_gt);
-        methods.add_method("expect_le", moonunit_expect_le);
-        methods.add_method("expect_lt", moonunit_expect_lt);
-        methods.add_method("expect_true", moonunit_expect_true);
-        methods.add_method("expect_false", moonunit_expect_false);
+// Check that a value has the given type, which is either one of the type
+// names returned by Lua's `type` function, or "integer" or "float" to check
+// the subtype of a number as well.
+fn check_type(
+    expected: &str,
+    value: &mlua::Value,
+) -> mlua::Result<Result<(), String>> {
+    let matches = match expected {
+        "nil" => matches!(value, mlua::Value::Nil),
+        "boolean" => matches!(value, mlua::Value::Boolean(_)),
+        "number" => {
+            matches!(value, mlua::Value::Integer(_) | mlua::Value::Number(_))
+        },
+        "integer" => matches!(value, mlua::Value::Integer(_)),
+        "float" => matches!(value, mlua::Value::Number(_)),
+        "string" => matches!(value, mlua::Value::String(_)),
+        "table" => matches!(value, mlua::Value::Table(_)),
+        "function" => matches!(value, mlua::Value::Function(_)),
+        "thread" => matches!(value, mlua::Value::Thread(_)),
+        "userdata" => matches!(
+            value,
+            mlua::Value::LightUserData(_) | mlua::Value::UserData(_)
+        ),
+        _ => {
+            return Err(mlua::Error::RuntimeError(format!(
+                "Unknown type name '{}'",
+                expected
+            )));
+        },
+    };
+    if matches {
+        return Ok(Ok(()));
     }
+    Ok(Err(match (expected, value) {
+        // A number of the wrong subtype is described by its subtype.
+        ("integer", mlua::Value::Number(number)) => {
+            format!("Expected an integer, but got a float ({:?})", number)
+        },
+        ("float", mlua::Value::Integer(integer)) => {
+            format!("Expected a float, but got an integer ({})", integer)
+        },
+
+        // Floats are shown with their subtype, so that a float with an
+        // integral value isn't mistaken for an integer.
+        (expected, mlua::Value::Number(number)) => format!(
+            "Expected value of type \"{}\", actual was {:?} (float)",
+            expected, number
+        ),
+        (expected, value) => format!(
+            "Expected value of type \"{}\", actual was {}",
+            expected,
+            LuaValueForDisplay(value)
+        ),
+    }))
 }
 
-fn moonunit_test(
+// Check that a string value matches a Lua pattern, as tested by Lua's own
+// `string.find`.  Values which aren't strings are an error rather than a
+// failed check, since comparing them against a pattern is a mistake in the
+// test itself.
+fn check_match(
     lua: &mlua::Lua,
-    this: &RunContext,
-    (suite, name, test): (String, String, mlua::Function),
-) -> mlua::Result<()> {
-    // Get line number information about the provided function.
-    let test_source = test.source();
-
-    // Make sure there is a table for this suite of tests.
-    let tests_table: mlua::Table =
-        lua.registry_value(&this.tests_registry_key)?;
-    if !tests_table.contains_key(suite.clone())? {
-        tests_table.set(suite.clone(), lua.create_table()?)?;
+    value: &mlua::Value,
+    pattern: &mlua::String,
+) -> mlua::Result<Result<(), String>> {
+    if !matches!(value, mlua::Value::String(_)) {
+        return Err(mlua::Error::RuntimeError(format!(
+            "Expected a string to match against pattern {}, but got {} ({})",
+            render(&mlua::Value::String(pattern.clone())),
+            describe_type(value),
+            render(value)
+        )));
+    }
+    let find: mlua::Function = lua
+        .globals()
+        .raw_get::<_, mlua::Table>("string")?
+        .raw_get("find")?;
+    let start: mlua::Value = find.call((value.clone(), pattern.clone()))?;
+    if let mlua::Value::Nil = start {
+        Ok(Err(format!(
+            "Expected {} to match pattern {}",
+            render(value),
+            render(&mlua::Value::String(pattern.clone()))
+        )))
+    } else {
+        Ok(Ok(()))
     }
-
-    // Store the function in the tests table.
-    let tests: mlua::Table = tests_table.get(suite.clone())?;
-    tests.set(name.clone(), test)?;
-
-    // Add information about the test to the runner.
-    let test_suites = &mut this.runner.inner.borrow_mut().test_suites;
-    let suite = test_suites.entry(suite).or_default();
-    #[allow(clippy::cast_sign_loss)]
-    suite.tests.entry(name).or_insert_with(|| Test {
-        file: this.file.clone(),
-        path: this.path.clone(),
-        line_number: test_source.line_defined as usize,
-    });
-    Ok(())
 }
 
-fn moonunit_assert_eq(
-    _lua: &mlua::Lua,
-    _this: &RunContext,
-    (lhs, rhs): (mlua::Value, mlua::Value),
-) -> mlua::Result<()> {
-    if let (mlua::Value::Table(lhs), mlua::Value::Table(rhs)) = (&lhs, &rhs) {
-        let (message, key_chain) =
-            RunContext::compare_lua_tables(lhs, rhs, Vec::new());
-        if message.is_empty() {
-            Ok(())
+// The longest a rendered container may be in a failure message before it's
+// cut short, so that searching a large table doesn't flood the output.
+const CONTAINER_SUMMARY_LENGTH: usize = 60;
+
+// Check that a table contains a value, as one of its elements (or, for
+// tables which aren't arrays, one of the values of its entries), using the
+// same equality as `check_eq`, so that tables are compared by content.
+fn check_contains(
+    container: &mlua::Value,
+    value: &mlua::Value,
+) -> mlua::Result<Result<(), String>> {
+    let table = if let mlua::Value::Table(table) = container {
+        table
+    } else {
+        return Err(mlua::Error::RuntimeError(format!(
+            "Expected a table to search for {}, but got {} ({})",
+            render(value),
+            describe_type(container),
+            render(container)
+        )));
+    };
+    let options = ComparisonOptions::default();
+    let found = sorted_pairs(table).into_iter().any(|(_, element)| {
+        if let (mlua::Value::Table(lhs), mlua::Value::Table(rhs)) =
+            (value, &element)
+        {
+            RunContext::compare_lua_tables(
+                lhs,
+                rhs,
+                &options,
+                Vec::new(),
+                &mut TablePairs::new(),
+            )
+            .0
+            .is_empty()
         } else {
-            Err(mlua::Error::RuntimeError(format!(
-                "Tables differ (path: {}) -- {}",
-                key_chain.into_iter().map(|value| render(&value)).fold(
-                    String::new(),
-                    |mut chain, key| {
-                        if !chain.is_empty() {
-                            chain.push('.');
-                        }
-                        chain += &key;
-                        chain
-                    }
-                ),
-                message
-            )))
+            options.values_equal(value, &element)
         }
-    } else if lhs == rhs {
-        Ok(())
+    });
+    if found {
+        Ok(Ok(()))
     } else {
-        Err(mlua::Error::RuntimeError(format!(
-            "Expected {}, actual was {}",
-            LuaValueForDisplay(&lhs),
-            LuaValueForDisplay(&rhs),
+        let mut summary = render(container);
+        if summary.chars().count() > CONTAINER_SUMMARY_LENGTH {
+            summary = summary
+                .chars()
+                .take(CONTAINER_SUMMARY_LENGTH)
+                .chain("...".chars())
+                .collect();
+        }
+        Ok(Err(format!(
+            "Expected {} to contain {}",
+            summary,
+            render(value)
         )))
     }
 }
 
-fn moonunit_assert_ne(
-    _lua: &mlua::Lua,
-    _this: &RunContext,
-    (lhs, rhs): (mlua::Value, mlua::Value),
-) -> mlua::Result<()> {
-    if let (mlua::Value::Table(lhs), mlua::Value::Table(rhs)) = (&lhs, &rhs) {
-        let (message, _key_chain) =
-            RunContext::compare_lua_tables(lhs, rhs, Vec::new());
-        if message.is_empty() {
-            Err(mlua::Error::RuntimeError(String::from(
-                "Tables should differ but are the same",
-            )))
-        } else {
-            Ok(())
-        }
-    } else if lhs == rhs {
-        Err(mlua::Error::RuntimeError(format!(
-            "Expected not {}, actual was {}",
-            LuaValueForDisplay(&lhs),
-            LuaValueForDisplay(&rhs),
-        )))
+// Check that a value is a table holding a proper array: one whose keys are
+// exactly the integers from 1 to n, with no holes, which is what the length
+// operator and `ipairs` expect.  Other keys are reported unless the
+// `allow_non_integer_keys` option is given.
+fn check_is_array(
+    value: &mlua::Value,
+    options: Option<mlua::Table>,
+) -> mlua::Result<Result<(), String>> {
+    let allow_non_integer_keys = match options {
+        Some(options) => options
+            .get::<_, Option<bool>>("allow_non_integer_keys")?
+            .unwrap_or(false),
+        None => false,
+    };
+    let table = if let mlua::Value::Table(table) = value {
+        table
     } else {
-        Ok(())
+        return Ok(Err(format!(
+            "Expected an array, but got {} ({}), which is not a table",
+            describe_type(value),
+            render(value)
+        )));
+    };
+    let mut indices = Vec::new();
+    for (key, _) in sorted_pairs(table) {
+        match key.0 {
+            mlua::Value::Integer(index) if index >= 1 => indices.push(index),
+
+            // Integer keys less than 1 are reported even when non-integer
+            // keys are allowed.
+            mlua::Value::Integer(index) => {
+                return Ok(Err(format!(
+                    "Array has an index less than 1: {}",
+                    index
+                )));
+            },
+            _ if allow_non_integer_keys => (),
+            key => {
+                return Ok(Err(format!(
+                    "Array has a non-integer key {}",
+                    LuaValueForDisplay(&key)
+                )));
+            },
+        }
+    }
+    // The indices are sorted, so the first one which doesn't match its
+    // position marks the first hole.
+    if let Some(hole) = (1..)
+        .zip(&indices)
+        .find(|(expected, index)| expected != *index)
+        .map(|(expected, _)| expected)
+    {
+        return Ok(Err(format!(
+            "Array has a hole at index {} (but has elements up to index {})",
+            hole,
+            indices.last().copied().unwrap_or_default()
+        )));
     }
+    Ok(Ok(()))
+}
+
+// Check that a value is a table of the given length, as given by the length
+// operator.  Values which aren't tables are an error rather than a failed
+// check, as with `check_contains`.
+fn check_len(
+    value: &mlua::Value,
+    expected: mlua::Integer,
+) -> mlua::Result<Result<(), String>> {
+    let table = if let mlua::Value::Table(table) = value {
+        table
+    } else {
+        return Err(mlua::Error::RuntimeError(format!(
+            "Expected a table of length {}, but got {} ({}), which is not a \
+            table",
+            expected,
+            describe_type(value),
+            render(value)
+        )));
+    };
+    let actual = table.len()?;
+    if actual == expected {
+        Ok(Ok(()))
+    } else {
+        Ok(Err(format!(
+            "Expected a table of length {}, but its length was {}",
+            expected, actual
+        )))
+    }
+}
+
+// This is the tolerance used by the `near` assertions when none is given.
+const DEFAULT_NEAR_TOLERANCE: mlua::Number = 1e-9;
+
+// Give the value of a number as a float, whether it's an integer or not.
+#[allow(clippy::cast_precision_loss)]
+fn as_number(value: &mlua::Value) -> Option<mlua::Number> {
+    match value {
+        mlua::Value::Integer(value) => Some(*value as mlua::Number),
+        mlua::Value::Number(value) => Some(*value),
+        _ => None,
+    }
+}
+
+fn check_tolerance(tolerance: mlua::Number) -> mlua::Result<()> {
+    if tolerance.is_nan() || tolerance < 0.0 {
+        Err(mlua::Error::RuntimeError(format!(
+            "Tolerance must be a non-negative number, not {}",
+            tolerance
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+// Check that two numbers differ by no more than the given tolerance.
+fn check_near(
+    lhs: &mlua::Value,
+    rhs: &mlua::Value,
+    tolerance: Option<mlua::Number>,
+) -> mlua::Result<Result<(), String>> {
+    let tolerance = tolerance.unwrap_or(DEFAULT_NEAR_TOLERANCE);
+    check_tolerance(tolerance)?;
+    let (lhs_number, rhs_number) = match (as_number(lhs), as_number(rhs)) {
+        (Some(lhs_number), Some(rhs_number)) => (lhs_number, rhs_number),
+        _ => {
+            return Err(mlua::Error::RuntimeError(format!(
+                "Cannot compare {} and {} approximately \
+                (only numbers can be compared approximately)",
+                lhs.type_name(),
+                rhs.type_name()
+            )));
+        },
+    };
+    let difference = (lhs_number - rhs_number).abs();
+    if difference.is_nan() || difference > tolerance {
+        Ok(Err(format!(
+            "Expected {}, actual was {} \
+            (difference {} exceeds tolerance {})",
+            LuaValueForDisplay(lhs),
+            LuaValueForDisplay(rhs),
+            difference,
+            tolerance
+        )))
+    } else {
+        Ok(Ok(()))
+    }
+}
+
+// Check that two values are equal, the same way as `check_eq`, except that
+// numbers, including those anywhere within tables, only need to be within
+// the given tolerance of each other.
+fn check_approx_eq<'lua>(
+    lua: &'lua mlua::Lua,
+    lhs: &mlua::Value<'lua>,
+    rhs: &mlua::Value<'lua>,
+    tolerance: Option<mlua::Number>,
+) -> mlua::Result<Result<(), String>> {
+    let how = lua.create_table()?;
+    how.set("tolerance", tolerance.unwrap_or(DEFAULT_NEAR_TOLERANCE))?;
+    check_eq(lua, lhs, rhs, mlua::Value::Table(how))
+}
+
+// Check that every key of the expected value is in the actual value, with
+// an equal value, where nested tables are compared the same way, so that
+// the actual value may have other keys as well.  This is the same as
+// comparing them with `check_eq` using the `allow_extra_keys` option.
+fn check_subset<'lua>(
+    lua: &'lua mlua::Lua,
+    actual: &mlua::Value<'lua>,
+    expected: &mlua::Value<'lua>,
+) -> mlua::Result<Result<(), String>> {
+    let how = lua.create_table()?;
+    how.set("allow_extra_keys", true)?;
+    check_eq(lua, expected, actual, mlua::Value::Table(how))
+}
+
+// Check that two arrays of numbers have the same length, and that each
+// element of the actual array is within the given tolerance of the
+// corresponding element of the expected array.
+fn check_array_near(
+    lhs: &mlua::Value,
+    rhs: &mlua::Value,
+    tolerance: mlua::Number,
+) -> mlua::Result<Result<(), String>> {
+    check_tolerance(tolerance)?;
+    let mut arrays = Vec::new();
+    for (which, value) in &[("Expected", lhs), ("Actual", rhs)] {
+        if let Err(message) = check_is_array(value, None)? {
+            return Ok(Err(format!(
+                "{} value is not an array: {}",
+                which, message
+            )));
+        }
+        if let mlua::Value::Table(table) = value {
+            arrays.push(
+                table
+                    .clone()
+                    .sequence_values::<mlua::Value>()
+                    .collect::<mlua::Result<Vec<_>>>()?,
+            );
+        }
+    }
+    let (lhs, rhs) = (&arrays[0], &arrays[1]);
+    if lhs.len() != rhs.len() {
+        return Ok(Err(format!(
+            "Arrays differ in length: expected {}, actual was {}",
+            lhs.len(),
+            rhs.len()
+        )));
+    }
+    for (i, (lhs, rhs)) in lhs.iter().zip(rhs).enumerate() {
+        let (lhs_number, rhs_number) = match (as_number(lhs), as_number(rhs)) {
+            (Some(lhs_number), Some(rhs_number)) => (lhs_number, rhs_number),
+            _ => {
+                return Err(mlua::Error::RuntimeError(format!(
+                    "Arrays can only be compared approximately if they \
+                    hold numbers, but at index {} expected {} and actual \
+                    was {}",
+                    i + 1,
+                    LuaValueForDisplay(lhs),
+                    LuaValueForDisplay(rhs)
+                )));
+            },
+        };
+        let difference = (lhs_number - rhs_number).abs();
+        if difference.is_nan() || difference > tolerance {
+            return Ok(Err(format!(
+                "Arrays differ at index {}: expected {}, actual was {} \
+                (difference {} exceeds tolerance {})",
+                i + 1,
+                LuaValueForDisplay(lhs),
+                LuaValueForDisplay(rhs),
+                difference,
+                tolerance
+            )));
+        }
+    }
+    Ok(Ok(()))
+}
+
+// Determine how two values are ordered, for the ordering assertions.  Only
+// numbers (integers and floats may be mixed) and strings can be ordered;
+// anything else is an error.  The result is `None` if the values are
+// unordered, which happens if either one is NaN.
+#[allow(clippy::cast_precision_loss)]
+fn compare_for_ordering(
+    lhs: &mlua::Value,
+    rhs: &mlua::Value,
+) -> mlua::Result<Option<std::cmp::Ordering>> {
+    match (lhs, rhs) {
+        (mlua::Value::Integer(lhs), mlua::Value::Integer(rhs)) => {
+            Ok(Some(lhs.cmp(rhs)))
+        },
+        (mlua::Value::Integer(lhs), mlua::Value::Number(rhs)) => {
+            Ok((*lhs as f64).partial_cmp(rhs))
+        },
+        (mlua::Value::Number(lhs), mlua::Value::Integer(rhs)) => {
+            Ok(lhs.partial_cmp(&(*rhs as f64)))
+        },
+        (mlua::Value::Number(lhs), mlua::Value::Number(rhs)) => {
+            Ok(lhs.partial_cmp(rhs))
+        },
+        (mlua::Value::String(lhs), mlua::Value::String(rhs)) => {
+            Ok(Some(lhs.as_bytes().cmp(rhs.as_bytes())))
+        },
+        (lhs, rhs) => Err(mlua::Error::RuntimeError(format!(
+            "Cannot compare {} and {} for ordering \
+            (only numbers and strings can be ordered)",
+            lhs.type_name(),
+            rhs.type_name()
+        ))),
+    }
+}
+
+// Render the path to a value in nested tables.  Integral keys are always
+// shown as integers, and other numeric keys are shown in brackets, so that
+// their decimal points aren't confused with the path delimiters.
+fn render_key_chain(key_chain: Vec<mlua::Value>) -> String {
+    key_chain
+        .into_iter()
+        .map(|key| match normalize_key(key) {
+            key @ mlua::Value::Number(_) => format!("[{}]", render(&key)),
+            key => render(&key),
+        })
+        .fold(String::new(), |mut chain, key| {
+            if !chain.is_empty() {
+                chain.push('.');
+            }
+            chain += &key;
+            chain
+        })
+}
+
+enum ComparatorVerdict {
+    Equal,
+    Different(Option<String>),
+    Failed(String),
+}
+
+// Ask a comparator function supplied by the test whether two values are
+// equal.  The comparator may return a boolean, or a message describing
+// how the values differ.
+fn call_comparator<'lua>(
+    comparator: &mlua::Function<'lua>,
+    lhs: &mlua::Value<'lua>,
+    rhs: &mlua::Value<'lua>,
+) -> ComparatorVerdict {
+    match comparator.call::<_, mlua::Value>((lhs.clone(), rhs.clone())) {
+        Ok(mlua::Value::Boolean(true)) => ComparatorVerdict::Equal,
+        Ok(mlua::Value::Boolean(false) | mlua::Value::Nil) => {
+            ComparatorVerdict::Different(None)
+        },
+        Ok(mlua::Value::String(message)) => ComparatorVerdict::Different(
+            Some(message.to_string_lossy().into_owned()),
+        ),
+        Ok(result) => ComparatorVerdict::Failed(format!(
+            "Comparator returned {} instead of a boolean or message",
+            LuaValueForDisplay(&result)
+        )),
+        Err(error) => ComparatorVerdict::Failed(format!(
+            "Comparator raised an error: {}",
+            error
+        )),
+    }
+}
+
+// This is a matcher, made by `moonunit:from_file`, which stands for the
+// value stored in a file (either as JSON, or as a Lua expression, such as
+// the contents of a golden file), which is loaded when it's compared.
+struct FileMatcher {
+    path: std::path::PathBuf,
+}
+
+impl mlua::UserData for FileMatcher {}
+
+impl FileMatcher {
+    fn load<'lua>(
+        &self,
+        lua: &'lua mlua::Lua,
+    ) -> Result<mlua::Value<'lua>, String> {
+        let text = std::fs::read_to_string(&self.path).map_err(|error| {
+            format!(
+                "Unable to read expected value file '{}': {}",
+                self.path.display(),
+                error
+            )
+        })?;
+        let is_json = self
+            .path
+            .extension()
+            .map_or(false, |extension| extension == "json");
+        let value = if is_json {
+            json::parse(&text).and_then(|value| {
+                json_to_lua(lua, value).map_err(|error| error.to_string())
+            })
+        } else {
+            let name = "=".to_string() + &self.path.to_string_lossy();
+            lua.load(&format!("return {}", text))
+                .set_name(name.as_bytes())
+                .and_then(mlua::Chunk::eval)
+                .map_err(|error| error.to_string())
+        };
+        value.map_err(|error| {
+            format!(
+                "Unable to parse expected value file '{}': {}",
+                self.path.display(),
+                error
+            )
+        })
+    }
+}
+
+// Convert a parsed JSON value to Lua, where JSON `null` becomes the
+// `moonunit.null` sentinel, so that it matches an absent value.
+fn json_to_lua(
+    lua: &mlua::Lua,
+    value: json::Value,
+) -> mlua::Result<mlua::Value> {
+    Ok(match value {
+        json::Value::Null => mlua::Value::LightUserData(null_sentinel()),
+        json::Value::Boolean(value) => mlua::Value::Boolean(value),
+        json::Value::Integer(value) => mlua::Value::Integer(value),
+        json::Value::Number(value) => mlua::Value::Number(value),
+        json::Value::String(value) => {
+            mlua::Value::String(lua.create_string(&value)?)
+        },
+        json::Value::Array(elements) => {
+            let table = lua.create_table()?;
+            for (i, element) in elements.into_iter().enumerate() {
+                table.raw_set(i + 1, json_to_lua(lua, element)?)?;
+            }
+            mlua::Value::Table(table)
+        },
+        json::Value::Object(members) => {
+            let table = lua.create_table()?;
+            for (key, value) in members {
+                table.raw_set(key, json_to_lua(lua, value)?)?;
+            }
+            mlua::Value::Table(table)
+        },
+    })
+}
+
+// Convert a Lua value to the form it would have after being converted to
+// JSON and back, for comparing values in their canonical JSON forms.  Floats
+// with integral values become integers, tables which are proper arrays
+// (other than empty tables) keep their elements in order, and other tables
+// have their keys converted to strings, leaving out members whose values are
+// `moonunit.null`, so that they match absent members, as when comparing
+// tables directly.
+fn to_json_form<'lua>(
+    lua: &'lua mlua::Lua,
+    value: &mlua::Value<'lua>,
+    ancestors: &mut Vec<mlua::Table<'lua>>,
+) -> Result<mlua::Value<'lua>, String> {
+    let check_utf8 = |value: &mlua::String| {
+        value.to_str().map(|_| ()).map_err(|_| {
+            String::from("strings must be valid UTF-8 to be converted to JSON")
+        })
+    };
+    let lua_error = |error: mlua::Error| error.to_string();
+    Ok(match normalize_key(value.clone()) {
+        mlua::Value::Nil => mlua::Value::LightUserData(null_sentinel()),
+        value if is_null(&value) => value,
+        value @ mlua::Value::Boolean(_) | value @ mlua::Value::Integer(_) => {
+            value
+        },
+        mlua::Value::Number(value) if value.is_finite() => {
+            mlua::Value::Number(value)
+        },
+        mlua::Value::Number(value) => {
+            return Err(format!("{} can't be converted to JSON", value));
+        },
+        mlua::Value::String(value) => {
+            check_utf8(&value)?;
+            mlua::Value::String(value)
+        },
+        mlua::Value::Table(table) => {
+            if ancestors.contains(&table) {
+                return Err(String::from(
+                    "tables which contain themselves can't be converted to \
+                    JSON",
+                ));
+            }
+            ancestors.push(table.clone());
+            let pairs = sorted_pairs(&table);
+            let is_array = !pairs.is_empty()
+                && matches!(
+                    check_is_array(&mlua::Value::Table(table.clone()), None),
+                    Ok(Ok(()))
+                );
+            let converted = lua.create_table().map_err(lua_error)?;
+            for (i, (key, value)) in pairs.iter().enumerate() {
+                if is_array {
+                    converted
+                        .raw_set(i + 1, to_json_form(lua, value, ancestors)?)
+                        .map_err(lua_error)?;
+                    continue;
+                }
+                if is_null(value) {
+                    continue;
+                }
+                let name = match normalize_key(key.0.clone()) {
+                    mlua::Value::String(key) => {
+                        check_utf8(&key)?;
+                        key
+                    },
+                    mlua::Value::Integer(key) => {
+                        lua.create_string(&key.to_string()).map_err(lua_error)?
+                    },
+                    mlua::Value::Number(key) => lua
+                        .create_string(&format!("{:?}", key))
+                        .map_err(lua_error)?,
+                    key => {
+                        return Err(format!(
+                            "{} keys can't be converted to JSON",
+                            key.type_name()
+                        ));
+                    },
+                };
+                converted
+                    .raw_set(name, to_json_form(lua, value, ancestors)?)
+                    .map_err(lua_error)?;
+            }
+            ancestors.pop();
+            mlua::Value::Table(converted)
+        },
+        value => {
+            return Err(format!(
+                "{} values can't be converted to JSON",
+                value.type_name()
+            ));
+        },
+    })
+}
+
+// Check that two values are equivalent, by comparing the serialized forms
+// (the same as those compared with golden files) of their canonical JSON
+// forms, reporting the lines at which they differ.
+fn check_json_equivalent<'lua>(
+    lua: &'lua mlua::Lua,
+    lhs: &mlua::Value<'lua>,
+    rhs: &mlua::Value<'lua>,
+) -> mlua::Result<Result<(), String>> {
+    let mut canonical = Vec::new();
+    for (which, value) in &[("expected", lhs), ("actual", rhs)] {
+        let json =
+            to_json_form(lua, value, &mut Vec::new()).map_err(|message| {
+                mlua::Error::RuntimeError(format!(
+                    "Unable to convert {} value to JSON: {}",
+                    which, message
+                ))
+            })?;
+        canonical.push(serialize(&json) + "\n");
+    }
+    if canonical[0] == canonical[1] {
+        Ok(Ok(()))
+    } else {
+        Ok(Err(format!(
+            "Canonical JSON forms differ:\n{}",
+            diff_lines(&canonical[0], &canonical[1])
+        )))
+    }
+}
+
+// If the given value is a matcher, give the value it stands for;
+// otherwise give the value itself.
+fn resolve_matcher<'lua>(
+    lua: &'lua mlua::Lua,
+    value: &mlua::Value<'lua>,
+) -> Result<mlua::Value<'lua>, String> {
+    if let mlua::Value::UserData(userdata) = value {
+        if let Ok(matcher) = userdata.borrow::<FileMatcher>() {
+            return matcher.load(lua);
+        }
+    }
+    Ok(value.clone())
+}
+
+// Resolve any matchers given on either side of a comparison.
+fn resolve_matchers<'lua>(
+    lua: &'lua mlua::Lua,
+    lhs: &mlua::Value<'lua>,
+    rhs: &mlua::Value<'lua>,
+) -> Result<(mlua::Value<'lua>, mlua::Value<'lua>), String> {
+    Ok((resolve_matcher(lua, lhs)?, resolve_matcher(lua, rhs)?))
+}
+
+// Check that two values are equal, using either the built-in equality
+// (adjusted by an optional table of options) or a comparator function
+// given in place of the options.  The outer result is for errors in
+// making the check; the inner result is for the check itself, giving a
+// message describing the difference if the values aren't equal.
+fn check_eq<'lua>(
+    lua: &'lua mlua::Lua,
+    lhs: &mlua::Value<'lua>,
+    rhs: &mlua::Value<'lua>,
+    how: mlua::Value<'lua>,
+) -> mlua::Result<Result<(), String>> {
+    let (lhs, rhs) = match resolve_matchers(lua, lhs, rhs) {
+        Ok(values) => values,
+        Err(message) => return Ok(Err(message)),
+    };
+    let (lhs, rhs) = (&lhs, &rhs);
+    if let mlua::Value::Function(comparator) = how {
+        return Ok(match call_comparator(&comparator, lhs, rhs) {
+            ComparatorVerdict::Equal => Ok(()),
+            ComparatorVerdict::Different(Some(message))
+            | ComparatorVerdict::Failed(message) => Err(message),
+            ComparatorVerdict::Different(None) => Err(format!(
+                "Expected {}, actual was {} (according to comparator)",
+                LuaValueForDisplay(lhs),
+                LuaValueForDisplay(rhs),
+            )),
+        });
+    }
+    check_equality_comparable(lhs, rhs)?;
+    let options = ComparisonOptions::from_lua(how)?;
+    if let (mlua::Value::Table(lhs), mlua::Value::Table(rhs)) = (lhs, rhs) {
+        let (message, key_chain) =
+            RunContext::compare_lua_tables(
+                lhs,
+                rhs,
+                &options,
+                Vec::new(),
+                &mut TablePairs::new(),
+            );
+        if message.is_empty() {
+            Ok(Ok(()))
+        } else if key_chain.is_empty() {
+            Ok(Err(format!(
+                "Tables differ (path: {}) -- {}",
+                render_key_chain(key_chain),
+                message
+            )))
+        } else {
+            let expected = render_elided(
+                &mlua::Value::Table(lhs.clone()),
+                &key_chain,
+                &options,
+            );
+            let actual = render_elided(
+                &mlua::Value::Table(rhs.clone()),
+                &key_chain,
+                &options,
+            );
+            Ok(Err(format!(
+                "Tables differ (path: {}) -- {}\n    expected: {}\n    \
+                actual:   {}",
+                render_key_chain(key_chain),
+                message,
+                expected,
+                actual
+            )))
+        }
+    } else if options.values_equal(lhs, rhs)
+        || (is_nil_or_null(lhs) && is_nil_or_null(rhs))
+    {
+        Ok(Ok(()))
+    } else {
+        Ok(Err(format!(
+            "Expected {}, actual was {}{}{}",
+            LuaValueForDisplay(lhs),
+            LuaValueForDisplay(rhs),
+            integer_boundary_note(lhs, rhs),
+            options.comparison_note(lhs, rhs),
+        )))
+    }
+}
+
+// Expand references to environment variables, written as `${NAME}` or
+// `$NAME`, in a line of a configuration file.  A '$' which isn't followed by
+// a name is kept as it is.  If a variable isn't set, its name is returned
+// as the error.
+fn expand_environment_variables(line: &str) -> Result<String, String> {
+    let mut expanded = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let (name, remainder) = if let Some(braced) = after.strip_prefix('{')
+        {
+            braced
+                .find('}')
+                .map_or(("", after), |end| (&braced[..end], &braced[end + 1..]))
+        } else {
+            let end = after
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(after.len());
+            (&after[..end], &after[end..])
+        };
+        if name.is_empty() {
+            expanded.push('$');
+            rest = after;
+        } else {
+            let value = std::env::var(name).map_err(|_| name.to_string())?;
+            expanded.push_str(&value);
+            rest = remainder;
+        }
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+// This is the set of pairs of tables (by identity) which have been reached
+// while comparing two tables.  When a pair is reached again, through a cycle
+// or a table referenced more than once, it's taken to be equal there, so
+// that comparing cyclic tables ends, with any difference between the pair
+// found where the pair was first reached.  Two cyclic tables are therefore
+// equal if all the values reachable from them match.
+type TablePairs = std::collections::HashSet<(
+    *const std::ffi::c_void,
+    *const std::ffi::c_void,
+)>;
+
+// Collect every difference between two tables, rather than just the first,
+// as lines giving the path to each difference, marked with '~' for values
+// which differ, '-' for keys missing from the actual table, and '+' for
+// extra keys in the actual table.
+fn collect_table_differences<'lua>(
+    lhs: &mlua::Table<'lua>,
+    rhs: &mlua::Table<'lua>,
+    options: &ComparisonOptions,
+    key_chain: &mut Vec<mlua::Value<'lua>>,
+    compared: &mut TablePairs,
+    differences: &mut Vec<String>,
+) -> mlua::Result<()> {
+    if lhs == rhs || !compared.insert((lhs.to_pointer(), rhs.to_pointer())) {
+        return Ok(());
+    }
+    let path = |key_chain: &[mlua::Value]| render_key_chain(key_chain.to_vec());
+    let mut rhs_keys = rhs
+        .clone()
+        .pairs::<mlua::Value, mlua::Value>()
+        .filter_map(Result::ok)
+        .map(|(key, _)| (options.comparison_key(key.clone()), key))
+        .collect::<std::collections::BTreeMap<_, _>>();
+    for (key, lhs_value) in sorted_pairs(lhs) {
+        key_chain.push(key.0.clone());
+        let comparison_key = options.comparison_key(key.0);
+        match rhs_keys.remove(&comparison_key) {
+            None if is_null(&lhs_value) => (),
+            None => differences.push(format!(
+                "- {}: missing (expected {})",
+                path(key_chain),
+                LuaValueForDisplay(&lhs_value)
+            )),
+            Some(rhs_key) => {
+                let rhs_value: mlua::Value = rhs.raw_get(rhs_key)?;
+                if let (
+                    mlua::Value::Table(lhs_value),
+                    mlua::Value::Table(rhs_value),
+                ) = (&lhs_value, &rhs_value)
+                {
+                    collect_table_differences(
+                        lhs_value,
+                        rhs_value,
+                        options,
+                        key_chain,
+                        compared,
+                        differences,
+                    )?;
+                } else if !options.values_equal(&lhs_value, &rhs_value)
+                    && !(is_nil_or_null(&lhs_value)
+                        && is_nil_or_null(&rhs_value))
+                {
+                    differences.push(format!(
+                        "~ {}: expected {}, actual was {}",
+                        path(key_chain),
+                        LuaValueForDisplay(&lhs_value),
+                        LuaValueForDisplay(&rhs_value)
+                    ));
+                }
+            },
+        }
+        key_chain.pop();
+    }
+    if options.allow_extra_keys {
+        return Ok(());
+    }
+    for (_, rhs_key) in rhs_keys {
+        let rhs_value: mlua::Value = rhs.raw_get(rhs_key.clone())?;
+        if !is_null(&rhs_value) {
+            key_chain.push(rhs_key);
+            differences.push(format!(
+                "+ {}: unexpected {}",
+                path(key_chain),
+                LuaValueForDisplay(&rhs_value)
+            ));
+            key_chain.pop();
+        }
+    }
+    Ok(())
+}
+
+// Check that two values are equal, like `check_eq`, except that when both
+// are tables, every difference between them is reported (up to the given
+// limit), rather than just the first.
+fn check_eq_diff<'lua>(
+    lua: &'lua mlua::Lua,
+    lhs: &mlua::Value<'lua>,
+    rhs: &mlua::Value<'lua>,
+    how: mlua::Value<'lua>,
+    limit: Option<usize>,
+) -> mlua::Result<Result<(), String>> {
+    let (lhs, rhs) = match resolve_matchers(lua, lhs, rhs) {
+        Ok(values) => values,
+        Err(message) => return Ok(Err(message)),
+    };
+    let (lhs, rhs) = (&lhs, &rhs);
+    let (lhs_table, rhs_table) = match (lhs, rhs) {
+        (mlua::Value::Table(lhs), mlua::Value::Table(rhs))
+            if !matches!(how, mlua::Value::Function(_)) =>
+        {
+            (lhs, rhs)
+        },
+        _ => return check_eq(lua, lhs, rhs, how),
+    };
+    let options = ComparisonOptions::from_lua(how)?;
+    let mut differences = Vec::new();
+    collect_table_differences(
+        lhs_table,
+        rhs_table,
+        &options,
+        &mut Vec::new(),
+        &mut TablePairs::new(),
+        &mut differences,
+    )?;
+    if differences.is_empty() {
+        return Ok(Ok(()));
+    }
+    let total = differences.len();
+    let shown = limit.map_or(total, |limit| limit.min(total));
+    let mut message = format!(
+        "Tables differ in {} place{}:",
+        total,
+        if total == 1 {
+            ""
+        } else {
+            "s"
+        }
+    );
+    for difference in &differences[..shown] {
+        message.push_str("\n    ");
+        message.push_str(difference);
+    }
+    if shown < total {
+        write!(message, "\n    ... and {} more", total - shown).unwrap();
+    }
+    Ok(Err(message))
+}
+
+fn render_values(values: &[mlua::Value]) -> String {
+    values.iter().map(render).collect::<Vec<_>>().join(", ")
+}
+
+// Check that two functions return equal results when called with each of
+// the given inputs, where each input is either a table holding the list of
+// arguments to pass, or a single argument.
+fn check_behaves_like<'lua>(
+    lua: &'lua mlua::Lua,
+    first: &mlua::Function<'lua>,
+    second: &mlua::Function<'lua>,
+    inputs: mlua::Table<'lua>,
+) -> mlua::Result<Result<(), String>> {
+    for (i, input) in inputs.sequence_values::<mlua::Value>().enumerate() {
+        let input = input?;
+        let args = match &input {
+            mlua::Value::Table(args) => args
+                .clone()
+                .sequence_values::<mlua::Value>()
+                .collect::<mlua::Result<Vec<_>>>()?,
+            input => vec![input.clone()],
+        };
+        let mut results = Vec::new();
+        for (which, function) in &[("first", first), ("second", second)] {
+            let call_args = mlua::MultiValue::from_vec(args.clone());
+            match function.call::<_, mlua::MultiValue>(call_args) {
+                Ok(values) => results.push(values.into_vec()),
+                Err(error) => {
+                    return Ok(Err(format!(
+                        "The {} function raised an error for input #{} ({}): \
+                        {}",
+                        which,
+                        i + 1,
+                        render_values(&args),
+                        error
+                    )));
+                },
+            }
+        }
+        let first_results = lua.create_sequence_from(results[0].clone())?;
+        let second_results = lua.create_sequence_from(results[1].clone())?;
+        let (message, key_chain) = RunContext::compare_lua_tables(
+            &first_results,
+            &second_results,
+            &ComparisonOptions::default(),
+            Vec::new(),
+            &mut TablePairs::new(),
+        );
+        if !message.is_empty() {
+            return Ok(Err(format!(
+                "Functions behave differently for input #{} ({}): \
+                first returned ({}), second returned ({}) \
+                (result path: {}) -- {}",
+                i + 1,
+                render_values(&args),
+                render_values(&results[0]),
+                render_values(&results[1]),
+                render_key_chain(key_chain),
+                message
+            )));
+        }
+    }
+    Ok(Ok(()))
+}
+
+// Call a function with the given arguments, expecting it to raise an error,
+// and return the value raised.  The function is called through Lua's own
+// `pcall`, so that the raised value comes back exactly as it was raised,
+// even if it isn't a string (such as a table with `code` and `message`
+// fields), rather than being converted into an error message.  Errors
+// raised to stop the test (such as by `moonunit:skip`, or when the test
+// times out) aren't what's expected, so they're raised again.
+fn check_raises<'lua>(
+    lua: &'lua mlua::Lua,
+    function: mlua::Function<'lua>,
+    args: &[mlua::Value<'lua>],
+) -> mlua::Result<Result<mlua::Value<'lua>, String>> {
+    let pcall: mlua::Function = lua.globals().raw_get("pcall")?;
+    let call_args = std::iter::once(mlua::Value::Function(function))
+        .chain(args.iter().cloned())
+        .collect();
+    let results = pcall
+        .call::<_, mlua::MultiValue>(mlua::MultiValue::from_vec(call_args))?
+        .into_vec();
+    match results.split_first() {
+        Some((mlua::Value::Boolean(false), raised)) => {
+            let raised = raised.first().cloned().unwrap_or(mlua::Value::Nil);
+            raise_if_stopping(&raised)?;
+            Ok(Ok(raised))
+        },
+        Some((_, returned)) => Ok(Err(format!(
+            "Expected function to raise an error, but it returned ({})",
+            render_values(returned)
+        ))),
+        None => Ok(Err(String::from(
+            "Expected function to raise an error, but it returned ()",
+        ))),
+    }
+}
+
+// Call a function with no arguments, expecting it to raise an error, and
+// optionally that the message of the error matches a Lua pattern.  Errors
+// raised which aren't strings are converted to their messages using
+// `tostring`, as Lua itself does when reporting them.
+fn check_throws<'lua>(
+    lua: &'lua mlua::Lua,
+    function: mlua::Function<'lua>,
+    pattern: Option<&mlua::String<'lua>>,
+) -> mlua::Result<Result<(), String>> {
+    let raised = match check_raises(lua, function, &[])? {
+        Ok(raised) => raised,
+        Err(message) => return Ok(Err(message)),
+    };
+    let pattern = if let Some(pattern) = pattern {
+        pattern
+    } else {
+        return Ok(Ok(()));
+    };
+    let tostring: mlua::Function = lua.globals().raw_get("tostring")?;
+    let message: mlua::String = tostring.call(raised)?;
+    let find: mlua::Function = lua
+        .globals()
+        .raw_get::<_, mlua::Table>("string")?
+        .raw_get("find")?;
+    let start: mlua::Value = find.call((message.clone(), pattern.clone()))?;
+    if let mlua::Value::Nil = start {
+        Ok(Err(format!(
+            "Expected function to raise an error matching pattern {}, \
+            but the error raised was {}",
+            render(&mlua::Value::String(pattern.clone())),
+            render(&mlua::Value::String(message))
+        )))
+    } else {
+        Ok(Ok(()))
+    }
+}
+
+// Call a function with no arguments, expecting it not to raise an error.
+// The function is called through Lua's own `xpcall`, with `debug.traceback`
+// as the message handler, so that if it does raise an error, the traceback
+// of where the error was raised is reported along with the error.  Errors
+// raised to stop the test (such as by `moonunit:skip`, or when the test
+// times out) are raised again, rather than being reported as failures.
+fn check_no_error<'lua>(
+    lua: &'lua mlua::Lua,
+    function: mlua::Function<'lua>,
+) -> mlua::Result<Result<(), String>> {
+    let xpcall: mlua::Function = lua.globals().raw_get("xpcall")?;
+    let debug: mlua::Table = lua.named_registry_value(DEBUG_REGISTRY_NAME)?;
+    let traceback: mlua::Function = debug.raw_get("traceback")?;
+    let (succeeded, raised): (bool, mlua::Value) =
+        xpcall.call((function, traceback))?;
+    if succeeded {
+        return Ok(Ok(()));
+    }
+    raise_if_stopping(&raised)?;
+
+    // Values raised other than strings (such as tables) are shown with
+    // their contents.
+    let message = match &raised {
+        mlua::Value::String(message) => message.to_string_lossy().into_owned(),
+        mlua::Value::Error(error) => error.to_string(),
+        raised => render(raised),
+    };
+    Ok(Err(format!(
+        "Expected function not to raise an error, but it raised: {}",
+        message
+    )))
+}
+
+fn check_ne<'lua>(
+    lua: &'lua mlua::Lua,
+    lhs: &mlua::Value<'lua>,
+    rhs: &mlua::Value<'lua>,
+    how: mlua::Value<'lua>,
+) -> mlua::Result<Result<(), String>> {
+    let (lhs, rhs) = match resolve_matchers(lua, lhs, rhs) {
+        Ok(values) => values,
+        Err(message) => return Ok(Err(message)),
+    };
+    let (lhs, rhs) = (&lhs, &rhs);
+    if let mlua::Value::Function(comparator) = how {
+        return Ok(match call_comparator(&comparator, lhs, rhs) {
+            ComparatorVerdict::Equal => Err(format!(
+                "Expected not {}, actual was {} (according to comparator)",
+                LuaValueForDisplay(lhs),
+                LuaValueForDisplay(rhs),
+            )),
+            ComparatorVerdict::Different(_) => Ok(()),
+            ComparatorVerdict::Failed(message) => Err(message),
+        });
+    }
+    check_equality_comparable(lhs, rhs)?;
+    let options = ComparisonOptions::from_lua(how)?;
+    if let (mlua::Value::Table(lhs), mlua::Value::Table(rhs)) = (lhs, rhs) {
+        let (message, _key_chain) =
+            RunContext::compare_lua_tables(
+                lhs,
+                rhs,
+                &options,
+                Vec::new(),
+                &mut TablePairs::new(),
+            );
+        if message.is_empty() {
+            Ok(Err(String::from("Tables should differ but are the same")))
+        } else {
+            Ok(Ok(()))
+        }
+    } else if options.values_equal(lhs, rhs)
+        || (is_nil_or_null(lhs) && is_nil_or_null(rhs))
+    {
+        Ok(Err(format!(
+            "Expected not {}, actual was {}{}",
+            LuaValueForDisplay(lhs),
+            LuaValueForDisplay(rhs),
+            options.comparison_note(lhs, rhs),
+        )))
+    } else {
+        Ok(Ok(()))
+    }
+}
+
+// Lua integer arithmetic silently wraps around on overflow, so when a numeric
+// check fails with an operand at either end of the integer range, add a
+// note to the failure message hinting that this may be why.
+fn integer_boundary_note(
+    lhs: &mlua::Value,
+    rhs: &mlua::Value,
+) -> String {
+    let notes = [lhs, rhs]
+        .iter()
+        .filter_map(|value| match value {
+            mlua::Value::Integer(value) if *value == mlua::Integer::MAX => {
+                Some(format!("{} is math.maxinteger", value))
+            },
+            mlua::Value::Integer(value) if *value == mlua::Integer::MIN => {
+                Some(format!("{} is math.mininteger", value))
+            },
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if notes.is_empty() {
+        String::new()
+    } else {
+        format!(
+            " (note: {}; integer arithmetic may have overflowed)",
+            notes.join(" and ")
+        )
+    }
+}
+
+struct LuaValueForDisplay<'lua>(&'lua mlua::Value<'lua>);
+
+impl<'lua> std::fmt::Display for LuaValueForDisplay<'lua> {
+    fn fmt(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+    ) -> std::fmt::Result {
+        match &self.0 {
+            mlua::Value::Nil => {
+                write!(f, "nil")
+            },
+            value if is_null(value) => {
+                write!(f, "null")
+            },
+            mlua::Value::Boolean(value) => {
+                write!(f, "{} (boolean)", value)
+            },
+            mlua::Value::Integer(value) => {
+                write!(f, "{} (integer)", value)
+            },
+            mlua::Value::Number(value) => {
+                write!(f, "{} (number)", value)
+            },
+            mlua::Value::String(value) => {
+                write!(f, "\"{}\" (string)", value.to_string_lossy())
+            },
+            mlua::Value::Thread(thread) => {
+                write!(f, "<coroutine: {}>", coroutine_status(thread))
+            },
+            mlua::Value::Table(_)
+            | mlua::Value::LightUserData(_)
+            | mlua::Value::Function(_)
+            | mlua::Value::UserData(_)
+            | mlua::Value::Error(_) => {
+                write!(f, "{}", render(self.0))
+            },
+        }
+    }
+}
+
+struct OrderedLuaValue<'lua>(mlua::Value<'lua>);
+
+impl<'lua> PartialEq for OrderedLuaValue<'lua> {
+    fn eq(
+        &self,
+        other: &Self,
+    ) -> bool {
+        self.0.eq(&other.0)
+    }
+}
+
+impl<'lua> Eq for OrderedLuaValue<'lua> {
+    fn assert_receiver_is_total_eq(&self) {}
+}
+
+impl<'lua> PartialOrd for OrderedLuaValue<'lua> {
+    fn partial_cmp(
+        &self,
+        other: &Self,
+    ) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<'lua> Ord for OrderedLuaValue<'lua> {
+    fn cmp(
+        &self,
+        other: &Self,
+    ) -> std::cmp::Ordering {
+        match (&self.0, &other.0) {
+            (
+                mlua::Value::Boolean(value),
+                mlua::Value::Boolean(other_value),
+            ) => value.cmp(other_value),
+            (
+                mlua::Value::Integer(value),
+                mlua::Value::Integer(other_value),
+            ) => value.cmp(other_value),
+            (mlua::Value::Number(value), mlua::Value::Number(other_value)) => {
+                if value < other_value {
+                    std::cmp::Ordering::Less
+                } else if value > other_value {
+                    std::cmp::Ordering::Greater
+                } else {
+                    std::cmp::Ordering::Equal
+                }
+            },
+
+            // Strings are compared byte by byte, since they aren't
+            // necessarily valid UTF-8.
+            (mlua::Value::String(value), mlua::Value::String(other_value)) => {
+                value.as_bytes().cmp(other_value.as_bytes())
+            },
+
+            // Values which can only be compared by reference are ordered by
+            // their addresses, which is arbitrary but stable for as long as
+            // the values are alive.
+            (
+                mlua::Value::LightUserData(value),
+                mlua::Value::LightUserData(other_value),
+            ) => value.0.cmp(&other_value.0),
+            (mlua::Value::Table(value), mlua::Value::Table(other_value)) => {
+                value.to_pointer().cmp(&other_value.to_pointer())
+            },
+            (
+                mlua::Value::Function(value),
+                mlua::Value::Function(other_value),
+            ) => value.to_pointer().cmp(&other_value.to_pointer()),
+            (mlua::Value::Thread(value), mlua::Value::Thread(other_value)) => {
+                value.to_pointer().cmp(&other_value.to_pointer())
+            },
+            (
+                mlua::Value::UserData(value),
+                mlua::Value::UserData(other_value),
+            ) => value.to_pointer().cmp(&other_value.to_pointer()),
+            (mlua::Value::Error(value), mlua::Value::Error(other_value)) => {
+                value.to_string().cmp(&other_value.to_string())
+            },
+            (mlua::Value::Nil, mlua::Value::Nil) => std::cmp::Ordering::Equal,
+
+            // Values of different types are ordered by the names of their
+            // types.
+            (value, other_value) => {
+                value.type_name().cmp(other_value.type_name())
+            },
+        }
+    }
+}
+
+fn sorted_pairs<'lua>(
+    table: &mlua::Table<'lua>
+) -> Vec<(OrderedLuaValue<'lua>, mlua::Value<'lua>)> {
+    let mut pairs = table
+        .clone()
+        .pairs::<mlua::Value, mlua::Value>()
+        .filter_map(Result::ok)
+        .map(|(key, value)| (OrderedLuaValue(key), value))
+        .collect::<Vec<_>>();
+    pairs.sort_by(|(lhs, _), (rhs, _)| lhs.cmp(rhs));
+    pairs
+}
+
+fn serialize(value: &mlua::Value) -> String {
+    let mut buffer = String::new();
+    serialize_value(&mut buffer, value, 0, &mut Vec::new());
+    buffer
+}
+
+fn serialize_indentation(
+    buffer: &mut String,
+    indentation: usize,
+) {
+    for _ in 0..indentation {
+        buffer.push_str("    ");
+    }
+}
+
+fn serialize_string(
+    buffer: &mut String,
+    value: &[u8],
+) {
+    buffer.push('"');
+    if let Ok(value) = std::str::from_utf8(value) {
+        for c in value.chars() {
+            match c {
+                '"' => buffer.push_str("\\\""),
+                '\\' => buffer.push_str("\\\\"),
+                '\n' => buffer.push_str("\\n"),
+                '\r' => buffer.push_str("\\r"),
+                '\t' => buffer.push_str("\\t"),
+                c if c.is_control() => {
+                    write!(buffer, "\\{:03}", u32::from(c)).unwrap();
+                },
+                c => buffer.push(c),
+            }
+        }
+    } else {
+        for byte in value {
+            match byte {
+                b'"' => buffer.push_str("\\\""),
+                b'\\' => buffer.push_str("\\\\"),
+                0x20..=0x7E => buffer.push(char::from(*byte)),
+                _ => write!(buffer, "\\{:03}", byte).unwrap(),
+            }
+        }
+    }
+    buffer.push('"');
+}
+
+fn is_lua_identifier(value: &[u8]) -> bool {
+    match value.split_first() {
+        Some((first, rest)) => {
+            (first.is_ascii_alphabetic() || *first == b'_')
+                && rest
+                    .iter()
+                    .all(|byte| byte.is_ascii_alphanumeric() || *byte == b'_')
+        },
+        None => false,
+    }
+}
+
+// Serialize a Lua value in a deterministic, human-readable form resembling
+// a Lua table constructor, with table keys sorted and nested tables
+// indented.  Tables which contain themselves are not followed back around;
+// the repeated reference is written as "<cycle>" instead.
+fn serialize_value<'lua>(
+    buffer: &mut String,
+    value: &mlua::Value<'lua>,
+    indentation: usize,
+    ancestors: &mut Vec<mlua::Table<'lua>>,
+) {
+    match value {
+        mlua::Value::Nil => buffer.push_str("nil"),
+        mlua::Value::Boolean(value) => write!(buffer, "{}", value).unwrap(),
+        mlua::Value::Integer(value) => write!(buffer, "{}", value).unwrap(),
+        mlua::Value::Number(value) => write!(buffer, "{:?}", value).unwrap(),
+        mlua::Value::String(value) => {
+            serialize_string(buffer, value.as_bytes());
+        },
+        mlua::Value::Table(table) => {
+            if ancestors.contains(table) {
+                buffer.push_str("<cycle>");
+                return;
+            }
+            let pairs = sorted_pairs(table);
+            if pairs.is_empty() {
+                buffer.push_str("{}");
+                return;
+            }
+            ancestors.push(table.clone());
+            buffer.push_str("{\n");
+            for (key, value) in &pairs {
+                serialize_indentation(buffer, indentation + 1);
+                match &key.0 {
+                    mlua::Value::String(key)
+                        if is_lua_identifier(key.as_bytes()) =>
+                    {
+                        buffer.push_str(&key.to_string_lossy());
+                    },
+                    key => {
+                        buffer.push('[');
+                        serialize_value(
+                            buffer,
+                            key,
+                            indentation + 1,
+                            ancestors,
+                        );
+                        buffer.push(']');
+                    },
+                }
+                buffer.push_str(" = ");
+                serialize_value(buffer, value, indentation + 1, ancestors);
+                buffer.push_str(",\n");
+            }
+            ancestors.pop();
+            serialize_indentation(buffer, indentation);
+            buffer.push('}');
+        },
+        value if is_null(value) => buffer.push_str("moonunit.null"),
+        mlua::Value::LightUserData(_)
+        | mlua::Value::Function(_)
+        | mlua::Value::Thread(_)
+        | mlua::Value::UserData(_)
+        | mlua::Value::Error(_) => {
+            write!(buffer, "<{}>", value.type_name()).unwrap();
+        },
+    }
+}
+
+// Produce a simple line-oriented difference between two texts, showing
+// the lines removed from the expected text with a leading '-' and the lines
+// added in the actual text with a leading '+', along with the line of
+// context immediately before the difference, if any.
+fn diff_lines(
+    expected: &str,
+    actual: &str,
+) -> String {
+    let expected = expected.lines().collect::<Vec<_>>();
+    let actual = actual.lines().collect::<Vec<_>>();
+    let prefix = expected
+        .iter()
+        .zip(actual.iter())
+        .take_while(|(expected, actual)| expected == actual)
+        .count();
+    let suffix = expected[prefix..]
+        .iter()
+        .rev()
+        .zip(actual[prefix..].iter().rev())
+        .take_while(|(expected, actual)| expected == actual)
+        .count();
+    let mut diff = String::new();
+    writeln!(&mut diff, "@@ line {} @@", prefix + 1).unwrap();
+    if prefix > 0 {
+        writeln!(&mut diff, " {}", expected[prefix - 1]).unwrap();
+    }
+    for line in &expected[prefix..expected.len() - suffix] {
+        writeln!(&mut diff, "-{}", line).unwrap();
+    }
+    for line in &actual[prefix..actual.len() - suffix] {
+        writeln!(&mut diff, "+{}", line).unwrap();
+    }
+    diff
+}
+
+#[derive(Default)]
+struct ComparisonOptions {
+    allow_extra_keys: bool,
+    coerce_numeric_string_keys: bool,
+    normalize: Option<NormalizationForm>,
+    tolerance: Option<mlua::Number>,
+}
+
+#[derive(Clone, Copy)]
+enum NormalizationForm {
+    Nfc,
+    Nfd,
+    Nfkc,
+    Nfkd,
+}
+
+impl NormalizationForm {
+    fn name(self) -> &'static str {
+        match self {
+            NormalizationForm::Nfc => "NFC",
+            NormalizationForm::Nfd => "NFD",
+            NormalizationForm::Nfkc => "NFKC",
+            NormalizationForm::Nfkd => "NFKD",
+        }
+    }
+
+    fn normalize(
+        self,
+        value: &str,
+    ) -> String {
+        match self {
+            NormalizationForm::Nfc => value.nfc().collect(),
+            NormalizationForm::Nfd => value.nfd().collect(),
+            NormalizationForm::Nfkc => value.nfkc().collect(),
+            NormalizationForm::Nfkd => value.nfkd().collect(),
+        }
+    }
+}
+
+impl ComparisonOptions {
+    // Determine the key to use when matching up the entries of two tables
+    // being compared.  Normally this is just the key itself, but if enabled,
+    // string keys which look like numbers are matched up with the
+    // corresponding numeric keys (so `{[1] = "a"}` and `{["1"] = "a"}`
+    // are considered equal).
+    fn comparison_key<'lua>(
+        &self,
+        key: mlua::Value<'lua>,
+    ) -> OrderedLuaValue<'lua> {
+        if self.coerce_numeric_string_keys {
+            if let mlua::Value::String(string) = &key {
+                if let Some(number) =
+                    string.to_str().ok().and_then(parse_numeric_key)
+                {
+                    return OrderedLuaValue(number);
+                }
+            }
+        }
+        OrderedLuaValue(normalize_key(key))
+    }
+
+    // Determine whether two values (which aren't both tables) are equal.
+    // Normally this is just Lua's raw equality, where an integer and a float
+    // are equal if they have the same numeric value (as in `1 == 1.0`), but
+    // if enabled, numbers are equal if they're within a tolerance of each
+    // other, and strings which are valid UTF-8 are compared after Unicode
+    // normalization.
+    fn values_equal(
+        &self,
+        lhs: &mlua::Value,
+        rhs: &mlua::Value,
+    ) -> bool {
+        if let (Some(tolerance), Some(lhs), Some(rhs)) =
+            (self.tolerance, as_number(lhs), as_number(rhs))
+        {
+            return (lhs - rhs).abs() <= tolerance;
+        }
+        if let (mlua::Value::Integer(_), mlua::Value::Number(_))
+        | (mlua::Value::Number(_), mlua::Value::Integer(_)) = (lhs, rhs)
+        {
+            // A float equals an integer only if it has exactly the same
+            // value, which is the case when it normalizes to the integer.
+            return normalize_key(lhs.clone()) == normalize_key(rhs.clone());
+        }
+        if let (
+            Some(form),
+            mlua::Value::String(lhs_string),
+            mlua::Value::String(rhs_string),
+        ) = (self.normalize, lhs, rhs)
+        {
+            if let (Ok(lhs), Ok(rhs)) =
+                (lhs_string.to_str(), rhs_string.to_str())
+            {
+                return form.normalize(lhs) == form.normalize(rhs);
+            }
+        }
+        lhs == rhs
+    }
+
+    // Give a note to add to failure messages about numbers, when they were
+    // compared within a tolerance, or strings, when they were normalized
+    // before being compared.
+    fn comparison_note(
+        &self,
+        lhs: &mlua::Value,
+        rhs: &mlua::Value,
+    ) -> String {
+        if let (Some(tolerance), Some(lhs_number), Some(rhs_number)) =
+            (self.tolerance, as_number(lhs), as_number(rhs))
+        {
+            return format!(
+                " (difference {}, tolerance {})",
+                (lhs_number - rhs_number).abs(),
+                tolerance
+            );
+        }
+        match (self.normalize, lhs, rhs) {
+            (
+                Some(form),
+                mlua::Value::String(_),
+                mlua::Value::String(_),
+            ) => format!(" (compared after {} normalization)", form.name()),
+            _ => String::new(),
+        }
+    }
+
+    fn from_lua(options: mlua::Value) -> mlua::Result<Self> {
+        let mut comparison_options = Self::default();
+        match options {
+            mlua::Value::Nil => (),
+            mlua::Value::Table(options) => {
+                comparison_options.allow_extra_keys = options
+                    .get::<_, Option<bool>>("allow_extra_keys")?
+                    .unwrap_or(false);
+                comparison_options.coerce_numeric_string_keys = options
+                    .get::<_, Option<bool>>("coerce_numeric_string_keys")?
+                    .unwrap_or(false);
+                comparison_options.normalize = match options
+                    .get::<_, Option<String>>("normalize")?
+                    .as_deref()
+                {
+                    None => None,
+                    Some("NFC") => Some(NormalizationForm::Nfc),
+                    Some("NFD") => Some(NormalizationForm::Nfd),
+                    Some("NFKC") => Some(NormalizationForm::Nfkc),
+                    Some("NFKD") => Some(NormalizationForm::Nfkd),
+                    Some(form) => {
+                        return Err(mlua::Error::RuntimeError(format!(
+                            "Unknown Unicode normalization form '{}' \
+                            (expected NFC, NFD, NFKC, or NFKD)",
+                            form
+                        )));
+                    },
+                };
+                comparison_options.tolerance =
+                    options.get::<_, Option<mlua::Number>>("tolerance")?;
+                if let Some(tolerance) = comparison_options.tolerance {
+                    check_tolerance(tolerance)?;
+                }
+            },
+            options => {
+                return Err(mlua::Error::RuntimeError(format!(
+                    "Expected a table of options or a comparator function, \
+                    but got {}",
+                    LuaValueForDisplay(&options)
+                )));
+            },
+        }
+        Ok(comparison_options)
+    }
+}
+
+// Parse a string as a number the way it would be normalized if used as a
+// table key in Lua, where floats with integral values become integers.
+#[allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+fn parse_numeric_key<'lua>(key: &str) -> Option<mlua::Value<'lua>> {
+    let key = key.trim();
+    if let Ok(integer) = key.parse::<mlua::Integer>() {
+        return Some(mlua::Value::Integer(integer));
+    }
+    let number = key.parse::<mlua::Number>().ok()?;
+    if number.is_finite() {
+        Some(normalize_key(mlua::Value::Number(number)))
+    } else {
+        None
+    }
+}
+
+// Normalize a table key the way Lua does, where floats with integral values
+// are the same keys as the corresponding integers.
+#[allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+fn normalize_key(key: mlua::Value) -> mlua::Value {
+    match key {
+        mlua::Value::Number(number)
+            if number.fract() == 0.0
+                && number >= mlua::Integer::MIN as mlua::Number
+                && number < mlua::Integer::MAX as mlua::Number =>
+        {
+            mlua::Value::Integer(number as mlua::Integer)
+        },
+        key => key,
+    }
+}
+
+struct RunContext {
+    default_suite: Option<String>,
+    errors: std::rc::Rc<std::cell::RefCell<Vec<String>>>,
+    file: String,
+    path: std::path::PathBuf,
+    runner: Runner,
+    tests_registry_key: std::rc::Rc<mlua::RegistryKey>,
+}
+
+// If the first line of a test script is a comment of the form
+// "-- @suite NAME", it provides the name of the test suite to use for
+// tests registered with an empty suite name.
+fn parse_suite_directive(script: &str) -> Option<String> {
+    let name = script
+        .lines()
+        .next()?
+        .trim()
+        .strip_prefix("--")?
+        .trim_start()
+        .strip_prefix("@suite")?;
+    if !name.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let name = name.trim();
+    if name.is_empty() {
+        None
+    } else {
+        Some(name.to_owned())
+    }
+}
+
+// This is the error raised by `moonunit:abort` to stop the current test.
+// It's recognized once the test has stopped, so that the test can be
+// reported as aborted, rather than as having raised an error.
+#[derive(Debug)]
+struct TestAborted(String);
+
+impl std::fmt::Display for TestAborted {
+    fn fmt(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+    ) -> std::fmt::Result {
+        write!(f, "Test aborted: {}", self.0)
+    }
+}
+
+impl std::error::Error for TestAborted {}
+
+// This is the error raised by `moonunit:skip` to stop the current test and
+// have it reported as skipped, rather than as passed or failed.
+#[derive(Debug)]
+struct TestSkipped(String);
+
+impl std::fmt::Display for TestSkipped {
+    fn fmt(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+    ) -> std::fmt::Result {
+        write!(f, "Test skipped: {}", self.0)
+    }
+}
+
+impl std::error::Error for TestSkipped {}
+
+// This is the error raised by the Lua hook to stop a test which has run
+// for longer than the timeout given to the runner.
+#[derive(Debug)]
+struct TestTimedOut(std::time::Duration);
+
+impl std::fmt::Display for TestTimedOut {
+    fn fmt(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+    ) -> std::fmt::Result {
+        write!(f, "Test exceeded timeout of {} ms", self.0.as_millis())
+    }
+}
+
+impl std::error::Error for TestTimedOut {}
+
+// Find the error of the given type raised by MoonUnit itself to stop a
+// test, if that's what the given error is, which may be wrapped in errors
+// from the callbacks it passed through on its way out of the test.
+fn stop_cause<T>(error: &mlua::Error) -> Option<&T>
+where
+    T: std::error::Error + 'static,
+{
+    match error {
+        mlua::Error::CallbackError {
+            cause,
+            ..
+        } => stop_cause(cause),
+        mlua::Error::ExternalError(error) => error.downcast_ref::<T>(),
+        _ => None,
+    }
+}
+
+// Find the reason given to `moonunit:abort`, if the given error was raised
+// by it.
+fn abort_reason(error: &mlua::Error) -> Option<&str> {
+    stop_cause::<TestAborted>(error).map(|aborted| aborted.0.as_str())
+}
+
+// Raise again a value caught while calling a function on behalf of an
+// assertion, if it's one of the errors raised by MoonUnit itself to stop
+// the test, so that catching it doesn't keep the test from stopping.
+fn raise_if_stopping(raised: &mlua::Value) -> mlua::Result<()> {
+    if let mlua::Value::Error(error) = raised {
+        if stop_cause::<TestAborted>(error).is_some()
+            || stop_cause::<TestSkipped>(error).is_some()
+            || stop_cause::<TestTimedOut>(error).is_some()
+        {
+            return Err(error.clone());
+        }
+    }
+    Ok(())
+}
+
+impl mlua::UserData for RunContext {
+    fn add_fields<'lua, F: mlua::UserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("null", |_, _| Ok(null_sentinel()));
+    }
+
+    fn add_methods<'lua, M: mlua::UserDataMethods<'lua, Self>>(
+        methods: &mut M
+    ) {
+        methods.add_method("test", moonunit_test);
+        methods.add_method("test_each", moonunit_test_each);
+        methods.add_method("before_all", moonunit_before_all);
+        methods.add_method("after_all", moonunit_after_all);
+        methods.add_method("setup", moonunit_setup);
+        methods.add_method("teardown", moonunit_teardown);
+        methods.add_method("before_each", moonunit_setup);
+        methods.add_method("after_each", moonunit_teardown);
+        methods.add_method("from_file", moonunit_from_file);
+        methods.add_method("abort", moonunit_abort);
+        methods.add_method("skip", moonunit_skip);
+        methods.add_method("log", moonunit_log);
+        // Assertions and expectations are counted as they're checked.
+        macro_rules! add_assertion {
+            ($name:expr, $method:ident) => {
+                methods.add_method($name, |lua, this, args| {
+                    this.runner.inner.borrow_mut().current_test_assertions += 1;
+                    $method(lua, this, args)
+                });
+            };
+        }
+        add_assertion!("assert_eq", moonunit_assert_eq);
+        add_assertion!("assert_ne", moonunit_assert_ne);
+        add_assertion!("assert_ge", moonunit_assert_ge);
+        add_assertion!("assert_gt", moonunit_assert_gt);
+        add_assertion!("assert_le", moonunit_assert_le);
+        add_assertion!("assert_lt", moonunit_assert_lt);
+        add_assertion!("assert_true", moonunit_assert_true);
+        add_assertion!("assert_false", moonunit_assert_false);
+        add_assertion!("assert_truthy", moonunit_assert_true);
+        add_assertion!("assert_falsy", moonunit_assert_false);
+        add_assertion!("assert_nil", moonunit_assert_nil);
+        add_assertion!("assert_not_nil", moonunit_assert_not_nil);
+        add_assertion!("assert_type", moonunit_assert_type);
+        add_assertion!("assert_match", moonunit_assert_match);
+        add_assertion!("assert_contains", moonunit_assert_contains);
+        add_assertion!("assert_is_array", moonunit_assert_is_array);
+        add_assertion!("assert_len", moonunit_assert_len);
+        add_assertion!("assert_near", moonunit_assert_near);
+        add_assertion!("assert_array_near", moonunit_assert_array_near);
+        add_assertion!(
+            "assert_json_equivalent",
+            moonunit_assert_json_equivalent
+        );
+        add_assertion!("assert_matches_golden", moonunit_assert_matches_golden);
+        add_assertion!("assert_behaves_like", moonunit_assert_behaves_like);
+        add_assertion!("assert_eq_diff", moonunit_assert_eq_diff);
+        add_assertion!("assert_approx_eq", moonunit_assert_approx_eq);
+        add_assertion!("assert_subset", moonunit_assert_subset);
+        add_assertion!(
+            "assert_raises_with_value",
+            moonunit_assert_raises_with_value
+        );
+        add_assertion!("assert_throws", moonunit_assert_throws);
+        add_assertion!("assert_throws_match", moonunit_assert_throws_match);
+        add_assertion!("assert_no_error", moonunit_assert_no_error);
+        add_assertion!("expect_eq", moonunit_expect_eq);
+        add_assertion!("expect_ne", moonunit_expect_ne);
+        add_assertion!("expect_ge", moonunit_expect_ge);
+        add_assertion!("expect_gt", moonunit_expect_gt);
+        add_assertion!("expect_le", moonunit_expect_le);
+        add_assertion!("expect_lt", moonunit_expect_lt);
+        add_assertion!("expect_true", moonunit_expect_true);
+        add_assertion!("expect_false", moonunit_expect_false);
+        add_assertion!("expect_truthy", moonunit_expect_true);
+        add_assertion!("expect_falsy", moonunit_expect_false);
+        add_assertion!("expect_nil", moonunit_expect_nil);
+        add_assertion!("expect_not_nil", moonunit_expect_not_nil);
+        add_assertion!("expect_type", moonunit_expect_type);
+        add_assertion!("expect_match", moonunit_expect_match);
+        add_assertion!("expect_contains", moonunit_expect_contains);
+        add_assertion!("expect_is_array", moonunit_expect_is_array);
+        add_assertion!("expect_len", moonunit_expect_len);
+        add_assertion!("expect_near", moonunit_expect_near);
+        add_assertion!("expect_array_near", moonunit_expect_array_near);
+        add_assertion!(
+            "expect_json_equivalent",
+            moonunit_expect_json_equivalent
+        );
+        add_assertion!("expect_matches_golden", moonunit_expect_matches_golden);
+        add_assertion!("expect_behaves_like", moonunit_expect_behaves_like);
+        add_assertion!("expect_eq_diff", moonunit_expect_eq_diff);
+        add_assertion!("expect_approx_eq", moonunit_expect_approx_eq);
+        add_assertion!("expect_subset", moonunit_expect_subset);
+        add_assertion!(
+            "expect_raises_with_value",
+            moonunit_expect_raises_with_value
+        );
+        add_assertion!("expect_throws", moonunit_expect_throws);
+        add_assertion!("expect_throws_match", moonunit_expect_throws_match);
+        add_assertion!("expect_no_error", moonunit_expect_no_error);
+    }
+}
+
+fn moonunit_test(
+    lua: &mlua::Lua,
+    this: &RunContext,
+    (suite, name, test, options): (
+        String,
+        String,
+        mlua::Function,
+        Option<mlua::Table>,
+    ),
+) -> mlua::Result<()> {
+    // Use the default suite name, if one was given, for tests registered
+    // without a suite name.
+    let suite = this.suite_name(suite, || format!("test '{}'", name))?;
+
+    // Get line number information about the provided function.
+    let line_number = test.source().line_defined;
+    register_test(lua, this, suite, name, test, line_number, options)
+}
+
+// This is the function registered for each test made by `test_each`,
+// which calls the function given to `test_each` with the test's case, and
+// then anything passed to the test (such as its fixture).
+const PARAMETERIZED_TEST_SCRIPT: &str = r#"
+    local test, case = ...
+    return function(...)
+        return test(case, ...)
+    end
+"#;
+
+// Register a test for each case in a table of cases, all calling the same
+// function, given the case.  The tests are named after the given name,
+// followed by '/' and either the `name` field of the case (if it's a table
+// with one) or the position of the case in the table, starting from 1.
+fn moonunit_test_each<'lua>(
+    lua: &'lua mlua::Lua,
+    this: &RunContext,
+    (suite, name, cases, test, options): (
+        String,
+        String,
+        mlua::Table<'lua>,
+        mlua::Function<'lua>,
+        Option<mlua::Table<'lua>>,
+    ),
+) -> mlua::Result<()> {
+    let suite = this.suite_name(suite, || format!("tests '{}'", name))?;
+    let line_number = test.source().line_defined;
+    let mut case_names = std::collections::HashSet::new();
+    for (i, case) in cases.sequence_values::<mlua::Value>().enumerate() {
+        let case = case?;
+        let case_name = match &case {
+            mlua::Value::Table(case) => case.get::<_, Option<String>>("name")?,
+            _ => None,
+        }
+        .unwrap_or_else(|| (i + 1).to_string());
+
+        // Cases with the same name would make tests with the same name,
+        // the last of which would replace the others.
+        if !case_names.insert(case_name.clone()) {
+            return Err(mlua::Error::RuntimeError(format!(
+                "More than one case of tests '{}' is named '{}'",
+                name, case_name
+            )));
+        }
+        let case_test: mlua::Function = lua
+            .load(PARAMETERIZED_TEST_SCRIPT)
+            .call((test.clone(), case))?;
+        register_test(
+            lua,
+            this,
+            suite.clone(),
+            format!("{}/{}", name, case_name),
+            case_test,
+            line_number,
+            options.clone(),
+        )?;
+    }
+    Ok(())
+}
+
+// Add a test to the tests table of the Lua state, and, if it isn't already
+// known, information about it to the runner.
+fn register_test(
+    lua: &mlua::Lua,
+    this: &RunContext,
+    suite: String,
+    name: String,
+    test: mlua::Function,
+    line_number: i32,
+    options: Option<mlua::Table>,
+) -> mlua::Result<()> {
+    // Get any optional information provided about the test.
+    let (tags, description, abort_after_failures, depends_on, expect_fail) =
+        if let Some(options) = options {
+            (
+                options
+                    .get::<_, Option<Vec<String>>>("tags")?
+                    .unwrap_or_default(),
+                options.get::<_, Option<String>>("description")?,
+                options.get::<_, Option<usize>>("abort_after_failures")?,
+                options
+                    .get::<_, Option<Vec<String>>>("depends_on")?
+                    .unwrap_or_default(),
+                options
+                    .get::<_, Option<bool>>("expect_fail")?
+                    .unwrap_or(false),
+            )
+        } else {
+            (Vec::new(), None, None, Vec::new(), false)
+        };
+
+    // Dependencies are named either "suite.name", or just "name" for
+    // another test in the same suite.
+    let depends_on = depends_on
+        .into_iter()
+        .map(|dependency| match dependency.find('.') {
+            Some(delimiter_index) => (
+                dependency[..delimiter_index].to_owned(),
+                dependency[delimiter_index + 1..].to_owned(),
+            ),
+            None => (suite.clone(), dependency),
+        })
+        .collect();
+
+    // Make sure there is a table for this suite of tests.
+    let tests_table: mlua::Table =
+        lua.registry_value(&this.tests_registry_key)?;
+    if !tests_table.contains_key(suite.clone())? {
+        tests_table.set(suite.clone(), lua.create_table()?)?;
+    }
+
+    // Store the function in the tests table.
+    let tests: mlua::Table = tests_table.get(suite.clone())?;
+    tests.set(name.clone(), test)?;
+
+    // Add information about the test to the runner, if it's not already
+    // known, numbering tests in the order in which they're first defined.
+    let mut runner = this.runner.inner.borrow_mut();
+    let runner = &mut *runner;
+    let suite = runner.test_suites.entry(suite).or_default();
+    if let std::collections::hash_map::Entry::Vacant(entry) =
+        suite.tests.entry(name)
+    {
+        #[allow(clippy::cast_sign_loss)]
+        entry.insert(Test {
+            file: this.file.clone(),
+            path: this.path.clone(),
+            configuration: runner.current_configuration.clone(),
+            line_number: line_number as usize,
+            tags,
+            description,
+            order: runner.tests_defined,
+            abort_after_failures,
+            depends_on,
+            expect_fail,
+        });
+        runner.tests_defined += 1;
+    }
+    Ok(())
+}
+
+fn moonunit_from_file(
+    _lua: &mlua::Lua,
+    this: &RunContext,
+    path: String,
+) -> mlua::Result<FileMatcher> {
+    Ok(FileMatcher {
+        path: this.resolve_path(&path),
+    })
+}
+
+fn moonunit_setup(
+    lua: &mlua::Lua,
+    this: &RunContext,
+    (suite, function): (String, mlua::Function),
+) -> mlua::Result<()> {
+    this.register_suite_hook(lua, SuiteHookKind::Setup, suite, function)
+}
+
+fn moonunit_teardown(
+    lua: &mlua::Lua,
+    this: &RunContext,
+    (suite, function): (String, mlua::Function),
+) -> mlua::Result<()> {
+    this.register_suite_hook(lua, SuiteHookKind::Teardown, suite, function)
+}
+
+// Stop the current test, like a failed assertion, but so that it's reported
+// as aborted.  Expectations which already failed are still reported.
+fn moonunit_abort(
+    _lua: &mlua::Lua,
+    _this: &RunContext,
+    reason: Option<String>,
+) -> mlua::Result<()> {
+    Err(mlua::Error::external(TestAborted(
+        reason.unwrap_or_else(|| String::from("no reason given")),
+    )))
+}
+
+// Stop the current test so that it's reported as skipped, for tests which
+// find out only once they're running that they can't be run.  If any
+// expectations failed before the test was skipped, it still fails.
+fn moonunit_skip(
+    _lua: &mlua::Lua,
+    _this: &RunContext,
+    reason: Option<String>,
+) -> mlua::Result<()> {
+    Err(mlua::Error::external(TestSkipped(
+        reason.unwrap_or_else(|| String::from("no reason given")),
+    )))
+}
+
+// Add a message to the log of the current test, which is only shown if the
+// test fails.  Messages logged before an expectation fails are shown just
+// before that failure.
+fn moonunit_log(
+    _lua: &mlua::Lua,
+    this: &RunContext,
+    message: String,
+) -> mlua::Result<()> {
+    this.runner.inner.borrow_mut().current_test_log.push(message);
+    Ok(())
+}
+
+fn moonunit_before_all(
+    lua: &mlua::Lua,
+    this: &RunContext,
+    (suite, function): (String, mlua::Function),
+) -> mlua::Result<()> {
+    this.register_suite_hook(lua, SuiteHookKind::BeforeAll, suite, function)
+}
+
+fn moonunit_after_all(
+    lua: &mlua::Lua,
+    this: &RunContext,
+    (suite, function): (String, mlua::Function),
+) -> mlua::Result<()> {
+    this.register_suite_hook(lua, SuiteHookKind::AfterAll, suite, function)
+}
+
+// Prefix the message describing a failed assertion or expectation with the
+// message given by the test to explain it, if any.
+fn with_context(
+    message: String,
+    context: Option<String>,
+) -> String {
+    match context {
+        Some(context) => format!("{}: {}", context, message),
+        None => message,
+    }
+}
+
+// Make the error raised by a failed assertion, given the message given by
+// the test to explain a failure, if any.
+fn assertion_failure(
+    context: Option<String>
+) -> impl FnOnce(String) -> mlua::Error {
+    move |message| mlua::Error::RuntimeError(with_context(message, context))
+}
+
+// The comparison options given to `eq`, `ne`, and `eq_diff` are never a
+// string, so a string given in their place is instead taken as the message
+// explaining a failure, allowing the options to be left out.
+fn split_context<'lua>(
+    how: mlua::Value<'lua>,
+    context: Option<String>,
+) -> (mlua::Value<'lua>, Option<String>) {
+    match (how, context) {
+        (mlua::Value::String(how), None) => (
+            mlua::Value::Nil,
+            Some(String::from_utf8_lossy(how.as_bytes()).into_owned()),
+        ),
+        (how, context) => (how, context),
+    }
+}
+
+fn moonunit_assert_eq<'lua>(
+    lua: &'lua mlua::Lua,
+    _this: &RunContext,
+    (lhs, rhs, how, context): (
+        mlua::Value<'lua>,
+        mlua::Value<'lua>,
+        mlua::Value<'lua>,
+        Option<String>,
+    ),
+) -> mlua::Result<()> {
+    let (how, context) = split_context(how, context);
+    check_eq(lua, &lhs, &rhs, how)?.map_err(assertion_failure(context))
+}
+
+fn moonunit_assert_ne<'lua>(
+    lua: &'lua mlua::Lua,
+    _this: &RunContext,
+    (lhs, rhs, how, context): (
+        mlua::Value<'lua>,
+        mlua::Value<'lua>,
+        mlua::Value<'lua>,
+        Option<String>,
+    ),
+) -> mlua::Result<()> {
+    let (how, context) = split_context(how, context);
+    check_ne(lua, &lhs, &rhs, how)?.map_err(assertion_failure(context))
 }
 
 fn moonunit_assert_ge(
     _lua: &mlua::Lua,
     _this: &RunContext,
-    (lhs, rhs): (mlua::Value, mlua::Value),
+    (lhs, rhs, context): (mlua::Value, mlua::Value, Option<String>),
+) -> mlua::Result<()> {
+    let order = compare_for_ordering(&lhs, &rhs)?;
+    if matches!(
+        order,
+        Some(std::cmp::Ordering::Greater | std::cmp::Ordering::Equal)
+    ) {
+        Ok(())
+    } else {
+        Err(assertion_failure(context)(format!(
+            "Expected {} >= {}{}",
+            LuaValueForDisplay(&lhs),
+            LuaValueForDisplay(&rhs),
+            integer_boundary_note(&lhs, &rhs),
+        )))
+    }
+}
+
+fn moonunit_assert_gt(
+    _lua: &mlua::Lua,
+    _this: &RunContext,
+    (lhs, rhs, context): (mlua::Value, mlua::Value, Option<String>),
+) -> mlua::Result<()> {
+    let order = compare_for_ordering(&lhs, &rhs)?;
+    if matches!(order, Some(std::cmp::Ordering::Greater)) {
+        Ok(())
+    } else {
+        Err(assertion_failure(context)(format!(
+            "Expected {} > {}{}",
+            LuaValueForDisplay(&lhs),
+            LuaValueForDisplay(&rhs),
+            integer_boundary_note(&lhs, &rhs),
+        )))
+    }
+}
+
+fn moonunit_assert_le(
+    _lua: &mlua::Lua,
+    _this: &RunContext,
+    (lhs, rhs, context): (mlua::Value, mlua::Value, Option<String>),
+) -> mlua::Result<()> {
+    let order = compare_for_ordering(&lhs, &rhs)?;
+    if matches!(
+        order,
+        Some(std::cmp::Ordering::Less | std::cmp::Ordering::Equal)
+    ) {
+        Ok(())
+    } else {
+        Err(assertion_failure(context)(format!(
+            "Expected {} <= {}{}",
+            LuaValueForDisplay(&lhs),
+            LuaValueForDisplay(&rhs),
+            integer_boundary_note(&lhs, &rhs),
+        )))
+    }
+}
+
+fn moonunit_assert_lt(
+    _lua: &mlua::Lua,
+    _this: &RunContext,
+    (lhs, rhs, context): (mlua::Value, mlua::Value, Option<String>),
+) -> mlua::Result<()> {
+    let order = compare_for_ordering(&lhs, &rhs)?;
+    if matches!(order, Some(std::cmp::Ordering::Less)) {
+        Ok(())
+    } else {
+        Err(assertion_failure(context)(format!(
+            "Expected {} < {}{}",
+            LuaValueForDisplay(&lhs),
+            LuaValueForDisplay(&rhs),
+            integer_boundary_note(&lhs, &rhs),
+        )))
+    }
+}
+
+fn moonunit_assert_true(
+    _lua: &mlua::Lua,
+    _this: &RunContext,
+    (value, context): (mlua::Value, Option<String>),
+) -> mlua::Result<()> {
+    match &value {
+        mlua::Value::Boolean(false) | mlua::Value::Nil => {
+            Err(assertion_failure(context)(format!(
+                "Expected {} to be true",
+                LuaValueForDisplay(&value),
+            )))
+        },
+        _ => Ok(()),
+    }
+}
+
+fn moonunit_assert_false(
+    _lua: &mlua::Lua,
+    _this: &RunContext,
+    (value, context): (mlua::Value, Option<String>),
+) -> mlua::Result<()> {
+    match &value {
+        mlua::Value::Boolean(false) | mlua::Value::Nil => Ok(()),
+        _ => Err(assertion_failure(context)(format!(
+            "Expected {} to be false",
+            LuaValueForDisplay(&value),
+        ))),
+    }
+}
+
+fn moonunit_assert_nil(
+    _lua: &mlua::Lua,
+    _this: &RunContext,
+    (value, context): (mlua::Value, Option<String>),
+) -> mlua::Result<()> {
+    match &value {
+        mlua::Value::Nil => Ok(()),
+        _ => Err(assertion_failure(context)(format!(
+            "Expected nil, actual was {}",
+            LuaValueForDisplay(&value),
+        ))),
+    }
+}
+
+fn moonunit_assert_not_nil(
+    _lua: &mlua::Lua,
+    _this: &RunContext,
+    (value, context): (mlua::Value, Option<String>),
+) -> mlua::Result<()> {
+    match &value {
+        mlua::Value::Nil => Err(assertion_failure(context)(String::from(
+            "Expected a value other than nil, but it was unexpectedly nil",
+        ))),
+        _ => Ok(()),
+    }
+}
+
+fn moonunit_assert_type(
+    _lua: &mlua::Lua,
+    _this: &RunContext,
+    (value, expected, context): (mlua::Value, String, Option<String>),
+) -> mlua::Result<()> {
+    check_type(&expected, &value)?.map_err(assertion_failure(context))
+}
+
+fn moonunit_assert_match(
+    lua: &mlua::Lua,
+    _this: &RunContext,
+    (value, pattern, context): (mlua::Value, mlua::String, Option<String>),
+) -> mlua::Result<()> {
+    check_match(lua, &value, &pattern)?.map_err(assertion_failure(context))
+}
+
+fn moonunit_assert_contains(
+    _lua: &mlua::Lua,
+    _this: &RunContext,
+    (container, value, context): (mlua::Value, mlua::Value, Option<String>),
+) -> mlua::Result<()> {
+    check_contains(&container, &value)?.map_err(assertion_failure(context))
+}
+
+fn moonunit_assert_is_array(
+    _lua: &mlua::Lua,
+    _this: &RunContext,
+    (value, options, context): (
+        mlua::Value,
+        Option<mlua::Table>,
+        Option<String>,
+    ),
+) -> mlua::Result<()> {
+    check_is_array(&value, options)?.map_err(assertion_failure(context))
+}
+
+fn moonunit_assert_len(
+    _lua: &mlua::Lua,
+    _this: &RunContext,
+    (value, expected, context): (mlua::Value, mlua::Integer, Option<String>),
+) -> mlua::Result<()> {
+    check_len(&value, expected)?.map_err(assertion_failure(context))
+}
+
+fn moonunit_assert_near(
+    _lua: &mlua::Lua,
+    _this: &RunContext,
+    (lhs, rhs, tolerance, context): (
+        mlua::Value,
+        mlua::Value,
+        Option<mlua::Number>,
+        Option<String>,
+    ),
+) -> mlua::Result<()> {
+    check_near(&lhs, &rhs, tolerance)?.map_err(assertion_failure(context))
+}
+
+fn moonunit_assert_array_near(
+    _lua: &mlua::Lua,
+    _this: &RunContext,
+    (lhs, rhs, tolerance, context): (
+        mlua::Value,
+        mlua::Value,
+        mlua::Number,
+        Option<String>,
+    ),
+) -> mlua::Result<()> {
+    check_array_near(&lhs, &rhs, tolerance)?.map_err(assertion_failure(context))
+}
+
+fn moonunit_assert_json_equivalent(
+    lua: &mlua::Lua,
+    _this: &RunContext,
+    (lhs, rhs, context): (mlua::Value, mlua::Value, Option<String>),
+) -> mlua::Result<()> {
+    check_json_equivalent(lua, &lhs, &rhs)?.map_err(assertion_failure(context))
+}
+
+fn moonunit_assert_matches_golden(
+    _lua: &mlua::Lua,
+    this: &RunContext,
+    (value, golden_path, context): (mlua::Value, String, Option<String>),
+) -> mlua::Result<()> {
+    this.check_golden(&value, &golden_path)
+        .map_err(assertion_failure(context))
+}
+
+fn moonunit_assert_eq_diff<'lua>(
+    lua: &'lua mlua::Lua,
+    this: &RunContext,
+    (lhs, rhs, how, context): (
+        mlua::Value<'lua>,
+        mlua::Value<'lua>,
+        mlua::Value<'lua>,
+        Option<String>,
+    ),
+) -> mlua::Result<()> {
+    let (how, context) = split_context(how, context);
+    let limit = this.runner.inner.borrow().max_failures_per_test;
+    check_eq_diff(lua, &lhs, &rhs, how, limit)?
+        .map_err(assertion_failure(context))
+}
+
+fn moonunit_assert_approx_eq<'lua>(
+    lua: &'lua mlua::Lua,
+    _this: &RunContext,
+    (lhs, rhs, tolerance, context): (
+        mlua::Value<'lua>,
+        mlua::Value<'lua>,
+        Option<mlua::Number>,
+        Option<String>,
+    ),
+) -> mlua::Result<()> {
+    check_approx_eq(lua, &lhs, &rhs, tolerance)?
+        .map_err(assertion_failure(context))
+}
+
+fn moonunit_assert_subset<'lua>(
+    lua: &'lua mlua::Lua,
+    _this: &RunContext,
+    (actual, expected, context): (
+        mlua::Value<'lua>,
+        mlua::Value<'lua>,
+        Option<String>,
+    ),
+) -> mlua::Result<()> {
+    check_subset(lua, &actual, &expected)?.map_err(assertion_failure(context))
+}
+
+fn moonunit_assert_behaves_like<'lua>(
+    lua: &'lua mlua::Lua,
+    _this: &RunContext,
+    (first, second, inputs, context): (
+        mlua::Function<'lua>,
+        mlua::Function<'lua>,
+        mlua::Table<'lua>,
+        Option<String>,
+    ),
 ) -> mlua::Result<()> {
-    if OrderedLuaValue(lhs.clone()).cmp(&OrderedLuaValue(rhs.clone()))
-        == std::cmp::Ordering::Less
-    {
-        Err(mlua::Error::RuntimeError(format!(
-            "Expected {} >= {}",
-            LuaValueForDisplay(&lhs),
-            LuaValueForDisplay(&rhs),
-        )))
-    } else {
-        Ok(())
-    }
+    check_behaves_like(lua, &first, &second, inputs)?
+        .map_err(assertion_failure(context))
 }
 
-fn moonunit_assert_gt(
-    _lua: &mlua::Lua,
+fn moonunit_assert_raises_with_value<'lua>(
+    lua: &'lua mlua::Lua,
+    _this: &RunContext,
+    (function, args): (mlua::Function<'lua>, mlua::Variadic<mlua::Value<'lua>>),
+) -> mlua::Result<mlua::Value<'lua>> {
+    check_raises(lua, function, &args)?.map_err(mlua::Error::RuntimeError)
+}
+
+fn moonunit_assert_throws<'lua>(
+    lua: &'lua mlua::Lua,
     _this: &RunContext,
-    (lhs, rhs): (mlua::Value, mlua::Value),
+    (function, context): (mlua::Function<'lua>, Option<String>),
 ) -> mlua::Result<()> {
-    if OrderedLuaValue(lhs.clone()).cmp(&OrderedLuaValue(rhs.clone()))
-        == std::cmp::Ordering::Greater
-    {
-        Ok(())
-    } else {
-        Err(mlua::Error::RuntimeError(format!(
-            "Expected {} > {}",
-            LuaValueForDisplay(&lhs),
-            LuaValueForDisplay(&rhs),
-        )))
-    }
+    check_throws(lua, function, None)?.map_err(assertion_failure(context))
 }
 
-fn moonunit_assert_le(
-    _lua: &mlua::Lua,
+fn moonunit_assert_no_error<'lua>(
+    lua: &'lua mlua::Lua,
     _this: &RunContext,
-    (lhs, rhs): (mlua::Value, mlua::Value),
+    (function, context): (mlua::Function<'lua>, Option<String>),
 ) -> mlua::Result<()> {
-    if OrderedLuaValue(lhs.clone()).cmp(&OrderedLuaValue(rhs.clone()))
-        == std::cmp::Ordering::Greater
-    {
-        Err(mlua::Error::RuntimeError(format!(
-            "Expected {} <= {}",
-            LuaValueForDisplay(&lhs),
-            LuaValueForDisplay(&rhs),
-        )))
-    } else {
-        Ok(())
-    }
+    check_no_error(lua, function)?.map_err(assertion_failure(context))
 }
 
-fn moonunit_assert_lt(
-    _lua: &mlua::Lua,
+fn moonunit_assert_throws_match<'lua>(
+    lua: &'lua mlua::Lua,
     _this: &RunContext,
-    (lhs, rhs): (mlua::Value, mlua::Value),
+    (function, pattern, context): (
+        mlua::Function<'lua>,
+        mlua::String<'lua>,
+        Option<String>,
+    ),
 ) -> mlua::Result<()> {
-    if OrderedLuaValue(lhs.clone()).cmp(&OrderedLuaValue(rhs.clone()))
-        == std::cmp::Ordering::Less
-    {
-        Ok(())
-    } else {
-        Err(mlua::Error::RuntimeError(format!(
-            "Expected {} < {}",
-            LuaValueForDisplay(&lhs),
-            LuaValueForDisplay(&rhs),
-        )))
+    check_throws(lua, function, Some(&pattern))?
+        .map_err(assertion_failure(context))
+}
+
+fn moonunit_expect_eq<'lua>(
+    lua: &'lua mlua::Lua,
+    this: &RunContext,
+    (lhs, rhs, how, context): (
+        mlua::Value<'lua>,
+        mlua::Value<'lua>,
+        mlua::Value<'lua>,
+        Option<String>,
+    ),
+) -> mlua::Result<()> {
+    let (how, context) = split_context(how, context);
+    if let Err(message) = check_eq(lua, &lhs, &rhs, how)? {
+        this.fail_expectation(lua, message, context)?;
+    }
+    Ok(())
+}
+
+fn moonunit_expect_ne<'lua>(
+    lua: &'lua mlua::Lua,
+    this: &RunContext,
+    (lhs, rhs, how, context): (
+        mlua::Value<'lua>,
+        mlua::Value<'lua>,
+        mlua::Value<'lua>,
+        Option<String>,
+    ),
+) -> mlua::Result<()> {
+    let (how, context) = split_context(how, context);
+    if let Err(message) = check_ne(lua, &lhs, &rhs, how)? {
+        this.fail_expectation(lua, message, context)?;
     }
+    Ok(())
 }
 
-fn moonunit_assert_true(
-    _lua: &mlua::Lua,
-    _this: &RunContext,
-    (value,): (mlua::Value,),
+fn moonunit_expect_ge(
+    lua: &mlua::Lua,
+    this: &RunContext,
+    (lhs, rhs, context): (mlua::Value, mlua::Value, Option<String>),
+) -> mlua::Result<()> {
+    let order = compare_for_ordering(&lhs, &rhs)?;
+    if !matches!(
+        order,
+        Some(std::cmp::Ordering::Greater | std::cmp::Ordering::Equal)
+    ) {
+        this.fail_expectation(
+            lua,
+            format!(
+                "Expected {} >= {}{}",
+                LuaValueForDisplay(&lhs),
+                LuaValueForDisplay(&rhs),
+                integer_boundary_note(&lhs, &rhs),
+            ),
+            context,
+        )?;
+    }
+    Ok(())
+}
+
+fn moonunit_expect_gt(
+    lua: &mlua::Lua,
+    this: &RunContext,
+    (lhs, rhs, context): (mlua::Value, mlua::Value, Option<String>),
+) -> mlua::Result<()> {
+    let order = compare_for_ordering(&lhs, &rhs)?;
+    if !matches!(order, Some(std::cmp::Ordering::Greater)) {
+        this.fail_expectation(
+            lua,
+            format!(
+                "Expected {} > {}{}",
+                LuaValueForDisplay(&lhs),
+                LuaValueForDisplay(&rhs),
+                integer_boundary_note(&lhs, &rhs),
+            ),
+            context,
+        )?;
+    }
+    Ok(())
+}
+
+fn moonunit_expect_le(
+    lua: &mlua::Lua,
+    this: &RunContext,
+    (lhs, rhs, context): (mlua::Value, mlua::Value, Option<String>),
+) -> mlua::Result<()> {
+    let order = compare_for_ordering(&lhs, &rhs)?;
+    if !matches!(
+        order,
+        Some(std::cmp::Ordering::Less | std::cmp::Ordering::Equal)
+    ) {
+        this.fail_expectation(
+            lua,
+            format!(
+                "Expected {} <= {}{}",
+                LuaValueForDisplay(&lhs),
+                LuaValueForDisplay(&rhs),
+                integer_boundary_note(&lhs, &rhs),
+            ),
+            context,
+        )?;
+    }
+    Ok(())
+}
+
+fn moonunit_expect_lt(
+    lua: &mlua::Lua,
+    this: &RunContext,
+    (lhs, rhs, context): (mlua::Value, mlua::Value, Option<String>),
+) -> mlua::Result<()> {
+    let order = compare_for_ordering(&lhs, &rhs)?;
+    if !matches!(order, Some(std::cmp::Ordering::Less)) {
+        this.fail_expectation(
+            lua,
+            format!(
+                "Expected {} < {}{}",
+                LuaValueForDisplay(&lhs),
+                LuaValueForDisplay(&rhs),
+                integer_boundary_note(&lhs, &rhs),
+            ),
+            context,
+        )?;
+    }
+    Ok(())
+}
+
+fn moonunit_expect_true(
+    lua: &mlua::Lua,
+    this: &RunContext,
+    (value, context): (mlua::Value, Option<String>),
 ) -> mlua::Result<()> {
     match &value {
-        mlua::Value::Boolean(false) | mlua::Value::Nil => {
-            Err(mlua::Error::RuntimeError(format!(
-                "Expected {} to be true",
-                LuaValueForDisplay(&value),
-            )))
-        },
+        mlua::Value::Boolean(false) | mlua::Value::Nil => this
+            .fail_expectation(
+                lua,
+                format!("Expected {} to be true", LuaValueForDisplay(&value)),
+                context,
+            ),
         _ => Ok(()),
     }
 }
 
-fn moonunit_assert_false(
-    _lua: &mlua::Lua,
-    _this: &RunContext,
-    (value,): (mlua::Value,),
+fn moonunit_expect_false(
+    lua: &mlua::Lua,
+    this: &RunContext,
+    (value, context): (mlua::Value, Option<String>),
 ) -> mlua::Result<()> {
     match &value {
         mlua::Value::Boolean(false) | mlua::Value::Nil => Ok(()),
-        _ => Err(mlua::Error::RuntimeError(format!(
-            "Expected {} to be false",
-            LuaValueForDisplay(&value),
-        ))),
+        _ => this.fail_expectation(
+            lua,
+            format!("Expected {} to be false", LuaValueForDisplay(&value)),
+            context,
+        ),
     }
 }
 
-fn moonunit_expect_eq(
+fn moonunit_expect_nil(
     lua: &mlua::Lua,
     this: &RunContext,
-    (lhs, rhs): (mlua::Value, mlua::Value),
+    (value, context): (mlua::Value, Option<String>),
 ) -> mlua::Result<()> {
-    let mut expectation_failed = false;
-    if let (mlua::Value::Table(lhs), mlua::Value::Table(rhs)) = (&lhs, &rhs) {
-        let (message, key_chain) =
-            RunContext::compare_lua_tables(lhs, rhs, Vec::new());
-        if !message.is_empty() {
-            expectation_failed = true;
-            this.errors.borrow_mut().push(format!(
-                "Tables differ (path: {}) -- {}",
-                key_chain.into_iter().map(|value| render(&value)).fold(
-                    String::new(),
-                    |mut chain, key| {
-                        if !chain.is_empty() {
-                            chain.push('.');
-                        }
-                        chain += &key;
-                        chain
-                    }
-                ),
-                message
-            ))
-        }
-    } else if lhs != rhs {
-        expectation_failed = true;
-        this.errors.borrow_mut().push(format!(
-            "Expected {}, actual was {}",
-            LuaValueForDisplay(&lhs),
-            LuaValueForDisplay(&rhs),
-        ));
+    match &value {
+        mlua::Value::Nil => Ok(()),
+        _ => this.fail_expectation(
+            lua,
+            format!("Expected nil, actual was {}", LuaValueForDisplay(&value)),
+            context,
+        ),
+    }
+}
+
+fn moonunit_expect_not_nil(
+    lua: &mlua::Lua,
+    this: &RunContext,
+    (value, context): (mlua::Value, Option<String>),
+) -> mlua::Result<()> {
+    match &value {
+        mlua::Value::Nil => this.fail_expectation(
+            lua,
+            String::from(
+                "Expected a value other than nil, but it was unexpectedly nil",
+            ),
+            context,
+        ),
+        _ => Ok(()),
     }
-    if expectation_failed {
-        this.runner.inner.borrow_mut().current_test_failed = true;
-        let traceback: String = lua.load("debug.traceback(nil, 3)").eval()?;
-        this.errors.borrow_mut().push(traceback);
+}
+
+fn moonunit_expect_type(
+    lua: &mlua::Lua,
+    this: &RunContext,
+    (value, expected, context): (mlua::Value, String, Option<String>),
+) -> mlua::Result<()> {
+    if let Err(message) = check_type(&expected, &value)? {
+        this.fail_expectation(lua, message, context)?;
     }
     Ok(())
 }
 
-fn moonunit_expect_ne(
+fn moonunit_expect_match(
     lua: &mlua::Lua,
     this: &RunContext,
-    (lhs, rhs): (mlua::Value, mlua::Value),
+    (value, pattern, context): (mlua::Value, mlua::String, Option<String>),
 ) -> mlua::Result<()> {
-    let mut expectation_failed = false;
-    if let (mlua::Value::Table(lhs), mlua::Value::Table(rhs)) = (&lhs, &rhs) {
-        let (message, _key_chain) =
-            RunContext::compare_lua_tables(lhs, rhs, Vec::new());
-        if message.is_empty() {
-            expectation_failed = true;
-            this.errors
-                .borrow_mut()
-                .push(String::from("Tables should differ but are the same"))
-        }
-    } else if lhs == rhs {
-        expectation_failed = true;
-        this.errors.borrow_mut().push(format!(
-            "Expected not {}, actual was {}",
-            LuaValueForDisplay(&lhs),
-            LuaValueForDisplay(&rhs),
-        ));
+    if let Err(message) = check_match(lua, &value, &pattern)? {
+        this.fail_expectation(lua, message, context)?;
     }
-    if expectation_failed {
-        this.runner.inner.borrow_mut().current_test_failed = true;
-        let traceback: String = lua.load("debug.traceback(nil, 3)").eval()?;
-        this.errors.borrow_mut().push(traceback);
+    Ok(())
+}
+
+fn moonunit_expect_contains(
+    lua: &mlua::Lua,
+    this: &RunContext,
+    (container, value, context): (mlua::Value, mlua::Value, Option<String>),
+) -> mlua::Result<()> {
+    if let Err(message) = check_contains(&container, &value)? {
+        this.fail_expectation(lua, message, context)?;
     }
     Ok(())
 }
 
-fn moonunit_expect_ge(
+fn moonunit_expect_is_array(
     lua: &mlua::Lua,
     this: &RunContext,
-    (lhs, rhs): (mlua::Value, mlua::Value),
+    (value, options, context): (
+        mlua::Value,
+        Option<mlua::Table>,
+        Option<String>,
+    ),
 ) -> mlua::Result<()> {
-    if OrderedLuaValue(lhs.clone()).cmp(&OrderedLuaValue(rhs.clone()))
-        == std::cmp::Ordering::Less
-    {
-        this.errors.borrow_mut().push(format!(
-            "Expected {} >= {}",
-            LuaValueForDisplay(&lhs),
-            LuaValueForDisplay(&rhs),
-        ));
-        this.runner.inner.borrow_mut().current_test_failed = true;
-        let traceback: String = lua.load("debug.traceback(nil, 3)").eval()?;
-        this.errors.borrow_mut().push(traceback);
+    if let Err(message) = check_is_array(&value, options)? {
+        this.fail_expectation(lua, message, context)?;
     }
     Ok(())
 }
 
-fn moonunit_expect_gt(
+fn moonunit_expect_len(
     lua: &mlua::Lua,
     this: &RunContext,
-    (lhs, rhs): (mlua::Value, mlua::Value),
+    (value, expected, context): (mlua::Value, mlua::Integer, Option<String>),
 ) -> mlua::Result<()> {
-    if OrderedLuaValue(lhs.clone()).cmp(&OrderedLuaValue(rhs.clone()))
-        != std::cmp::Ordering::Greater
-    {
-        this.errors.borrow_mut().push(format!(
-            "Expected {} > {}",
-            LuaValueForDisplay(&lhs),
-            LuaValueForDisplay(&rhs),
-        ));
-        this.runner.inner.borrow_mut().current_test_failed = true;
-        let traceback: String = lua.load("debug.traceback(nil, 3)").eval()?;
-        this.errors.borrow_mut().push(traceback);
+    if let Err(message) = check_len(&value, expected)? {
+        this.fail_expectation(lua, message, context)?;
     }
     Ok(())
 }
 
-fn moonunit_expect_le(
+fn moonunit_expect_near(
     lua: &mlua::Lua,
     this: &RunContext,
-    (lhs, rhs): (mlua::Value, mlua::Value),
+    (lhs, rhs, tolerance, context): (
+        mlua::Value,
+        mlua::Value,
+        Option<mlua::Number>,
+        Option<String>,
+    ),
 ) -> mlua::Result<()> {
-    if OrderedLuaValue(lhs.clone()).cmp(&OrderedLuaValue(rhs.clone()))
-        == std::cmp::Ordering::Greater
-    {
-        this.errors.borrow_mut().push(format!(
-            "Expected {} <= {}",
-            LuaValueForDisplay(&lhs),
-            LuaValueForDisplay(&rhs),
-        ));
-        this.runner.inner.borrow_mut().current_test_failed = true;
-        let traceback: String = lua.load("debug.traceback(nil, 3)").eval()?;
-        this.errors.borrow_mut().push(traceback);
+    if let Err(message) = check_near(&lhs, &rhs, tolerance)? {
+        this.fail_expectation(lua, message, context)?;
     }
     Ok(())
 }
 
-fn moonunit_expect_lt(
+fn moonunit_expect_array_near(
     lua: &mlua::Lua,
     this: &RunContext,
-    (lhs, rhs): (mlua::Value, mlua::Value),
+    (lhs, rhs, tolerance, context): (
+        mlua::Value,
+        mlua::Value,
+        mlua::Number,
+        Option<String>,
+    ),
 ) -> mlua::Result<()> {
-    if OrderedLuaValue(lhs.clone()).cmp(&OrderedLuaValue(rhs.clone()))
-        != std::cmp::Ordering::Less
-    {
-        this.errors.borrow_mut().push(format!(
-            "Expected {} < {}",
-            LuaValueForDisplay(&lhs),
-            LuaValueForDisplay(&rhs),
-        ));
-        this.runner.inner.borrow_mut().current_test_failed = true;
-        let traceback: String = lua.load("debug.traceback(nil, 3)").eval()?;
-        this.errors.borrow_mut().push(traceback);
+    if let Err(message) = check_array_near(&lhs, &rhs, tolerance)? {
+        this.fail_expectation(lua, message, context)?;
     }
     Ok(())
 }
 
-fn moonunit_expect_true(
+fn moonunit_expect_json_equivalent(
     lua: &mlua::Lua,
     this: &RunContext,
-    (value,): (mlua::Value,),
+    (lhs, rhs, context): (mlua::Value, mlua::Value, Option<String>),
 ) -> mlua::Result<()> {
-    let mut expectation_failed = false;
-    match &value {
-        mlua::Value::Boolean(false) | mlua::Value::Nil => {
-            expectation_failed = true;
-            this.errors.borrow_mut().push(format!(
-                "Expected {} to be true",
-                LuaValueForDisplay(&value),
-            ));
-        },
-        _ => (),
-    };
-    if expectation_failed {
-        this.runner.inner.borrow_mut().current_test_failed = true;
-        let traceback: String = lua.load("debug.traceback(nil, 3)").eval()?;
-        this.errors.borrow_mut().push(traceback);
+    if let Err(message) = check_json_equivalent(lua, &lhs, &rhs)? {
+        this.fail_expectation(lua, message, context)?;
     }
     Ok(())
 }
 
-fn moonunit_expect_false(
+fn moonunit_expect_matches_golden(
     lua: &mlua::Lua,
     this: &RunContext,
-    (value,): (mlua::Value,),
+    (value, golden_path, context): (mlua::Value, String, Option<String>),
 ) -> mlua::Result<()> {
-    let mut expectation_failed = false;
-    match &value {
-        mlua::Value::Boolean(false) | mlua::Value::Nil => (),
-        _ => {
-            expectation_failed = true;
-            this.errors.borrow_mut().push(format!(
-                "Expected {} to be false",
-                LuaValueForDisplay(&value),
-            ));
+    if let Err(message) = this.check_golden(&value, &golden_path) {
+        this.fail_expectation(lua, message, context)?;
+    }
+    Ok(())
+}
+
+fn moonunit_expect_eq_diff<'lua>(
+    lua: &'lua mlua::Lua,
+    this: &RunContext,
+    (lhs, rhs, how, context): (
+        mlua::Value<'lua>,
+        mlua::Value<'lua>,
+        mlua::Value<'lua>,
+        Option<String>,
+    ),
+) -> mlua::Result<()> {
+    let (how, context) = split_context(how, context);
+    let limit = this.runner.inner.borrow().max_failures_per_test;
+    if let Err(message) = check_eq_diff(lua, &lhs, &rhs, how, limit)? {
+        this.fail_expectation(lua, message, context)?;
+    }
+    Ok(())
+}
+
+fn moonunit_expect_approx_eq<'lua>(
+    lua: &'lua mlua::Lua,
+    this: &RunContext,
+    (lhs, rhs, tolerance, context): (
+        mlua::Value<'lua>,
+        mlua::Value<'lua>,
+        Option<mlua::Number>,
+        Option<String>,
+    ),
+) -> mlua::Result<()> {
+    if let Err(message) = check_approx_eq(lua, &lhs, &rhs, tolerance)? {
+        this.fail_expectation(lua, message, context)?;
+    }
+    Ok(())
+}
+
+fn moonunit_expect_subset<'lua>(
+    lua: &'lua mlua::Lua,
+    this: &RunContext,
+    (actual, expected, context): (
+        mlua::Value<'lua>,
+        mlua::Value<'lua>,
+        Option<String>,
+    ),
+) -> mlua::Result<()> {
+    if let Err(message) = check_subset(lua, &actual, &expected)? {
+        this.fail_expectation(lua, message, context)?;
+    }
+    Ok(())
+}
+
+fn moonunit_expect_behaves_like<'lua>(
+    lua: &'lua mlua::Lua,
+    this: &RunContext,
+    (first, second, inputs, context): (
+        mlua::Function<'lua>,
+        mlua::Function<'lua>,
+        mlua::Table<'lua>,
+        Option<String>,
+    ),
+) -> mlua::Result<()> {
+    if let Err(message) = check_behaves_like(lua, &first, &second, inputs)? {
+        this.fail_expectation(lua, message, context)?;
+    }
+    Ok(())
+}
+
+// If the function doesn't raise an error, the expectation fails and nil is
+// returned in place of the value raised.
+fn moonunit_expect_raises_with_value<'lua>(
+    lua: &'lua mlua::Lua,
+    this: &RunContext,
+    (function, args): (mlua::Function<'lua>, mlua::Variadic<mlua::Value<'lua>>),
+) -> mlua::Result<mlua::Value<'lua>> {
+    match check_raises(lua, function, &args)? {
+        Ok(raised) => Ok(raised),
+        Err(message) => {
+            this.fail_expectation(lua, message, None)?;
+            Ok(mlua::Value::Nil)
         },
-    };
-    if expectation_failed {
-        this.runner.inner.borrow_mut().current_test_failed = true;
-        let traceback: String = lua.load("debug.traceback(nil, 3)").eval()?;
-        this.errors.borrow_mut().push(traceback);
     }
-    Ok(())
-}
+}
+
+fn moonunit_expect_throws<'lua>(
+    lua: &'lua mlua::Lua,
+    this: &RunContext,
+    (function, context): (mlua::Function<'lua>, Option<String>),
+) -> mlua::Result<()> {
+    if let Err(message) = check_throws(lua, function, None)? {
+        this.fail_expectation(lua, message, context)?;
+    }
+    Ok(())
+}
+
+fn moonunit_expect_no_error<'lua>(
+    lua: &'lua mlua::Lua,
+    this: &RunContext,
+    (function, context): (mlua::Function<'lua>, Option<String>),
+) -> mlua::Result<()> {
+    if let Err(message) = check_no_error(lua, function)? {
+        this.fail_expectation(lua, message, context)?;
+    }
+    Ok(())
+}
+
+fn moonunit_expect_throws_match<'lua>(
+    lua: &'lua mlua::Lua,
+    this: &RunContext,
+    (function, pattern, context): (
+        mlua::Function<'lua>,
+        mlua::String<'lua>,
+        Option<String>,
+    ),
+) -> mlua::Result<()> {
+    if let Err(message) = check_throws(lua, function, Some(&pattern))? {
+        this.fail_expectation(lua, message, context)?;
+    }
+    Ok(())
+}
+
+// This finds where an expectation which failed was checked, as "FILE:LINE",
+// along with a traceback starting there.  Rather than assuming how deep in
+// the stack that is, it looks for the MoonUnit method which was called (the
+// first C function up the stack from here), and then for the first Lua
+// function calling it which has line information, so that the location is
+// right even when the method is called indirectly.
+//
+// The script is given the `debug` library, since in safe mode it isn't
+// available to test scripts (see `SANDBOX_SCRIPT`).
+const FAILURE_LOCATION_SCRIPT: &str = r#"
+    local debug = ...
+    local level = 2
+    while true do
+        local info = debug.getinfo(level, "S")
+        if info == nil then
+            return nil, debug.traceback(nil, 3)
+        elseif info.what == "C" then
+            break
+        end
+        level = level + 1
+    end
+    while true do
+        level = level + 1
+        local info = debug.getinfo(level, "Sl")
+        if info == nil then
+            return nil, debug.traceback(nil, 3)
+        elseif info.currentline > 0 then
+            return info.short_src .. ":" .. info.currentline,
+                debug.traceback(nil, level)
+        end
+    end
+"#;
+
+impl RunContext {
+    // Determine the name of the test suite to use, given the name provided
+    // by the script, which may be empty to use the default suite name
+    // given by a "-- @suite" directive.
+    fn suite_name<F>(
+        &self,
+        suite: String,
+        what: F,
+    ) -> mlua::Result<String>
+    where
+        F: FnOnce() -> String,
+    {
+        if suite.is_empty() {
+            self.default_suite.clone().ok_or_else(|| {
+                mlua::Error::RuntimeError(format!(
+                    "No suite name given for {}, and the script has no \
+                    '-- @suite' directive on its first line",
+                    what()
+                ))
+            })
+        } else {
+            Ok(suite)
+        }
+    }
+
+    fn register_suite_hook(
+        &self,
+        lua: &mlua::Lua,
+        kind: SuiteHookKind,
+        suite: String,
+        function: mlua::Function,
+    ) -> mlua::Result<()> {
+        let suite = self.suite_name(suite, || String::from(kind.name()))?;
+
+        // Store the function in the registry table for this kind of hook.
+        let hooks = if let Some(hooks) = lua
+            .named_registry_value::<_, Option<mlua::Table>>(
+                kind.registry_name(),
+            )? {
+            hooks
+        } else {
+            let hooks = lua.create_table()?;
+            lua.set_named_registry_value(kind.registry_name(), hooks.clone())?;
+            hooks
+        };
+        hooks.set(suite.clone(), function)?;
+
+        // Remember which script registered the hook, if it's called once
+        // for the suite, since it's called in a Lua state of its own.
+        let mut runner = self.runner.inner.borrow_mut();
+        let suite_hooks = match kind {
+            SuiteHookKind::BeforeAll => &mut runner.before_all,
+            SuiteHookKind::AfterAll => &mut runner.after_all,
+            SuiteHookKind::Setup | SuiteHookKind::Teardown => return Ok(()),
+        };
+        suite_hooks.entry(suite).or_insert_with(|| SuiteHook {
+            file: self.file.clone(),
+            path: self.path.clone(),
+        });
+        Ok(())
+    }
+
+    // Resolve a path given by a test relative to the test file.
+    fn resolve_path(
+        &self,
+        path: &str,
+    ) -> std::path::PathBuf {
+        self.path
+            .parent()
+            .unwrap_or_else(|| std::path::Path::new(""))
+            .join(path.fix_silly_path_delimiter_nonsense().as_ref())
+    }
+
+    // Compare the serialized form of the given value with the contents of
+    // the given golden file, resolved relative to the test file.  If
+    // golden files are being updated, the golden file is overwritten with
+    // the serialized value instead.
+    fn check_golden(
+        &self,
+        value: &mlua::Value,
+        golden_path: &str,
+    ) -> Result<(), String> {
+        let golden_path = self.resolve_path(golden_path);
+        let actual = serialize(value) + "\n";
+        if self.runner.inner.borrow().update_golden {
+            return golden_path
+                .parent()
+                .map_or(Ok(()), std::fs::create_dir_all)
+                .and_then(|()| std::fs::write(&golden_path, actual))
+                .map_err(|error| {
+                    format!(
+                        "Unable to update golden file '{}': {}",
+                        golden_path.display(),
+                        error
+                    )
+                });
+        }
+        let expected = match std::fs::read_to_string(&golden_path) {
+            Ok(expected) => expected.replace("\r\n", "\n"),
+            Err(error) => {
+                return Err(format!(
+                    "Unable to read golden file '{}': {} \
+                    (run with --update-golden to create it)",
+                    golden_path.display(),
+                    error
+                ));
+            },
+        };
+        if expected == actual {
+            Ok(())
+        } else {
+            Err(format!(
+                "Value does not match golden file '{}' \
+                (run with --update-golden to update it):\n{}",
+                golden_path.display(),
+                diff_lines(&expected, &actual)
+            ))
+        }
+    }
 
-impl RunContext {
     fn compare_lua_tables<'lua>(
         lhs: &mlua::Table<'lua>,
         rhs: &mlua::Table<'lua>,
+        options: &ComparisonOptions,
         mut key_chain: Vec<mlua::Value<'lua>>,
+        compared: &mut TablePairs,
     ) -> (String, Vec<mlua::Value<'lua>>) {
-        let lhs_keys = lhs
-            .clone()
-            .pairs::<mlua::Value, mlua::Value>()
-            .map(|pair| OrderedLuaValue(pair.unwrap().0));
+        // Tables are compared by reference, so this is cheap, and it avoids
+        // walking the whole table when it's compared against itself.
+        if lhs == rhs || !compared.insert((lhs.to_pointer(), rhs.to_pointer()))
+        {
+            return (String::new(), key_chain);
+        }
+        let lhs_keys =
+            lhs.clone().pairs::<mlua::Value, mlua::Value>().map(|pair| {
+                let key = pair.unwrap().0;
+                (options.comparison_key(key.clone()), key)
+            });
         let mut rhs_keys = rhs
             .clone()
             .pairs::<mlua::Value, mlua::Value>()
-            .map(|pair| OrderedLuaValue(pair.unwrap().0))
-            .collect::<std::collections::BTreeSet<OrderedLuaValue>>();
-        for key in lhs_keys {
-            key_chain = match rhs_keys.get(&key) {
+            .map(|pair| {
+                let key = pair.unwrap().0;
+                (options.comparison_key(key.clone()), key)
+            })
+            .collect::<std::collections::BTreeMap<_, _>>();
+        for (comparison_key, key) in lhs_keys {
+            key_chain = match rhs_keys.get(&comparison_key) {
+                // A key with the null sentinel value stands for a key
+                // which is intentionally absent.
+                None if is_null(&lhs.get(key.clone()).unwrap()) => key_chain,
                 None => {
                     return (
                         format!(
                             "Actual value missing key {}",
-                            LuaValueForDisplay(&key.0)
+                            LuaValueForDisplay(&key)
                         ),
                         key_chain,
                     );
                 },
-                Some(_) => {
-                    let lhs = lhs.get(key.0.clone()).unwrap();
-                    let rhs = rhs.get(key.0.clone()).unwrap();
+                Some(rhs_key) => {
+                    let lhs = lhs.get(key.clone()).unwrap();
+                    let rhs = rhs.get(rhs_key.clone()).unwrap();
                     let (message, key_chain) = if let (
                         mlua::Value::Table(lhs),
                         mlua::Value::Table(rhs),
                     ) = (&lhs, &rhs)
                     {
-                        key_chain.push(key.0.clone());
+                        key_chain.push(key.clone());
                         let (message, mut key_chain) =
                             RunContext::compare_lua_tables(
-                                &lhs, &rhs, key_chain,
+                                &lhs, &rhs, options, key_chain, compared,
                             );
                         if message.is_empty() {
                             key_chain.pop();
                         }
                         (message, key_chain)
-                    } else if lhs == rhs {
+                    } else if options.values_equal(&lhs, &rhs) {
                         (String::from(""), key_chain)
                     } else {
-                        key_chain.push(key.0.clone());
+                        key_chain.push(key.clone());
                         (
                             format!(
-                                "Expected {}, actual was {}",
+                                "Expected {}, actual was {}{}",
                                 LuaValueForDisplay(&lhs),
                                 LuaValueForDisplay(&rhs),
+                                options.comparison_note(&lhs, &rhs),
                             ),
                             key_chain,
                         )
@@ -663,22 +3862,84 @@ impl RunContext {
                     if !message.is_empty() {
                         return (message, key_chain);
                     }
-                    rhs_keys.remove(&key);
+                    rhs_keys.remove(&comparison_key);
                     key_chain
                 },
             };
         }
-        if rhs_keys.is_empty() {
-            (String::from(""), key_chain)
-        } else {
+        if options.allow_extra_keys {
+            return (String::from(""), key_chain);
+        }
+        if let Some((_, rhs_key)) = rhs_keys
+            .into_iter()
+            .find(|(_, rhs_key)| !is_null(&rhs.get(rhs_key.clone()).unwrap()))
+        {
             (
                 format!(
                     "Actual value has extra key {}",
-                    LuaValueForDisplay(&rhs_keys.into_iter().next().unwrap().0)
+                    LuaValueForDisplay(&rhs_key)
                 ),
                 key_chain,
             )
+        } else {
+            (String::from(""), key_chain)
+        }
+    }
+
+    // Record the failure of an expectation, along with a traceback showing
+    // where it happened, and mark the current test as failed.  Once the
+    // maximum number of failures to report for a test is reached, further
+    // failures are only counted.
+    fn fail_expectation(
+        &self,
+        lua: &mlua::Lua,
+        message: String,
+        context: Option<String>,
+    ) -> mlua::Result<()> {
+        let (suppressed, abort_after_failures) = {
+            let mut runner = self.runner.inner.borrow_mut();
+            runner.current_test_failed = true;
+            runner.current_test_failures += 1;
+            let failures = runner.current_test_failures;
+            (
+                runner
+                    .max_failures_per_test
+                    .map_or(false, |max_failures| failures > max_failures),
+                runner
+                    .current_test_abort_after_failures
+                    .filter(|&abort_after_failures| {
+                        failures >= abort_after_failures
+                    }),
+            )
+        };
+        if !suppressed {
+            let debug: mlua::Table =
+                lua.named_registry_value(DEBUG_REGISTRY_NAME)?;
+            let (location, traceback): (Option<String>, String) =
+                lua.load(FAILURE_LOCATION_SCRIPT).call(debug)?;
+            let mut errors = self.errors.borrow_mut();
+            let logged = std::mem::take(
+                &mut self.runner.inner.borrow_mut().current_test_log,
+            );
+            for line in logged {
+                errors.push(format!("LOG: {}", line));
+            }
+            if let Some(location) = location {
+                errors.push(format!("{}: Failure", location));
+            }
+            errors.push(with_context(message, context));
+            errors.push(traceback);
+        }
+
+        // Once enough expectations have failed, stop the test, as if an
+        // assertion had failed.
+        if let Some(abort_after_failures) = abort_after_failures {
+            return Err(mlua::Error::RuntimeError(format!(
+                "Test stopped after {} expectation failures",
+                abort_after_failures
+            )));
         }
+        Ok(())
     }
 
     fn new(
@@ -689,6 +3950,7 @@ impl RunContext {
         tests_registry_key: &std::rc::Rc<mlua::RegistryKey>,
     ) -> Self {
         Self {
+            default_suite: parse_suite_directive(file),
             errors: errors.clone(),
             file: file.to_owned(),
             path: path.to_owned(),
@@ -698,12 +3960,392 @@ impl RunContext {
     }
 }
 
+// Derive a seed for `math.randomseed` from the full name of a test, using
+// the FNV-1a hash, so that each test gets its own seed, which is the same
+// every time the test is run.
+fn test_name_seed(
+    test_suite_name: &str,
+    test_name: &str,
+) -> u32 {
+    format!("{}.{}", test_suite_name, test_name).bytes().fold(
+        0x811C_9DC5,
+        |hash, byte| (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193),
+    )
+}
+
+// The number of Lua instructions executed between checks of whether a test
+// has run past its deadline.
+const TIMEOUT_CHECK_INTERVAL: u32 = 1000;
+
+// This is the name of the value in the Lua registry which holds the `debug`
+// library, which MoonUnit uses to find where expectations fail, even when
+// test scripts aren't allowed to use it.
+const DEBUG_REGISTRY_NAME: &str = "moonunit_debug";
+
+// This removes the parts of the standard library which reach outside of
+// Lua, or which could be used to break out of it, for running test scripts
+// in safe mode: the `debug` and `io` libraries, the functions of the `os`
+// library other than those dealing with time, loading C modules, and
+// loading precompiled chunks.  Each function removed is replaced by one
+// which raises an error explaining that it's disabled, rather than leaving
+// scripts which call it to fail with an error about calling a nil value.
+// It returns the `debug` library, which is kept aside for MoonUnit's own
+// use.
+const SANDBOX_SCRIPT: &str = r#"
+    local debug, error, load, loadfile = debug, error, load, loadfile
+    local function disable(library, names)
+        local stubs = {}
+        for _, name in ipairs(names) do
+            local message = library .. "." .. name
+                .. " is disabled in safe mode (run without --safe)"
+            stubs[name] = function()
+                error(message, 2)
+            end
+        end
+        return stubs
+    end
+    local debug_names = {}
+    for name in pairs(debug) do
+        debug_names[#debug_names + 1] = name
+    end
+    _G.debug = disable("debug", debug_names)
+    _G.io = disable("io", {
+        "close", "flush", "input", "lines", "open", "output", "popen",
+        "read", "tmpfile", "type", "write",
+    })
+    package.loaded.debug = _G.debug
+    package.loaded.io = _G.io
+    local os_names = {}
+    for name in pairs(os) do
+        if name ~= "clock" and name ~= "date" and name ~= "difftime"
+            and name ~= "time"
+        then
+            os_names[#os_names + 1] = name
+        end
+    end
+    for name, stub in pairs(disable("os", os_names)) do
+        os[name] = stub
+    end
+    package.loadlib = disable("package", {"loadlib"}).loadlib
+    package.cpath = ""
+    package.searchers[4] = nil
+    package.searchers[3] = nil
+    function _G.load(chunk, name, _, ...)
+        return load(chunk, name, "t", ...)
+    end
+    function _G.loadfile(filename, _, ...)
+        return loadfile(filename, "t", ...)
+    end
+    function _G.dofile(filename)
+        return assert(loadfile(filename, "t"))()
+    end
+    return debug
+"#;
+
+// Make a fresh Lua state in which to run a test script.  In safe mode,
+// only some parts of the standard library are loaded, and the state is
+// then sandboxed further by `SANDBOX_SCRIPT`.  The `io` library is left out
+// entirely.  The `os` library is loaded for its functions dealing with
+// time, and the rest of it is removed by the script.  The `debug` library
+// is loaded only so that MoonUnit can keep it aside for its own use, which
+// is why the state is made with `Lua::unsafe_new_with` rather than
+// `Lua::new_with`, which refuses to load it.
+fn new_lua_state(
+    safe: bool,
+    coverage: Option<std::rc::Rc<std::cell::RefCell<coverage::Coverage>>>,
+) -> mlua::Lua {
+    let lua = if safe {
+        let libraries = mlua::StdLib::COROUTINE
+            | mlua::StdLib::TABLE
+            | mlua::StdLib::STRING
+            | mlua::StdLib::UTF8
+            | mlua::StdLib::MATH
+            | mlua::StdLib::PACKAGE
+            | mlua::StdLib::OS
+            | mlua::StdLib::DEBUG;
+        unsafe { mlua::Lua::unsafe_new_with(libraries) }
+    } else {
+        unsafe { mlua::Lua::unsafe_new() }
+    };
+    {
+        let debug: mlua::Table = if safe {
+            lua.load(SANDBOX_SCRIPT).eval().unwrap()
+        } else {
+            lua.globals().get("debug").unwrap()
+        };
+        lua.set_named_registry_value(DEBUG_REGISTRY_NAME, debug).unwrap();
+    }
+    if coverage.is_some() {
+        set_lua_hook(&lua, coverage, None, None).unwrap();
+    }
+    lua
+}
+
+// Make a copy of the globals table of the given Lua state, along with its
+// metatable, to be used by `restore_globals` to undo any changes made to
+// the globals since.  Only the table itself is copied, not the values in
+// it, so changes made inside of tables held by globals aren't undone.
+fn snapshot_globals(lua: &mlua::Lua) -> mlua::Result<mlua::RegistryKey> {
+    let globals = lua.globals();
+    let copy = lua.create_table()?;
+    for pair in globals.clone().pairs::<mlua::Value, mlua::Value>() {
+        let (key, value) = pair?;
+        copy.raw_set(key, value)?;
+    }
+    let snapshot = lua.create_table()?;
+    snapshot.raw_set(1, copy)?;
+    snapshot.raw_set(2, globals.get_metatable())?;
+    lua.create_registry_value(snapshot)
+}
+
+// Put the globals of the given Lua state back the way they were when the
+// given snapshot was made by `snapshot_globals`.
+fn restore_globals(
+    lua: &mlua::Lua,
+    snapshot: &mlua::RegistryKey,
+) -> mlua::Result<()> {
+    let snapshot: mlua::Table = lua.registry_value(snapshot)?;
+    let copy: mlua::Table = snapshot.raw_get(1)?;
+    let metatable: Option<mlua::Table> = snapshot.raw_get(2)?;
+    let globals = lua.globals();
+    let added = globals
+        .clone()
+        .pairs::<mlua::Value, mlua::Value>()
+        .filter_map(|pair| match pair {
+            Ok((key, _)) => match copy.raw_get::<_, mlua::Value>(key.clone()) {
+                Ok(mlua::Value::Nil) => Some(Ok(key)),
+                Ok(_) => None,
+                Err(err) => Some(Err(err)),
+            },
+            Err(err) => Some(Err(err)),
+        })
+        .collect::<mlua::Result<Vec<_>>>()?;
+    for key in added {
+        globals.raw_set(key, mlua::Value::Nil)?;
+    }
+    for pair in copy.pairs::<mlua::Value, mlua::Value>() {
+        let (key, value) = pair?;
+        globals.raw_set(key, value)?;
+    }
+    globals.set_metatable(metatable);
+    Ok(())
+}
+
+// Make the folder of a test script the current working directory, so that
+// the script can find files relative to itself, and return the previous
+// working directory, to be restored by `leave_script_directory`.
+fn enter_script_directory(
+    path: &std::path::Path
+) -> Result<std::path::PathBuf, String> {
+    let original_working_directory = std::env::current_dir().map_err(|err| {
+        format!("Unable to determine the current working directory: {}", err)
+    })?;
+    let directory = path.parent().ok_or_else(|| {
+        format!("Unable to determine the folder of '{}'", path.display())
+    })?;
+    std::env::set_current_dir(directory).map_err(|err| {
+        format!(
+            "Unable to change the working directory to '{}': {}",
+            directory.display(),
+            err
+        )
+    })?;
+    Ok(original_working_directory)
+}
+
+// Restore the working directory which was current before a test script was
+// run, failing the given result if that isn't possible.
+fn leave_script_directory(
+    original_working_directory: &std::path::Path,
+    result: Result<(), String>,
+) -> Result<(), String> {
+    std::env::set_current_dir(original_working_directory).map_err(|err| {
+        format!(
+            "Unable to change the working directory back to '{}': {}",
+            original_working_directory.display(),
+            err
+        )
+    })?;
+    result
+}
+
+// Make a replacement for the Lua `print` function which, rather than writing
+// to standard output, adds the line it would have written to the output
+// captured for the currently running test.
+fn make_print<'lua>(
+    lua: &'lua mlua::Lua,
+    inner: &std::rc::Rc<std::cell::RefCell<RunnerInner>>,
+) -> mlua::Function<'lua> {
+    let inner = inner.clone();
+    lua.create_function(move |lua, args: mlua::MultiValue| {
+        let tostring: mlua::Function = lua.globals().get("tostring")?;
+        let line = args
+            .into_iter()
+            .map(|arg| {
+                tostring.call::<_, mlua::String>(arg).map(|arg| {
+                    String::from_utf8_lossy(arg.as_bytes()).into_owned()
+                })
+            })
+            .collect::<mlua::Result<Vec<_>>>()?
+            .join("\t");
+        inner.borrow_mut().current_test_output.push(line);
+        Ok(())
+    })
+    .unwrap()
+}
+
+// Install a hook in a Lua state which gathers line coverage, if given
+// coverage to add to, counts function calls, if given a counter to
+// increment, and stops the running code with a `TestTimedOut` error once
+// the given deadline (with the timeout it came from) has passed.  If none
+// of these are given, the hook is removed instead.  Lua only supports one
+// hook at a time, which is why all are done by the same hook.
+fn set_lua_hook(
+    lua: &mlua::Lua,
+    coverage: Option<std::rc::Rc<std::cell::RefCell<coverage::Coverage>>>,
+    calls: Option<std::rc::Rc<std::cell::Cell<usize>>>,
+    deadline: Option<(std::time::Instant, std::time::Duration)>,
+) -> mlua::Result<()> {
+    if coverage.is_none() && calls.is_none() && deadline.is_none() {
+        lua.remove_hook();
+        return Ok(());
+    }
+
+    // Finding the file for a chunk can involve looking up the current
+    // working directory, so it's only done once for each chunk source.
+    let files = std::cell::RefCell::new(std::collections::HashMap::new());
+    lua.set_hook(
+        mlua::HookTriggers {
+            on_calls: calls.is_some(),
+            every_line: coverage.is_some(),
+            every_nth_instruction: deadline.map(|_| TIMEOUT_CHECK_INTERVAL),
+            ..Default::default()
+        },
+        move |_, debug| {
+            if let mlua::DebugEvent::Count = debug.event() {
+                if let Some((deadline, timeout)) = deadline {
+                    if std::time::Instant::now() >= deadline {
+                        return Err(mlua::Error::external(TestTimedOut(
+                            timeout,
+                        )));
+                    }
+                }
+            } else if let mlua::DebugEvent::Line = debug.event() {
+                if let Some(coverage) = &coverage {
+                    let source = debug.source().source.unwrap_or_default();
+                    let mut files = files.borrow_mut();
+                    let file = files
+                        .entry(source.to_vec())
+                        .or_insert_with(|| coverage::source_file(source));
+                    if let (Some(file), Ok(line)) =
+                        (file, usize::try_from(debug.curr_line()))
+                    {
+                        coverage.borrow_mut().record(file, line);
+                    }
+                }
+            } else if let Some(calls) = &calls {
+                calls.set(calls.get() + 1);
+            }
+            Ok(())
+        },
+    )
+}
+
+// This is a test as it appears in a report of the test run.
+struct ReportedTest {
+    name: String,
+    path: std::path::PathBuf,
+    configuration: Option<std::path::PathBuf>,
+    line_number: usize,
+    result: Option<TestResult>,
+}
+
+// This is a test suite as it appears in a report of the test run.
+struct ReportedTestSuite {
+    name: String,
+    tests: Vec<ReportedTest>,
+}
+
+impl ReportedTestSuite {
+    fn failures(&self) -> usize {
+        self.tests
+            .iter()
+            .filter(|test| {
+                matches!(&test.result, Some(result) if !result.passed)
+            })
+            .count()
+    }
+
+    fn elapsed(&self) -> std::time::Duration {
+        self.tests
+            .iter()
+            .filter_map(|test| test.result.as_ref())
+            .map(|result| result.elapsed)
+            .sum()
+    }
+}
+
+// Escape text for use in an XML attribute value, including line breaks,
+// which would otherwise be normalized into spaces by XML parsers.
+fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\n' => escaped.push_str("&#x0A;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
 #[derive(Clone)]
 pub struct Runner {
     inner: std::rc::Rc<std::cell::RefCell<RunnerInner>>,
 }
 
 impl Runner {
+    // Forget all discovered tests and the state of the last test run, so
+    // that the runner can be configured and run again.  Settings such as
+    // the maximum failures per test, along with registered globals, are
+    // kept.  Clones of a runner share the same inner state, so this clears
+    // every clone of it as well.
+    pub fn clear(&mut self) {
+        let mut inner = self.inner.borrow_mut();
+        inner.current_test_aborted = false;
+        inner.current_test_assertions = 0;
+        inner.current_test_skipped = None;
+        inner.current_test_unexpectedly_passed = false;
+        inner.current_test_failed = false;
+        inner.current_test_failures = 0;
+        inner.current_test_log.clear();
+        inner.current_test_output.clear();
+        inner.after_all.clear();
+        inner.assertions_checked = 0;
+        inner.before_all.clear();
+        inner.loaded_scripts.clear();
+        inner.profile = Profile::default();
+        inner.results.clear();
+        inner.suite_setups.clear();
+        inner.test_suites.clear();
+        inner.tests_defined = 0;
+        inner.unavailable_sources.clear();
+    }
+
+    // Drop the Lua states kept for reuse, so that the next test of each
+    // test script is run in a fresh Lua state, in which the script is
+    // executed again.  Test scripts found to be missing are forgotten as
+    // well, so that they're reported again if they're still missing.
+    pub fn forget_lua_states(&mut self) {
+        let mut inner = self.inner.borrow_mut();
+        inner.loaded_scripts.clear();
+        inner.unavailable_sources.clear();
+    }
+
     pub fn configure<E, P>(
         &mut self,
         configuration_file_path: P,
@@ -722,25 +4364,84 @@ impl Runner {
         if configuration_file.read_to_string(&mut configuration).is_err() {
             return;
         }
+
+        // Tests discovered while reading this configuration file are
+        // attributed to it, and tests discovered while reading any
+        // configuration file it includes are attributed to that one.
+        let parent_configuration = self
+            .inner
+            .borrow_mut()
+            .current_configuration
+            .replace(configuration_file_path.to_path_buf());
+        self.configure_from_str(
+            &configuration,
+            configuration_file_path.parent().unwrap(),
+            error_delegate,
+        );
+        self.inner.borrow_mut().current_configuration = parent_configuration;
+    }
+
+    // Configure the runner using the given manifest content, as if it were
+    // read from a '.moonunit' file in the given base directory, against
+    // which relative paths in the manifest are resolved.
+    pub fn configure_from_str<E, P>(
+        &mut self,
+        configuration: &str,
+        base_path: P,
+        error_delegate: E,
+    ) where
+        E: FnMut(String) + Copy,
+        P: AsRef<std::path::Path>,
+    {
+        let base_path = base_path.as_ref();
+        let mut report_error = error_delegate;
         for line in configuration.lines() {
+            // Blank lines and comments are skipped.
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let line = match expand_environment_variables(line) {
+                Ok(line) => line,
+                Err(name) => {
+                    report_error(format!(
+                        "ERROR: Environment variable '{}' used in \
+                        configuration line '{}' is not set",
+                        name, line
+                    ));
+                    continue;
+                },
+            };
             let mut search_path = std::path::PathBuf::from(
-                line.trim().fix_silly_path_delimiter_nonsense().as_ref(),
+                line.as_str().fix_silly_path_delimiter_nonsense().as_ref(),
             );
             if !search_path.is_absolute() {
-                search_path =
-                    configuration_file_path.parent().unwrap().join(search_path);
+                search_path = base_path.join(search_path);
+            }
+            if glob::has_wildcards(&line) {
+                let paths = glob::find_files(&search_path)
+                    .into_iter()
+                    .filter(|path| {
+                        path.extension()
+                            .map_or(false, |extension| extension == "lua")
+                    })
+                    .collect::<Vec<_>>();
+                if paths.is_empty() {
+                    report_error(format!(
+                        "WARNING: No Lua test files match the pattern '{}'",
+                        search_path.display()
+                    ));
+                }
+                for path in paths {
+                    self.load_test_suite(path, error_delegate);
+                }
+                continue;
             }
             if !search_path.exists() {
-                println!("{} does not exist.", search_path.display());
-                println!(
-                    "{} {} a directory",
-                    search_path.display(),
-                    if search_path.is_dir() {
-                        "is"
-                    } else {
-                        "is not"
-                    }
-                );
+                report_error(format!(
+                    "WARNING: '{}' does not exist",
+                    search_path.display()
+                ));
                 continue;
             }
             if search_path.is_dir() {
@@ -770,37 +4471,121 @@ impl Runner {
         }
     }
 
+    pub fn get_profile(&self) -> Profile {
+        self.inner.borrow().profile
+    }
+
+    // Gather what goes into a report of the test run: every discovered
+    // test, sorted by test suite and then test name, along with its result
+    // if it was run.
+    fn collect_report(&self) -> Vec<ReportedTestSuite> {
+        let runner = self.inner.borrow();
+        let mut test_suites = runner
+            .test_suites
+            .iter()
+            .map(|(test_suite_name, test_suite)| {
+                let mut tests = test_suite
+                    .tests
+                    .iter()
+                    .map(|(test_name, test)| ReportedTest {
+                        name: test_name.clone(),
+                        path: test.path.clone(),
+                        configuration: test.configuration.clone(),
+                        line_number: test.line_number,
+                        result: runner
+                            .results
+                            .get(&(test_suite_name.clone(), test_name.clone()))
+                            .cloned(),
+                    })
+                    .collect::<Vec<_>>();
+                tests.sort_by(|lhs, rhs| lhs.name.cmp(&rhs.name));
+                ReportedTestSuite {
+                    name: test_suite_name.clone(),
+                    tests,
+                }
+            })
+            .collect::<Vec<_>>();
+        test_suites.sort_by(|lhs, rhs| lhs.name.cmp(&rhs.name));
+        test_suites
+    }
+
     pub fn get_report(&self) -> String {
-        let mut num_tests = 0;
-        for test_suite in self.inner.borrow().test_suites.values() {
-            num_tests += test_suite.tests.len();
-        }
+        let test_suites = self.collect_report();
+        let num_tests = test_suites
+            .iter()
+            .map(|test_suite| test_suite.tests.len())
+            .sum::<usize>();
+        let elapsed = test_suites
+            .iter()
+            .map(ReportedTestSuite::elapsed)
+            .sum::<std::time::Duration>();
         let mut buffer = String::new();
         writeln!(&mut buffer, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")
             .unwrap();
         writeln!(
             &mut buffer,
-            "<testsuites tests=\"{}\" name=\"AllTests\">",
-            num_tests
+            "<testsuites tests=\"{}\" time=\"{:.3}\" name=\"AllTests\">",
+            num_tests,
+            elapsed.as_secs_f64(),
         )
         .unwrap();
-        for (test_suite_name, test_suite) in &self.inner.borrow().test_suites {
+        for test_suite in &test_suites {
             writeln!(
                 &mut buffer,
-                "  <testsuite name=\"{}\" tests=\"{}\">",
-                test_suite_name,
-                test_suite.tests.len()
+                "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" \
+                time=\"{:.3}\">",
+                escape_xml(&test_suite.name),
+                test_suite.tests.len(),
+                test_suite.failures(),
+                test_suite.elapsed().as_secs_f64(),
             )
             .unwrap();
-            for (test_name, test) in &test_suite.tests {
-                writeln!(
+            for test in &test_suite.tests {
+                write!(
                     &mut buffer,
-                    "    <testcase name=\"{}\" file=\"{}\" line=\"{}\" />",
-                    test_name,
-                    test.path.display(),
+                    "    <testcase name=\"{}\" file=\"{}\" line=\"{}\" \
+                    time=\"{:.3}\" assertions=\"{}\"",
+                    escape_xml(&test.name),
+                    escape_xml(&test.path.display().to_string()),
                     test.line_number,
+                    test.result.as_ref().map_or(0.0, |result| {
+                        result.elapsed.as_secs_f64()
+                    }),
+                    test.result.as_ref().map_or(0, |result| result.assertions),
                 )
                 .unwrap();
+                if let Some(configuration) = &test.configuration {
+                    write!(
+                        &mut buffer,
+                        " configuration=\"{}\"",
+                        escape_xml(&configuration.display().to_string()),
+                    )
+                    .unwrap();
+                }
+                match &test.result {
+                    Some(result) if !result.passed => {
+                        writeln!(
+                            &mut buffer,
+                            ">\n      <failure message=\"{}\" />\n    \
+                            </testcase>",
+                            escape_xml(&result.messages.join("\n")),
+                        )
+                        .unwrap();
+                    },
+                    Some(TestResult {
+                        skipped: Some(reason),
+                        ..
+                    }) => {
+                        writeln!(
+                            &mut buffer,
+                            ">\n      <skipped message=\"{}\" />\n    \
+                            </testcase>",
+                            escape_xml(reason),
+                        )
+                        .unwrap();
+                    },
+                    _ => writeln!(&mut buffer, " />").unwrap(),
+                }
             }
             writeln!(&mut buffer, "</testsuite>").unwrap();
         }
@@ -808,6 +4593,154 @@ impl Runner {
         buffer
     }
 
+    // Render a report of the test run as a JSON document laid out like the
+    // one Google Test writes for "--gtest_output=json".  Tests which weren't
+    // run (for example, because they were filtered out) are included, with
+    // the status "NOTRUN".
+    pub fn get_json_report(&self) -> String {
+        let test_suites = self.collect_report();
+        let seconds = |elapsed: std::time::Duration| {
+            json::Value::String(format!("{:.3}s", elapsed.as_secs_f64()))
+        };
+        let count = |count: usize| {
+            json::Value::Integer(i64::try_from(count).unwrap_or(i64::MAX))
+        };
+        let text = |text: &str| json::Value::String(text.to_owned());
+        let test_suite_values = test_suites
+            .iter()
+            .map(|test_suite| {
+                let tests = test_suite
+                    .tests
+                    .iter()
+                    .map(|test| {
+                        let mut members = vec![
+                            (String::from("name"), text(&test.name)),
+                            (
+                                String::from("file"),
+                                text(&test.path.display().to_string()),
+                            ),
+                            (String::from("line"), count(test.line_number)),
+                            (
+                                String::from("status"),
+                                text(if test.result.is_some() {
+                                    "RUN"
+                                } else {
+                                    "NOTRUN"
+                                }),
+                            ),
+                            (
+                                String::from("result"),
+                                text(match &test.result {
+                                    Some(TestResult {
+                                        skipped: Some(_),
+                                        passed: true,
+                                        ..
+                                    }) => "SKIPPED",
+                                    Some(_) => "COMPLETED",
+                                    None => "SUPPRESSED",
+                                }),
+                            ),
+                            (
+                                String::from("time"),
+                                seconds(test.result.as_ref().map_or(
+                                    std::time::Duration::default(),
+                                    |result| result.elapsed,
+                                )),
+                            ),
+                            (String::from("classname"), text(&test_suite.name)),
+                        ];
+                        if let Some(result) =
+                            test.result.as_ref().filter(|result| !result.passed)
+                        {
+                            members.push((
+                                String::from("failures"),
+                                json::Value::Array(vec![json::Value::Object(
+                                    vec![
+                                        (
+                                            String::from("failure"),
+                                            text(&result.messages.join("\n")),
+                                        ),
+                                        (String::from("type"), text("")),
+                                    ],
+                                )]),
+                            ));
+                        }
+                        json::Value::Object(members)
+                    })
+                    .collect();
+                json::Value::Object(vec![
+                    (String::from("name"), text(&test_suite.name)),
+                    (String::from("tests"), count(test_suite.tests.len())),
+                    (String::from("failures"), count(test_suite.failures())),
+                    (String::from("disabled"), count(0)),
+                    (String::from("errors"), count(0)),
+                    (String::from("time"), seconds(test_suite.elapsed())),
+                    (String::from("testsuite"), json::Value::Array(tests)),
+                ])
+            })
+            .collect();
+        let report = json::Value::Object(vec![
+            (
+                String::from("tests"),
+                count(
+                    test_suites
+                        .iter()
+                        .map(|test_suite| test_suite.tests.len())
+                        .sum(),
+                ),
+            ),
+            (
+                String::from("failures"),
+                count(
+                    test_suites.iter().map(ReportedTestSuite::failures).sum(),
+                ),
+            ),
+            (String::from("disabled"), count(0)),
+            (String::from("errors"), count(0)),
+            (
+                String::from("time"),
+                seconds(
+                    test_suites.iter().map(ReportedTestSuite::elapsed).sum(),
+                ),
+            ),
+            (String::from("name"), text("AllTests")),
+            (String::from("testsuites"), json::Value::Array(test_suite_values)),
+        ]);
+        format!("{}\n", report.to_canonical())
+    }
+
+    // Render the line coverage gathered while running tests, in the LCOV
+    // tracefile format, if coverage is being gathered.
+    pub fn get_coverage_report(&self) -> Option<String> {
+        self.inner
+            .borrow()
+            .coverage
+            .as_ref()
+            .map(|coverage| coverage.borrow().to_lcov())
+    }
+
+    pub fn get_test_info<S>(
+        &self,
+        suite: S,
+        name: S,
+    ) -> Option<TestInfo>
+    where
+        S: AsRef<str>,
+    {
+        let runner = self.inner.borrow();
+        let test =
+            runner.test_suites.get(suite.as_ref())?.tests.get(name.as_ref())?;
+        Some(TestInfo {
+            path: test.path.clone(),
+            line_number: test.line_number,
+            tags: test.tags.clone(),
+            description: test.description.clone(),
+            order: test.order,
+            depends_on: test.depends_on.clone(),
+            expect_fail: test.expect_fail,
+        })
+    }
+
     pub fn get_test_names<S>(
         &self,
         suite: S,
@@ -815,7 +4748,8 @@ impl Runner {
     where
         S: AsRef<str>,
     {
-        self.inner        // Start with our shared inner state
+        let mut names = self
+            .inner        // Start with our shared inner state
             .borrow()     // It's in a RefCell, so borrow its contents
             .test_suites  // From there visit our test suites hash map
             .get(suite.as_ref())  // look up a specific test suite
@@ -823,8 +4757,20 @@ impl Runner {
             .tests        // From there visit its tests
             .keys()       // Iterate the test keys (the names of them)
             .cloned()     // Make copies of each name
-            .collect::<Vec<_>>()  // Push them all into a vector
-            .into_iter() // Turn this into an iterator
+            .collect::<Vec<_>>();  // Push them all into a vector
+        names.sort();     // Put them in alphabetical order
+        names.into_iter() // Turn this into an iterator
+    }
+
+    pub fn get_test_path<S>(
+        &self,
+        suite: S,
+        name: S,
+    ) -> Option<std::path::PathBuf>
+    where
+        S: AsRef<str>,
+    {
+        self.lookup_test(suite, name).ok().map(|(_file, path)| path)
     }
 
     pub fn get_test_suite_names(
@@ -834,13 +4780,17 @@ impl Runner {
         // a new vector/iterator since the test suites are inside a cell,
         // meaning you can't get to them without borrowing, which we're
         // not allowed to return (can't return something we borrow inside).
-        self.inner
+        // The names are sorted, since the test suites are kept in a hash map
+        // and would otherwise be listed in a different order every time.
+        let mut names = self
+            .inner
             .borrow()
             .test_suites
             .keys()       // Iterate the test suite keys (the names of them)
             .cloned()     // Make copies of each name
-            .collect::<Vec<_>>()  // Push them all into a vector
-            .into_iter() // Turn this into an iterator
+            .collect::<Vec<_>>();  // Push them all into a vector
+        names.sort();
+        names.into_iter() // Turn this into an iterator
     }
 
     pub fn load_test_suite<E, P>(
@@ -869,6 +4819,7 @@ impl Runner {
             ));
             return;
         }
+        self.inner.borrow_mut().current_test_output.clear();
         self.with_lua(|runner, lua| {
             match runner.with_script(
                 lua,
@@ -886,45 +4837,371 @@ impl Runner {
                     ));
                 },
             }
-        });
-    }
-
-    pub fn new() -> Self {
-        Self {
-            inner: std::rc::Rc::new(
-                std::cell::RefCell::new(RunnerInner::new()),
-            ),
+        });
+
+        // Anything the script printed while its tests were being discovered
+        // is passed along, since there's no test to attach it to.
+        let output =
+            std::mem::take(&mut self.inner.borrow_mut().current_test_output);
+        if !output.is_empty() {
+            error_delegate(format!(
+                "Captured output of Lua script file '{}':",
+                file_path.display()
+            ));
+            for line in output {
+                error_delegate(line);
+            }
+        }
+    }
+
+    pub fn new() -> Self {
+        Self {
+            inner: std::rc::Rc::new(
+                std::cell::RefCell::new(RunnerInner::new()),
+            ),
+        }
+    }
+
+    fn lookup_test<S>(
+        &self,
+        suite: S,
+        name: S,
+    ) -> Result<(String, std::path::PathBuf), String>
+    where
+        S: AsRef<str>,
+    {
+        let runner = self.inner.borrow();
+        let suite = suite.as_ref();
+        let name = name.as_ref();
+        let test_suite = if let Some(test_suite) = runner.test_suites.get(suite)
+        {
+            test_suite
+        } else {
+            return Err(format!("ERROR: No test suite '{}' found", suite));
+        };
+        let test = if let Some(test) = test_suite.tests.get(name) {
+            test
+        } else {
+            return Err(format!(
+                "ERROR: No test '{}' found in test suite '{}'",
+                name, suite,
+            ));
+        };
+        let file = test.file.clone();
+        let path = test.path.clone();
+        Ok((file, path))
+    }
+
+    // Register a global to be made visible to test scripts, in addition to
+    // the `moonunit` object.  The global's value is made by calling the
+    // given function with the Lua state of the test script, each time the
+    // script is loaded into a fresh Lua state, and before each test run in
+    // a reused one, for example:
+    //
+    //     runner.register_global("add", |lua| {
+    //         lua.create_function(|_, (a, b): (i64, i64)| Ok(a + b))
+    //             .map(mlua::Value::Function)
+    //     });
+    //
+    // Registering a global with the same name again replaces it.
+    pub fn register_global<S, F>(
+        &mut self,
+        name: S,
+        make_value: F,
+    ) where
+        S: Into<String>,
+        F: for<'lua> Fn(&'lua mlua::Lua) -> mlua::Result<mlua::Value<'lua>>
+            + 'static,
+    {
+        let name = name.into();
+        let mut runner = self.inner.borrow_mut();
+        runner.globals.retain(|(existing_name, _)| *existing_name != name);
+        runner.globals.push((name, std::rc::Rc::new(make_value)));
+    }
+
+    // Call the `after_all` function registered for the given test suite, if
+    // any, with the value its `before_all` function returned.  This isn't
+    // done if the `before_all` function failed.
+    pub fn run_after_all<S>(
+        &mut self,
+        test_suite_name: S,
+    ) -> Option<TestResult>
+    where
+        S: AsRef<str>,
+    {
+        let suite_setup = self
+            .inner
+            .borrow_mut()
+            .suite_setups
+            .remove(test_suite_name.as_ref());
+        let fixture = match suite_setup {
+            Some(SuiteSetup::Failed) => return None,
+            Some(SuiteSetup::Ready(fixture)) => fixture,
+            None => FixtureValue::Nil,
+        };
+        self.run_suite_hook(
+            SuiteHookKind::AfterAll,
+            test_suite_name.as_ref(),
+            |lua, function| {
+                function.call::<_, ()>(fixture.to_lua(lua)?)?;
+                Ok(())
+            },
+        )
+        .map(|(result, _)| result)
+    }
+
+    // Call the `before_all` function registered for the given test suite,
+    // if any, keeping the value it returns to give to each test of the
+    // suite.  If it fails, the tests of the suite aren't run.
+    pub fn run_before_all<S>(
+        &mut self,
+        test_suite_name: S,
+    ) -> Option<TestResult>
+    where
+        S: AsRef<str>,
+    {
+        let (result, fixture) = self.run_suite_hook(
+            SuiteHookKind::BeforeAll,
+            test_suite_name.as_ref(),
+            |_, function| {
+                let value = function.call::<_, mlua::Value>(())?;
+                FixtureValue::from_lua(&value, &mut Vec::new())
+                    .map_err(mlua::Error::RuntimeError)
+            },
+        )?;
+        self.inner.borrow_mut().suite_setups.insert(
+            test_suite_name.as_ref().to_string(),
+            fixture.map_or(SuiteSetup::Failed, SuiteSetup::Ready),
+        );
+        Some(result)
+    }
+
+    // Find the discovered tests whose full names (the name of the test
+    // suite, followed by '.' and the name of the test) match the given
+    // filter, which has the same form as the value of `--gtest_filter`, or
+    // all of them if no filter is given, grouped by test suite, with test
+    // suites and the tests of each in alphabetical order, ready to be run by
+    // `run_all`.  Disabled tests are left out, unless `include_disabled` is
+    // set.
+    pub fn select_tests(
+        &self,
+        filter: Option<&str>,
+        include_disabled: bool,
+    ) -> Vec<(String, Vec<String>)> {
+        self.get_test_suite_names()
+            .filter_map(|test_suite_name| {
+                let test_names = self
+                    .get_test_names(&test_suite_name)
+                    .filter(|test_name| {
+                        (include_disabled
+                            || (!test_suite_name.starts_with("DISABLED_")
+                                && !test_name.starts_with("DISABLED_")))
+                            && filter.map_or(true, |filter| {
+                                glob::matches_filter(
+                                    filter,
+                                    &format!(
+                                        "{}.{}",
+                                        test_suite_name, test_name
+                                    ),
+                                )
+                            })
+                    })
+                    .collect::<Vec<_>>();
+                if test_names.is_empty() {
+                    None
+                } else {
+                    Some((test_suite_name, test_names))
+                }
+            })
+            .collect()
+    }
+
+    // Run the given tests (such as those found by `select_tests`), grouped
+    // by test suite, in the order given.  The `before_all` and `after_all`
+    // functions of each test suite are called before and after its tests,
+    // and a test is skipped if a test on which it depends was run before it
+    // and didn't pass.  The observer is told about each step as it happens,
+    // and returns whether to keep going.  Once it returns false, no more
+    // tests are run, although the `after_all` function of the test suite
+    // being run is still called.  Nothing is written to the console;
+    // everything is in the summary returned (and whatever the observer
+    // does with the steps it's told about), so that other code can drive
+    // MoonUnit and decide what to do with the results.
+    pub fn run_all<F>(
+        &mut self,
+        ordered_tests: &[(String, Vec<String>)],
+        mut observer: F,
+    ) -> RunSummary
+    where
+        F: FnMut(RunEvent) -> bool,
+    {
+        let start_time = std::time::Instant::now();
+        let mut summary = RunSummary::default();
+        let mut results = std::collections::HashMap::new();
+        let mut stopped = false;
+        for (test_suite_name, test_names) in ordered_tests {
+            if stopped {
+                break;
+            }
+            let test_suite_start_time = std::time::Instant::now();
+            stopped |= !observer(RunEvent::SuiteStart {
+                suite: test_suite_name,
+                tests: test_names.len(),
+            });
+            if let Some(result) = self.run_before_all(test_suite_name) {
+                let outcome = TestOutcome {
+                    suite: test_suite_name.clone(),
+                    name: String::from("before_all"),
+                    result,
+                };
+                stopped |= !observer(RunEvent::SuiteHook(&outcome));
+                summary.suite_hooks.push(outcome);
+            }
+            for test_name in test_names {
+                if stopped {
+                    break;
+                }
+                let test_id = (test_suite_name.clone(), test_name.clone());
+                if let Some((dependency_suite_name, dependency_name)) =
+                    self.failed_dependency(&results, test_suite_name, test_name)
+                {
+                    let reason = format!(
+                        "dependency {}.{} did not pass",
+                        dependency_suite_name, dependency_name,
+                    );
+                    stopped |= !observer(RunEvent::TestSkip {
+                        suite: test_suite_name,
+                        name: test_name,
+                        reason: &reason,
+                    });
+                    results.insert(test_id, false);
+                    summary.tests.push(TestOutcome {
+                        suite: test_suite_name.clone(),
+                        name: test_name.clone(),
+                        result: TestResult {
+                            passed: true,
+                            aborted: false,
+                            skipped: Some(reason),
+                            elapsed: std::time::Duration::default(),
+                            messages: Vec::new(),
+                            assertions: 0,
+                            unexpectedly_passed: false,
+                        },
+                    });
+                    continue;
+                }
+                stopped |= !observer(RunEvent::TestStart {
+                    suite: test_suite_name,
+                    name: test_name,
+                });
+                let result = self
+                    .run_named(test_suite_name.as_str(), test_name.as_str());
+                results
+                    .insert(test_id, result.passed && result.skipped.is_none());
+                let outcome = TestOutcome {
+                    suite: test_suite_name.clone(),
+                    name: test_name.clone(),
+                    result,
+                };
+                stopped |= !observer(RunEvent::TestEnd(&outcome));
+                summary.tests.push(outcome);
+            }
+            if let Some(result) = self.run_after_all(test_suite_name) {
+                let outcome = TestOutcome {
+                    suite: test_suite_name.clone(),
+                    name: String::from("after_all"),
+                    result,
+                };
+                stopped |= !observer(RunEvent::SuiteHook(&outcome));
+                summary.suite_hooks.push(outcome);
+            }
+            stopped |= !observer(RunEvent::SuiteEnd {
+                suite: test_suite_name,
+                tests: test_names.len(),
+                elapsed: test_suite_start_time.elapsed(),
+            });
         }
+        summary.elapsed = start_time.elapsed();
+        summary
     }
 
-    fn lookup_test<S>(
+    // Find the first test on which the given test depends which was run (or
+    // skipped) and didn't pass, if any.
+    fn failed_dependency(
         &self,
-        suite: S,
-        name: S,
-    ) -> Result<(String, std::path::PathBuf), String>
+        results: &std::collections::HashMap<(String, String), bool>,
+        test_suite_name: &str,
+        test_name: &str,
+    ) -> Option<(String, String)> {
+        self.inner
+            .borrow()
+            .test_suites
+            .get(test_suite_name)?
+            .tests
+            .get(test_name)?
+            .depends_on
+            .iter()
+            .find(|dependency| results.get(*dependency) == Some(&false))
+            .cloned()
+    }
+
+    pub fn run_named<S>(
+        &mut self,
+        test_suite_name: S,
+        test_name: S,
+    ) -> TestResult
     where
         S: AsRef<str>,
     {
-        let runner = self.inner.borrow();
-        let suite = suite.as_ref();
-        let name = name.as_ref();
-        let test_suite = if let Some(test_suite) = runner.test_suites.get(suite)
-        {
-            test_suite
-        } else {
-            return Err(format!("ERROR: No test suite '{}' found", suite));
-        };
-        let test = if let Some(test) = test_suite.tests.get(name) {
-            test
-        } else {
-            return Err(format!(
-                "ERROR: No test '{}' found in test suite '{}'",
-                name, suite,
-            ));
+        let messages = std::cell::RefCell::new(Vec::new());
+        let start_time = std::time::Instant::now();
+        let passed = self.run_test(test_suite_name, test_name, |message| {
+            messages.borrow_mut().push(message)
+        });
+        let result = TestResult {
+            passed,
+            aborted: self.inner.borrow().current_test_aborted,
+            skipped: self.inner.borrow().current_test_skipped.clone(),
+            elapsed: start_time.elapsed(),
+            messages: messages.into_inner(),
+            assertions: self.inner.borrow().current_test_assertions,
+            unexpectedly_passed: self
+                .inner
+                .borrow()
+                .current_test_unexpectedly_passed,
         };
-        let file = test.file.clone();
-        let path = test.path.clone();
-        Ok((file, path))
+
+        // Keep the result for the report, which is generated after all the
+        // tests have run.
+        self.record_result(test_suite_name, test_name, result.clone());
+        result
+    }
+
+    // Keep the result of a test for the report, including tests run
+    // elsewhere, such as by a worker process.
+    pub fn record_result<S>(
+        &mut self,
+        test_suite_name: S,
+        test_name: S,
+        result: TestResult,
+    ) where
+        S: AsRef<str>,
+    {
+        let mut inner = self.inner.borrow_mut();
+        inner.assertions_checked += result.assertions;
+        inner.results.insert(
+            (
+                test_suite_name.as_ref().to_string(),
+                test_name.as_ref().to_string(),
+            ),
+            result,
+        );
+    }
+
+    // Get the total number of assertions and expectations checked by all
+    // the tests whose results were recorded.
+    pub fn get_assertions_checked(&self) -> usize {
+        self.inner.borrow().assertions_checked
     }
 
     pub fn run_test<S, E>(
@@ -945,47 +5222,460 @@ impl Runner {
                 return false;
             },
         };
-        self.inner.borrow_mut().current_test_failed = false;
-        self.with_lua(|runner, lua| {
-            match runner.with_script(
+        let (configuration, expect_fail) = self
+            .inner
+            .borrow()
+            .test_suites
+            .get(test_suite_name.as_ref())
+            .and_then(|suite| suite.tests.get(test_name.as_ref()))
+            .map_or((None, false), |test| {
+                (test.configuration.clone(), test.expect_fail)
+            });
+        if !path.is_file() {
+            // The test suite's source file was removed or replaced since the
+            // tests were discovered.  Fail all its tests, but only explain
+            // why once, rather than repeating it for every test.
+            if self.inner.borrow_mut().unavailable_sources.insert(path.clone())
+            {
+                error_delegate(format!(
+                    "ERROR: Suite source '{}' is no longer available",
+                    path.display()
+                ));
+            }
+            return false;
+        }
+        if let Some(SuiteSetup::Failed) =
+            self.inner.borrow().suite_setups.get(test_suite_name.as_ref())
+        {
+            error_delegate(format!(
+                "ERROR: Test not run, since before_all failed for \
+                test suite '{}'",
+                test_suite_name.as_ref()
+            ));
+            return false;
+        }
+        {
+            let mut runner = self.inner.borrow_mut();
+            runner.current_test_abort_after_failures = runner
+                .test_suites
+                .get(test_suite_name.as_ref())
+                .and_then(|suite| suite.tests.get(test_name.as_ref()))
+                .and_then(|test| test.abort_after_failures)
+                .or(runner.abort_after_failures);
+            runner.current_test_aborted = false;
+            runner.current_test_assertions = 0;
+            runner.current_test_skipped = None;
+            runner.current_test_unexpectedly_passed = false;
+            runner.current_test_failed = false;
+            runner.current_test_failures = 0;
+            runner.current_test_log.clear();
+            runner.current_test_output.clear();
+        }
+        let run = move |runner: &mut Self,
+                        lua: &mut mlua::Lua,
+                        tests_registry_key: std::rc::Rc<mlua::RegistryKey>|
+         -> mlua::Result<()> {
+            let tests_table: mlua::Table =
+                lua.registry_value(&tests_registry_key)?;
+            let tests: mlua::Table =
+                tests_table.get(test_suite_name.as_ref())?;
+            let test: mlua::Function = tests.get(test_name.as_ref())?;
+            let fixture_seed = runner.inner.borrow().fixture_seed;
+            if let Some(fixture_seed) = fixture_seed {
+                let seed = match fixture_seed {
+                    FixtureSeed::Fixed(seed) => seed,
+                    FixtureSeed::TestName => test_name_seed(
+                        test_suite_name.as_ref(),
+                        test_name.as_ref(),
+                    ),
+                };
+                let math: mlua::Table = lua.globals().get("math")?;
+                let randomseed: mlua::Function = math.get("randomseed")?;
+                randomseed.call::<_, ()>(seed)?;
+            }
+            let fixture = match runner
+                .inner
+                .borrow()
+                .suite_setups
+                .get(test_suite_name.as_ref())
+            {
+                Some(SuiteSetup::Ready(fixture)) => fixture.to_lua(lua)?,
+                _ => mlua::Value::Nil,
+            };
+
+            // The test isn't run if the setup function of its suite
+            // fails, but the teardown function is still called.
+            if let Err(error) = call_test_hook(
+                lua,
+                SuiteHookKind::Setup,
+                test_suite_name.as_ref(),
+                fixture.clone(),
+            ) {
+                error_delegate(format!("ERROR: setup failed: {}", error));
+                runner.inner.borrow_mut().current_test_failed = true;
+                runner.call_teardown(
+                    lua,
+                    test_suite_name.as_ref(),
+                    fixture,
+                    error_delegate,
+                );
+                return Ok(());
+            }
+
+            // In strict mode, count the function calls made while
+            // the test runs (including the calls to `xpcall` and to
+            // the test itself, by `call_test`) in order to detect
+            // tests which don't call anything.
+            // If there's a timeout, the test is stopped once it
+            // runs past it, so that an infinite loop can't hang
+            // the runner.
+            let strict = runner.inner.borrow().strict;
+            let timeout = runner.inner.borrow().timeout;
+            let coverage = runner.inner.borrow().coverage.clone();
+            let calls = std::rc::Rc::new(std::cell::Cell::new(0));
+            let start_time = std::time::Instant::now();
+            if strict || timeout.is_some() {
+                set_lua_hook(
+                    lua,
+                    coverage.clone(),
+                    if strict {
+                        Some(calls.clone())
+                    } else {
+                        None
+                    },
+                    timeout.map(|timeout| (start_time + timeout, timeout)),
+                )?;
+            }
+            let result = call_test(lua, test, fixture.clone());
+            runner.inner.borrow_mut().profile.test_bodies +=
+                start_time.elapsed();
+            if strict || timeout.is_some() {
+                set_lua_hook(lua, coverage, None, None)?;
+            }
+            if strict && result.is_ok() && calls.get() <= 2 {
+                error_delegate(String::from(
+                    "WARNING: Test made no function calls; it may \
+                    be missing a call to the code under test",
+                ));
+            }
+            if let Some(skipped) =
+                result.as_ref().err().and_then(stop_cause::<TestSkipped>)
+            {
+                error_delegate(format!("SKIPPED: {}", skipped.0));
+                runner.inner.borrow_mut().current_test_skipped =
+                    Some(skipped.0.clone());
+            } else if let Err(error) = result {
+                // Anything the test logged which wasn't already shown along
+                // with an expectation failure is shown before the error
+                // which stopped the test, since it was logged before then.
+                let logged = std::mem::take(
+                    &mut runner.inner.borrow_mut().current_test_log,
+                );
+                for line in logged {
+                    error_delegate(format!("LOG: {}", line));
+                }
+                if let Some(reason) = abort_reason(&error) {
+                    error_delegate(format!("ABORTED: {}", reason));
+                    runner.inner.borrow_mut().current_test_aborted = true;
+                } else if let Some(timed_out) =
+                    stop_cause::<TestTimedOut>(&error)
+                {
+                    error_delegate(format!("ERROR: {}", timed_out));
+                } else if let mlua::Error::CallbackError {
+                    traceback,
+                    cause,
+                } = error
+                {
+                    error_delegate(format!("ERROR: {}", cause));
+                    error_delegate(traceback);
+                } else {
+                    error_delegate(format!("ERROR: {}", error));
+                }
+                runner.inner.borrow_mut().current_test_failed = true;
+            }
+
+            // The teardown function is called after the test's own
+            // failures are reported, so that they aren't masked by
+            // any failure of the teardown function.
+            runner.call_teardown(
                 lua,
+                test_suite_name.as_ref(),
+                fixture,
                 error_delegate,
-                &file,
-                &path,
-                |runner, lua, tests_registry_key| {
-                    let tests_table: mlua::Table =
-                        lua.registry_value(&tests_registry_key)?;
-                    let tests: mlua::Table =
-                        tests_table.get(test_suite_name.as_ref())?;
-                    let test: mlua::Function = tests.get(test_name.as_ref())?;
-                    if let Err(error) = test.call::<_, ()>(()) {
-                        if let mlua::Error::CallbackError {
-                            traceback,
-                            cause,
-                        } = error
-                        {
-                            error_delegate(format!("ERROR: {}", cause));
-                            error_delegate(traceback);
-                        } else {
-                            error_delegate(format!("ERROR: {}", error));
-                        }
-                        runner.inner.borrow_mut().current_test_failed = true;
+            );
+            Ok(())
+        };
+        let reuse_lua_states = self.inner.borrow().reuse_lua_states;
+        let result = if reuse_lua_states {
+            self.with_loaded_script(error_delegate, &file, &path, run)
+        } else {
+            let mut result = Ok(());
+            self.with_lua(|runner, lua| {
+                result =
+                    runner.with_script(lua, error_delegate, &file, &path, run);
+            });
+            result
+        };
+        if let Err(message) = result {
+            self.inner.borrow_mut().current_test_failed = true;
+            error_delegate(format!(
+                "ERROR: Unable to load Lua script file '{}': {}",
+                path.display(),
+                message
+            ));
+        }
+        let runner = self.inner.borrow();
+        if let Some(max_failures) = runner.max_failures_per_test {
+            if runner.current_test_failures > max_failures {
+                let suppressed = runner.current_test_failures - max_failures;
+                error_delegate(format!(
+                    "({} additional failure{} suppressed)",
+                    suppressed,
+                    if suppressed == 1 {
+                        ""
+                    } else {
+                        "s"
                     }
+                ));
+            }
+        }
+
+        // Anything the test logged which wasn't already shown along with an
+        // expectation failure is shown if the test failed, and otherwise
+        // discarded.
+        if runner.current_test_failed {
+            for line in &runner.current_test_log {
+                error_delegate(format!("LOG: {}", line));
+            }
+        }
+
+        // Anything the test printed is only shown if the test failed, since
+        // it's usually only of interest when figuring out why.
+        if runner.current_test_failed && !runner.current_test_output.is_empty()
+        {
+            error_delegate(String::from("Captured output:"));
+            for line in &runner.current_test_output {
+                error_delegate(line.clone());
+            }
+        }
+
+        // In case the test script was included by one of several nested
+        // '.moonunit' files, show which one, to help find who owns it.
+        if runner.current_test_failed {
+            if let Some(configuration) = configuration {
+                error_delegate(format!(
+                    "Test script included by '{}'",
+                    configuration.display()
+                ));
+            }
+        }
+
+        // The outcome of a test which is expected to fail is inverted,
+        // unless it was skipped.
+        let failed = runner.current_test_failed;
+        if expect_fail && runner.current_test_skipped.is_none() {
+            drop(runner);
+            if failed {
+                error_delegate(String::from("XFAIL: Test failed, as expected"));
+            } else {
+                error_delegate(String::from(
+                    "ERROR: Test was expected to fail, but it passed",
+                ));
+                self.inner.borrow_mut().current_test_unexpectedly_passed = true;
+            }
+            return failed;
+        }
+        !failed
+    }
+
+    fn call_teardown<E>(
+        &self,
+        lua: &mlua::Lua,
+        test_suite_name: &str,
+        fixture: mlua::Value,
+        mut error_delegate: E,
+    ) where
+        E: FnMut(String),
+    {
+        if let Err(error) = call_test_hook(
+            lua,
+            SuiteHookKind::Teardown,
+            test_suite_name,
+            fixture,
+        ) {
+            error_delegate(format!("ERROR: teardown failed: {}", error));
+            self.inner.borrow_mut().current_test_failed = true;
+        }
+    }
+
+    fn run_suite_hook<F, T>(
+        &mut self,
+        kind: SuiteHookKind,
+        test_suite_name: &str,
+        f: F,
+    ) -> Option<(TestResult, Option<T>)>
+    where
+        F: FnOnce(&mlua::Lua, mlua::Function) -> mlua::Result<T>,
+    {
+        let hook = {
+            let runner = self.inner.borrow();
+            match kind {
+                SuiteHookKind::BeforeAll => &runner.before_all,
+                SuiteHookKind::AfterAll => &runner.after_all,
+                SuiteHookKind::Setup | SuiteHookKind::Teardown => return None,
+            }
+            .get(test_suite_name)?
+            .clone()
+        };
+        let messages = std::cell::RefCell::new(Vec::new());
+        let start_time = std::time::Instant::now();
+        let mut output = None;
+        self.inner.borrow_mut().current_test_output.clear();
+        self.with_lua(|runner, lua| {
+            let result = runner.with_script(
+                lua,
+                |message| messages.borrow_mut().push(message),
+                &hook.file,
+                &hook.path,
+                |_, lua, _| {
+                    let hooks: mlua::Table =
+                        lua.named_registry_value(kind.registry_name())?;
+                    let function: mlua::Function =
+                        hooks.get(test_suite_name)?;
+                    output = Some(f(lua, function)?);
                     Ok(())
                 },
-            ) {
-                Ok(_) => (),
-                Err(message) => {
-                    runner.inner.borrow_mut().current_test_failed = true;
-                    error_delegate(format!(
-                        "ERROR: Unable to load Lua script file '{}': {}",
-                        path.display(),
-                        message
-                    ));
-                },
-            };
+            );
+            if let Err(message) = result {
+                messages.borrow_mut().push(format!(
+                    "ERROR: {} failed for test suite '{}': {}",
+                    kind.name(),
+                    test_suite_name,
+                    message
+                ));
+            }
         });
-        !self.inner.borrow().current_test_failed
+
+        // Anything printed by the function is passed along, whether or not
+        // it succeeded, since it isn't part of any one test.
+        let printed =
+            std::mem::take(&mut self.inner.borrow_mut().current_test_output);
+        if !printed.is_empty() {
+            let mut messages = messages.borrow_mut();
+            messages.push(format!(
+                "Captured output of {} for test suite '{}':",
+                kind.name(),
+                test_suite_name
+            ));
+            messages.extend(printed);
+        }
+        Some((
+            TestResult {
+                passed: output.is_some(),
+                aborted: false,
+                skipped: None,
+                elapsed: start_time.elapsed(),
+                messages: messages.into_inner(),
+                assertions: 0,
+                unexpectedly_passed: false,
+            },
+            output,
+        ))
+    }
+
+    pub fn set_abort_after_failures(
+        &mut self,
+        abort_after_failures: Option<usize>,
+    ) {
+        self.inner.borrow_mut().abort_after_failures = abort_after_failures;
+    }
+
+    // Gather line coverage from every Lua state made from now on.
+    pub fn set_coverage(
+        &mut self,
+        coverage: bool,
+    ) {
+        self.inner.borrow_mut().coverage = if coverage {
+            Some(std::rc::Rc::new(std::cell::RefCell::new(
+                coverage::Coverage::default(),
+            )))
+        } else {
+            None
+        };
+    }
+
+    pub fn set_fixture_seed(
+        &mut self,
+        fixture_seed: Option<FixtureSeed>,
+    ) {
+        self.inner.borrow_mut().fixture_seed = fixture_seed;
+    }
+
+    pub fn set_max_failures_per_test(
+        &mut self,
+        max_failures_per_test: Option<usize>,
+    ) {
+        self.inner.borrow_mut().max_failures_per_test = max_failures_per_test;
+    }
+
+    // When Lua states are reused, each test script is executed only once,
+    // in a Lua state which is then kept for running all of the script's
+    // tests, rather than being executed again in a fresh Lua state for
+    // each test.  Tests of the same script then share any globals they set.
+    pub fn set_reuse_lua_states(
+        &mut self,
+        reuse_lua_states: bool,
+    ) {
+        let mut inner = self.inner.borrow_mut();
+        inner.reuse_lua_states = reuse_lua_states;
+        if !reuse_lua_states {
+            inner.loaded_scripts.clear();
+        }
+    }
+
+    // When Lua states are reused, the globals of each test script are put
+    // back the way the script left them before each test is run, so that
+    // globals set by one test aren't seen by the next, unless globals are
+    // shared, which saves the time taken to copy and restore them.
+    pub fn set_share_globals(
+        &mut self,
+        share_globals: bool,
+    ) {
+        let mut inner = self.inner.borrow_mut();
+        inner.share_globals = share_globals;
+        inner.loaded_scripts.clear();
+    }
+
+    // In safe mode, test scripts are run in sandboxed Lua states, without
+    // the parts of the standard library which reach outside of Lua.
+    pub fn set_safe(
+        &mut self,
+        safe: bool,
+    ) {
+        let mut inner = self.inner.borrow_mut();
+        inner.safe = safe;
+        inner.loaded_scripts.clear();
+    }
+
+    pub fn set_strict(
+        &mut self,
+        strict: bool,
+    ) {
+        self.inner.borrow_mut().strict = strict;
+    }
+
+    pub fn set_timeout(
+        &mut self,
+        timeout: Option<std::time::Duration>,
+    ) {
+        self.inner.borrow_mut().timeout = timeout;
+    }
+
+    pub fn set_update_golden(
+        &mut self,
+        update_golden: bool,
+    ) {
+        self.inner.borrow_mut().update_golden = update_golden;
     }
 
     fn with_lua<F>(
@@ -994,10 +5684,12 @@ impl Runner {
     ) where
         F: FnOnce(&mut Self, &mut mlua::Lua),
     {
-        unsafe {
-            let mut lua = mlua::Lua::unsafe_new();
-            f(self, &mut lua)
-        }
+        let coverage = self.inner.borrow().coverage.clone();
+        let safe = self.inner.borrow().safe;
+        let start_time = std::time::Instant::now();
+        let mut lua = new_lua_state(safe, coverage);
+        self.inner.borrow_mut().profile.state_setup += start_time.elapsed();
+        f(self, &mut lua)
     }
 
     fn with_script<E, F>(
@@ -1016,39 +5708,171 @@ impl Runner {
             std::rc::Rc<mlua::RegistryKey>,
         ) -> mlua::Result<()>,
     {
-        let original_working_directory = std::env::current_dir().unwrap();
-        std::env::set_current_dir(path.parent().unwrap()).unwrap();
-        let name: String =
-            "=".to_string() + &path.to_string_lossy().to_string();
+        let original_working_directory = enter_script_directory(path)?;
         let result = (move || {
-            let tests_table = lua.create_table().unwrap();
-            let tests_registry_key = std::rc::Rc::new(
-                lua.create_registry_value(tests_table).unwrap(),
-            );
-            let errors = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
-            lua.globals()
-                .set(
-                    "moonunit",
-                    RunContext::new(
-                        &errors,
-                        script,
-                        path,
-                        self,
-                        &tests_registry_key,
-                    ),
-                )
-                .unwrap();
-            lua.load(script)
-                .set_name(name.as_bytes())
-                .and_then(mlua::Chunk::exec)
-                .map_err(|err| err.to_string())?;
+            let (tests_registry_key, errors) =
+                self.load_script(lua, script, path)?;
             f(self, lua, tests_registry_key).map_err(|err| err.to_string())?;
             for message in errors.borrow_mut().iter() {
                 error_delegate(message.clone());
             }
             Ok(())
         })();
-        std::env::set_current_dir(original_working_directory).unwrap();
-        result
+        leave_script_directory(&original_working_directory, result)
+    }
+
+    // This is like `with_script`, except that the Lua state in which the
+    // script is executed is kept after the given function returns, and
+    // used again the next time a test of the same script is run, rather
+    // than executing the script again in a fresh Lua state.
+    fn with_loaded_script<E, F>(
+        &mut self,
+        mut error_delegate: E,
+        script: &str,
+        path: &std::path::Path,
+        f: F,
+    ) -> Result<(), String>
+    where
+        E: FnMut(String),
+        F: FnOnce(
+            &mut Self,
+            &mut mlua::Lua,
+            std::rc::Rc<mlua::RegistryKey>,
+        ) -> mlua::Result<()>,
+    {
+        // The loaded script is taken out of the runner while it's used, so
+        // that the runner isn't borrowed while the test is running.
+        let original_working_directory = enter_script_directory(path)?;
+        let loaded_script = self.inner.borrow_mut().loaded_scripts.remove(path);
+        let result = (move || {
+            let mut loaded_script = match loaded_script {
+                Some(loaded_script) => {
+                    loaded_script.errors.borrow_mut().clear();
+                    if let Some(globals_snapshot) =
+                        &loaded_script.globals_snapshot
+                    {
+                        restore_globals(&loaded_script.lua, globals_snapshot)
+                            .map_err(|err| err.to_string())?;
+                    }
+                    self.install_globals(&loaded_script.lua)?;
+                    loaded_script
+                },
+                None => {
+                    let coverage = self.inner.borrow().coverage.clone();
+                    let safe = self.inner.borrow().safe;
+                    let start_time = std::time::Instant::now();
+                    let lua = new_lua_state(safe, coverage);
+                    self.inner.borrow_mut().profile.state_setup +=
+                        start_time.elapsed();
+                    let (tests_registry_key, errors) =
+                        self.load_script(&lua, script, path)?;
+                    let globals_snapshot = if self.inner.borrow().share_globals
+                    {
+                        None
+                    } else {
+                        Some(
+                            snapshot_globals(&lua)
+                                .map_err(|err| err.to_string())?,
+                        )
+                    };
+                    LoadedScript {
+                        lua,
+                        tests_registry_key,
+                        errors,
+                        globals_snapshot,
+                    }
+                },
+            };
+            let result = f(
+                self,
+                &mut loaded_script.lua,
+                loaded_script.tests_registry_key.clone(),
+            )
+            .map_err(|err| err.to_string());
+            if result.is_ok() {
+                for message in loaded_script.errors.borrow_mut().iter() {
+                    error_delegate(message.clone());
+                }
+            }
+            self.inner
+                .borrow_mut()
+                .loaded_scripts
+                .insert(path.to_path_buf(), loaded_script);
+            result
+        })();
+        leave_script_directory(&original_working_directory, result)
+    }
+
+    // Set up the globals of the given Lua state for running a test script,
+    // and execute the script, giving back the registry key of the table of
+    // tests it defined, along with the errors reported while doing so.
+    // The current working directory should already be the folder of the
+    // test script.
+    fn load_script(
+        &mut self,
+        lua: &mlua::Lua,
+        script: &str,
+        path: &std::path::Path,
+    ) -> Result<
+        (
+            std::rc::Rc<mlua::RegistryKey>,
+            std::rc::Rc<std::cell::RefCell<Vec<String>>>,
+        ),
+        String,
+    > {
+        let name: String =
+            "=".to_string() + &path.to_string_lossy().to_string();
+        let tests_table = lua.create_table().unwrap();
+        let tests_registry_key =
+            std::rc::Rc::new(lua.create_registry_value(tests_table).unwrap());
+        let errors = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        lua.globals()
+            .set(
+                "moonunit",
+                RunContext::new(
+                    &errors,
+                    script,
+                    path,
+                    self,
+                    &tests_registry_key,
+                ),
+            )
+            .unwrap();
+        lua.globals().set("print", make_print(lua, &self.inner)).unwrap();
+        self.install_globals(lua)?;
+        let coverage = self.inner.borrow().coverage.clone();
+        if let Some(coverage) = &coverage {
+            coverage.borrow_mut().begin_script(path);
+        }
+        let start_time = std::time::Instant::now();
+        let executed = lua
+            .load(script)
+            .set_name(name.as_bytes())
+            .and_then(mlua::Chunk::exec);
+        self.inner.borrow_mut().profile.script_execution +=
+            start_time.elapsed();
+        if let Some(coverage) = &coverage {
+            coverage.borrow_mut().end_script();
+        }
+        executed.map_err(|err| err.to_string())?;
+        Ok((tests_registry_key, errors))
+    }
+
+    // Set the globals registered by the host application (see
+    // `register_global`) in the given Lua state, making a new value for
+    // each of them.
+    fn install_globals(
+        &self,
+        lua: &mlua::Lua,
+    ) -> Result<(), String> {
+        let globals = self.inner.borrow().globals.clone();
+        for (name, make_value) in globals {
+            make_value(lua)
+                .and_then(|value| lua.globals().set(name.as_str(), value))
+                .map_err(|err| {
+                    format!("Unable to set global '{}': {}", name, err)
+                })?;
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,42 @@
+// Run MoonUnit on a folder of test scripts, one of which removes another
+// after the tests are discovered but before the tests of the removed one are
+// run, and check that the removed script is reported once, with its tests
+// failed.
+#[test]
+fn source_removed_between_discovery_and_run() {
+    let folder = std::env::temp_dir()
+        .join(format!("moonunit-unavailable-source-{}", std::process::id()));
+    std::fs::create_dir_all(&folder).unwrap();
+    std::fs::write(folder.join(".moonunit"), "first.lua\nsecond.lua\n")
+        .unwrap();
+    std::fs::write(
+        folder.join("first.lua"),
+        "moonunit:test(\"a_first\", \"remove_second\", function()\n    \
+        moonunit:assert_eq(true, os.remove(\"second.lua\"))\n\
+        end)\n",
+    )
+    .unwrap();
+    std::fs::write(
+        folder.join("second.lua"),
+        "moonunit:test(\"b_second\", \"one\", function() end)\n\
+        moonunit:test(\"b_second\", \"two\", function() end)\n",
+    )
+    .unwrap();
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_moon_unit"))
+        .arg("--path")
+        .arg(&folder)
+        .arg("--test-order=alpha")
+        .output()
+        .unwrap();
+    std::fs::remove_dir_all(&folder).unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(
+        1,
+        stdout.matches("is no longer available").count(),
+        "{}",
+        stdout
+    );
+    assert!(stdout.contains("b_second.one"), "{}", stdout);
+    assert!(stdout.contains("b_second.two"), "{}", stdout);
+    assert!(!output.status.success());
+}